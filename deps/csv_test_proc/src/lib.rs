@@ -4,6 +4,17 @@ use syn::{parse_macro_input, LitStr};
 /// This macro generates a test function that reads a CSV file, imports it into a `Shard`, exports
 /// the `Shard` to a CSV file, and compares the exported CSV file with a reference CSV file.
 ///
+/// A second test does the same with a `Shard` that keeps its accounts and transactions in a
+/// `FileStore`, and then opens the store again in a new `Shard` to check that the accounts survive.
+/// A third uses a `CachedStore` that only keeps a single transaction in memory, so that nearly every
+/// transaction is evicted to disk and loaded back. A fourth checks that the `Shard` is unchanged by
+/// a snapshot and restore. A fifth runs the events through a `ShardPool` with a few threads, which
+/// should give the same output as a single `Shard`.
+///
+/// When the `i128` feature of the crate under test is enabled, `<name>.i128.want.csv` is used as the
+/// reference file instead, if it exists. The output has a currency column if the reference file has
+/// one.
+///
 /// A proc_macro is required here, because a simple macro doesn't work in this case, due to the fact
 /// that we cannot use the `concat_idents!` macro to create a function name.
 #[proc_macro]
@@ -12,24 +23,87 @@ pub fn csv_test(input: TokenStream) -> TokenStream {
     let value = literal.value();
 
     format!(
-        "#[test]
-fn test_{}() {{
-    let mut shard = Shard::new();
-    let csv_reader = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .from_path(\"data/{}.test.csv\")
-        .unwrap();
-    import_csv(&mut shard, csv_reader);
+        "fn read_{name}(sink: &mut impl EventSink) {{
+    import_csv(sink, std::fs::File::open(\"data/{name}.test.csv\").unwrap());
+}}
+
+fn check_{name}(shard: &Shard<impl Store>) {{
+    // Amounts that overflow an i64 may not overflow an i128, so those fixtures can have a separate
+    // reference file for the `i128` feature.
+    let want_i128 = \"data/{name}.i128.want.csv\";
+    let want = if cfg!(feature = \"i128\") && std::path::Path::new(want_i128).exists() {{
+        want_i128
+    }} else {{
+        \"data/{name}.want.csv\"
+    }};
+    let want = std::fs::read_to_string(want).unwrap();
 
+    let currencies = want.lines().next().is_some_and(|headers| headers.contains(\"currency\"));
     let mut got = Vec::new();
-    export_csv(&shard, &mut std::io::BufWriter::new(&mut got));
+    export_csv(shard, &mut std::io::BufWriter::new(&mut got), currencies).unwrap();
     let got = String::from_utf8(got).unwrap();
-
-    let want = std::fs::read_to_string(\"data/{}.want.csv\")
-        .unwrap();
     assert_eq!(got, want);
+}}
+
+#[test]
+fn test_{name}() {{
+    let mut shard = Shard::new();
+    read_{name}(&mut shard);
+    check_{name}(&shard);
+}}
+
+#[test]
+fn test_{name}_file_store() {{
+    let path = std::env::temp_dir().join(format!(
+        \"txn_test_{name}_{{}}.store\",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+
+    let mut shard = Shard::with_store(FileStore::open(&path).unwrap(), Policy::default());
+    read_{name}(&mut shard);
+    check_{name}(&shard);
+    drop(shard);
+
+    let shard = Shard::with_store(FileStore::open(&path).unwrap(), Policy::default());
+    check_{name}(&shard);
+    std::fs::remove_file(&path).unwrap();
+}}
+
+#[test]
+fn test_{name}_cached_store() {{
+    let path = std::env::temp_dir().join(format!(
+        \"txn_test_{name}_{{}}.spill\",
+        std::process::id()
+    ));
+
+    let mut shard = Shard::with_store(CachedStore::new(1, &path).unwrap(), Policy::default());
+    read_{name}(&mut shard);
+    check_{name}(&shard);
+}}
+
+#[test]
+fn test_{name}_snapshot() {{
+    let mut shard = Shard::new();
+    read_{name}(&mut shard);
+    let mut snapshot = Vec::new();
+    shard.snapshot(&mut snapshot).unwrap();
+
+    let restored = Shard::restore(&snapshot[..]).unwrap();
+    check_{name}(&restored);
+    assert_eq!(restored.generate_output_sorted(), shard.generate_output_sorted());
+    assert_eq!(restored.events(), shard.events());
+    let messages = |shard: &Shard| shard.errors().iter().map(|err| err.to_string()).collect::<Vec<_>>();
+    assert_eq!(messages(&restored), messages(&shard));
+}}
+
+#[test]
+fn test_{name}_pool() {{
+    let mut pool = ShardPool::new(3, Policy::default());
+    read_{name}(&mut pool);
+    check_{name}(&pool.finish());
 }}",
-        value, value, value
+        name = value
     )
     .parse()
     .unwrap()
@@ -0,0 +1,335 @@
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    path::PathBuf,
+};
+use txn::{Amount, DisputeMode, RoundingMode};
+
+pub fn usage(program: &str) -> String {
+    format!(
+        "Usage: {} [OPTIONS] [INPUT.csv]...
+
+Reads the transactions from each input file, in order, and writes a summary of every client account
+as csv. If no input files are given (or the input is `-`), the transactions are read from stdin.
+
+Options:
+  -o, --output <PATH>         Write the account summaries to PATH instead of stdout
+      --store <PATH>          Keep the accounts and transactions in the file at PATH, starting
+                              from whatever it already holds, instead of only in memory
+      --cache <N>             Keep at most N transactions in memory, evicting the least recently
+                              used ones to a temporary file (cannot be used with --store)
+      --threads <N>           Process the events on N threads, each with a share of the clients
+                              (cannot be used with --store or --cache)
+      --errors <PATH>         Write the errors to PATH instead of stderr
+      --errors-format <FMT>   Format of the errors, either `text` (default) or `json`
+      --currencies            Add a currency column to the account summaries, with a row for
+                              each currency that a client has used (added anyway when any
+                              event is in a currency)
+      --strict                Reject dispute events that arrive out of order
+      --deferred              Apply dispute events that arrive out of order once the transaction
+                              is ready for them
+      --scale <N>             Number of decimal places that amounts are read and written with
+                              (default {}, at most {})
+      --rounding <MODE>       How extra decimal places in the input are handled, one of
+                              `truncate` (default), `half-up`, `half-even` or `reject`
+  -h, --help                  Print this message
+",
+        program,
+        Amount::DEFAULT_SCALE,
+        Amount::MAX_SCALE
+    )
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Run(Options),
+    Help,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    // An empty list means that the input should be read from stdin.
+    pub inputs: Vec<Input>,
+    pub output: Option<PathBuf>,
+    // Where the accounts and transactions are kept between runs, if anywhere.
+    pub store: Option<PathBuf>,
+    // How many transactions are kept in memory, if not all of them.
+    pub cache: Option<usize>,
+    // How many threads the events are processed on, if more than the main thread.
+    pub threads: Option<usize>,
+    pub errors: Option<PathBuf>,
+    pub errors_format: ErrorsFormat,
+    pub currencies: bool,
+    pub disputes: DisputeMode,
+    pub scale: u32,
+    pub rounding: RoundingMode,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            inputs: Vec::new(),
+            output: None,
+            store: None,
+            cache: None,
+            threads: None,
+            errors: None,
+            errors_format: ErrorsFormat::Text,
+            currencies: false,
+            disputes: DisputeMode::Tolerant,
+            scale: Amount::DEFAULT_SCALE,
+            rounding: RoundingMode::Truncate,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    Stdin,
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorsFormat {
+    // One human readable error per line.
+    Text,
+    // One json object per line.
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsageError {
+    UnknownOption(String),
+    MissingValue(&'static str),
+    InvalidValue { option: &'static str, value: String },
+    Conflict(&'static str, &'static str),
+}
+
+impl Display for UsageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::UnknownOption(option) => write!(f, "error: unknown option `{}`", option),
+            Self::MissingValue(option) => write!(f, "error: `{}` requires a value", option),
+            Self::InvalidValue { option, value } => {
+                write!(f, "error: invalid value `{}` for `{}`", value, option)
+            }
+            Self::Conflict(option, other) => {
+                write!(f, "error: `{}` cannot be used with `{}`", option, other)
+            }
+        }
+    }
+}
+
+// Parses the command line arguments, not including the name of the program.
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Command, UsageError> {
+    let mut options = Options::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        // Allow `--option=value` as well as `--option value`.
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = |option: &'static str| {
+            inline_value
+                .clone()
+                .or_else(|| args.next())
+                .ok_or(UsageError::MissingValue(option))
+        };
+
+        match name {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-o" | "--output" => options.output = Some(value("--output")?.into()),
+            "--store" => options.store = Some(value("--store")?.into()),
+            "--cache" => {
+                let cache = value("--cache")?;
+                options.cache = match cache.parse() {
+                    Ok(cache) => Some(cache),
+                    Err(_) => {
+                        return Err(UsageError::InvalidValue {
+                            option: "--cache",
+                            value: cache,
+                        })
+                    }
+                }
+            }
+            "--threads" => {
+                let threads = value("--threads")?;
+                options.threads = match threads.parse() {
+                    Ok(threads) if threads > 0 => Some(threads),
+                    _ => {
+                        return Err(UsageError::InvalidValue {
+                            option: "--threads",
+                            value: threads,
+                        })
+                    }
+                }
+            }
+            "--errors" => options.errors = Some(value("--errors")?.into()),
+            "--errors-format" => {
+                options.errors_format = match value("--errors-format")?.as_str() {
+                    "text" => ErrorsFormat::Text,
+                    "json" => ErrorsFormat::Json,
+                    other => {
+                        return Err(UsageError::InvalidValue {
+                            option: "--errors-format",
+                            value: other.to_string(),
+                        })
+                    }
+                }
+            }
+            "--currencies" => options.currencies = true,
+            "--strict" => options.disputes = DisputeMode::Strict,
+            "--deferred" => options.disputes = DisputeMode::Deferred,
+            "--scale" => {
+                let scale = value("--scale")?;
+                options.scale = match scale.parse() {
+                    Ok(scale) if scale <= Amount::MAX_SCALE => scale,
+                    _ => {
+                        return Err(UsageError::InvalidValue {
+                            option: "--scale",
+                            value: scale,
+                        })
+                    }
+                }
+            }
+            "--rounding" => {
+                options.rounding = match value("--rounding")?.as_str() {
+                    "truncate" => RoundingMode::Truncate,
+                    "half-up" => RoundingMode::HalfUp,
+                    "half-even" => RoundingMode::HalfEven,
+                    "reject" => RoundingMode::Reject,
+                    other => {
+                        return Err(UsageError::InvalidValue {
+                            option: "--rounding",
+                            value: other.to_string(),
+                        })
+                    }
+                }
+            }
+            "-" => options.inputs.push(Input::Stdin),
+            _ if name.starts_with('-') => return Err(UsageError::UnknownOption(arg)),
+            _ => options.inputs.push(Input::File(arg.into())),
+        }
+    }
+
+    if options.store.is_some() && options.cache.is_some() {
+        return Err(UsageError::Conflict("--cache", "--store"));
+    }
+    if options.threads.is_some() {
+        if options.store.is_some() {
+            return Err(UsageError::Conflict("--threads", "--store"));
+        }
+        if options.cache.is_some() {
+            return Err(UsageError::Conflict("--threads", "--cache"));
+        }
+    }
+
+    Ok(Command::Run(options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Command, UsageError> {
+        parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_args(&[]), Ok(Command::Run(Options::default())));
+        assert_eq!(parse_args(&["a.csv", "--help"]), Ok(Command::Help));
+        assert_eq!(
+            parse_args(&["--deferred"]),
+            Ok(Command::Run(Options {
+                disputes: DisputeMode::Deferred,
+                ..Options::default()
+            }))
+        );
+        assert_eq!(
+            parse_args(&[
+                "a.csv",
+                "--output",
+                "out.csv",
+                "--store",
+                "txn.store",
+                "-",
+                "--errors=errors.json",
+                "--errors-format",
+                "json",
+                "--currencies",
+                "--strict",
+                "--scale=8",
+                "--rounding",
+                "half-even",
+                "b.csv",
+            ]),
+            Ok(Command::Run(Options {
+                inputs: vec![
+                    Input::File("a.csv".into()),
+                    Input::Stdin,
+                    Input::File("b.csv".into()),
+                ],
+                output: Some("out.csv".into()),
+                store: Some("txn.store".into()),
+                cache: None,
+                threads: None,
+                errors: Some("errors.json".into()),
+                errors_format: ErrorsFormat::Json,
+                currencies: true,
+                disputes: DisputeMode::Strict,
+                scale: 8,
+                rounding: RoundingMode::HalfEven,
+            }))
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse_args(&["--verbose"]),
+            Err(UsageError::UnknownOption("--verbose".to_string()))
+        );
+        assert_eq!(
+            parse_args(&["a.csv", "--output"]),
+            Err(UsageError::MissingValue("--output"))
+        );
+        assert_eq!(
+            parse_args(&["--errors-format", "xml"]),
+            Err(UsageError::InvalidValue {
+                option: "--errors-format",
+                value: "xml".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_args(&["--threads", "0"]),
+            Err(UsageError::InvalidValue {
+                option: "--threads",
+                value: "0".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_args(&["--threads=4", "--store", "txn.store"]),
+            Err(UsageError::Conflict("--threads", "--store"))
+        );
+        assert_eq!(
+            parse_args(&["--cache=1000", "--store", "txn.store"]),
+            Err(UsageError::Conflict("--cache", "--store"))
+        );
+        assert_eq!(
+            parse_args(&["--cache", "-1"]),
+            Err(UsageError::InvalidValue {
+                option: "--cache",
+                value: "-1".to_string(),
+            })
+        );
+        assert_eq!(
+            parse_args(&["--scale", &(Amount::MAX_SCALE + 1).to_string()]),
+            Err(UsageError::InvalidValue {
+                option: "--scale",
+                value: (Amount::MAX_SCALE + 1).to_string(),
+            })
+        );
+    }
+}
@@ -0,0 +1,303 @@
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    io,
+};
+
+// A compact binary encoding, used to keep accounts and transactions on disk. Integers are written in
+// little endian, and each type is written as its fields, one after another, in the order that they
+// are declared. An `Option` is written as a 0 (for None) or a 1 followed by its value.
+pub trait Encode {
+    // Writes the value to the end of the output. Nothing is written past the value when an error is
+    // returned, but part of the value may have been.
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError>;
+}
+
+pub trait Decode: Sized {
+    // Reads a value from the start of the input, and advances the input past it.
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError>;
+}
+
+// @returns the encoding of the value
+pub fn encode_to_vec(value: &impl Encode) -> Result<Vec<u8>, EncodeError> {
+    let mut out = Vec::new();
+    value.encode(&mut out)?;
+    Ok(out)
+}
+
+// Writes a length, which must fit in a u32, as the encoding has no room for anything larger.
+pub fn encode_len(len: usize, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+    u32::try_from(len)
+        .map_err(|_| EncodeError::new("too long to encode"))?
+        .encode(out)
+}
+
+// @returns the value encoded by the bytes, which must not contain anything else
+pub fn decode_from_slice<T: Decode>(mut bytes: &[u8]) -> Result<T, DecodeError> {
+    let value = T::decode(&mut bytes)?;
+    if !bytes.is_empty() {
+        return Err(DecodeError::new(
+            "unexpected data after the end of the value",
+        ));
+    }
+    Ok(value)
+}
+
+// A value that can not be encoded, because it is larger than the encoding allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodeError {
+    pub reason: &'static str,
+}
+
+impl EncodeError {
+    pub fn new(reason: &'static str) -> Self {
+        Self { reason }
+    }
+}
+
+impl Display for EncodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "can not encode: {}", self.reason)
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+impl From<EncodeError> for io::Error {
+    fn from(err: EncodeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeError {
+    pub reason: &'static str,
+}
+
+impl DecodeError {
+    const END_OF_DATA: &'static str = "unexpected end of data";
+
+    pub fn new(reason: &'static str) -> Self {
+        Self { reason }
+    }
+
+    // The input ended part way through a value.
+    pub fn end_of_data() -> Self {
+        Self::new(Self::END_OF_DATA)
+    }
+
+    // @returns whether the input ended part way through a value, in which case more of it may be
+    // all that is needed to decode the value
+    pub fn is_end_of_data(&self) -> bool {
+        self.reason == Self::END_OF_DATA
+    }
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "invalid data: {}", self.reason)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<DecodeError> for io::Error {
+    fn from(err: DecodeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+// @returns the next N bytes of the input, advancing it past them
+pub fn take<const N: usize>(input: &mut &[u8]) -> Result<[u8; N], DecodeError> {
+    if input.len() < N {
+        return Err(DecodeError::end_of_data());
+    }
+    let (head, rest) = input.split_at(N);
+    let mut bytes = [0; N];
+    bytes.copy_from_slice(head);
+    *input = rest;
+    Ok(bytes)
+}
+
+macro_rules! int_codec {
+    ($($int:ty),*) => {
+        $(
+            impl Encode for $int {
+                fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+                    out.extend_from_slice(&self.to_le_bytes());
+                    Ok(())
+                }
+            }
+
+            impl Decode for $int {
+                fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+                    take(input).map(<$int>::from_le_bytes)
+                }
+            }
+        )*
+    };
+}
+
+int_codec!(u8, u16, u32, u64, u128, i128);
+
+impl Encode for bool {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        u8::from(*self).encode(out)
+    }
+}
+
+impl Decode for bool {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode(input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DecodeError::new("invalid bool")),
+        }
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        match self {
+            None => 0u8.encode(out)?,
+            Some(value) => {
+                1u8.encode(out)?;
+                value.encode(out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode(input)? {
+            0 => Ok(None),
+            1 => T::decode(input).map(Some),
+            _ => Err(DecodeError::new("invalid option")),
+        }
+    }
+}
+
+// A sequence is written as its length (u32), followed by each of its items.
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        encode_len(self.len(), out)?;
+        self.iter().try_for_each(|item| item.encode(out))
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = u32::decode(input)?;
+        // The length is not trusted to reserve space up front, as the data may be damaged.
+        let mut items = Vec::new();
+        for _ in 0..len {
+            items.push(T::decode(input)?);
+        }
+        Ok(items)
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        encode_len(self.len(), out)?;
+        out.extend_from_slice(self.as_bytes());
+        Ok(())
+    }
+}
+
+impl Decode for String {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let len = u32::decode(input)? as usize;
+        if input.len() < len {
+            return Err(DecodeError::end_of_data());
+        }
+        let (bytes, rest) = input.split_at(len);
+        *input = rest;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::new("invalid utf-8"))
+    }
+}
+
+// @returns the CRC-32 checksum of the bytes (the same one used by zip and png)
+pub fn checksum(bytes: &[u8]) -> u32 {
+    let mut crc = Checksum::new();
+    crc.update(bytes);
+    crc.finish()
+}
+
+// Works out a checksum (see `checksum`) of data that arrives in pieces.
+#[derive(Debug, Clone, Copy)]
+pub struct Checksum(u32);
+
+impl Checksum {
+    pub fn new() -> Self {
+        Self(!0)
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u32::from(*byte);
+            for _ in 0..8 {
+                let mask = (self.0 & 1).wrapping_neg();
+                self.0 = (self.0 >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for Checksum {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codec() {
+        let value = (Some(7u32), None::<u16>, true);
+        let mut out = Vec::new();
+        value.0.encode(&mut out).unwrap();
+        value.1.encode(&mut out).unwrap();
+        value.2.encode(&mut out).unwrap();
+        assert_eq!(out, [1, 7, 0, 0, 0, 0, 1]);
+
+        let mut input = &out[..];
+        assert_eq!(Option::<u32>::decode(&mut input), Ok(Some(7)));
+        assert_eq!(Option::<u16>::decode(&mut input), Ok(None));
+        assert_eq!(bool::decode(&mut input), Ok(true));
+        assert!(input.is_empty());
+
+        assert!(decode_from_slice::<u32>(&[1, 2]).is_err());
+        assert!(decode_from_slice::<u8>(&[1, 2]).is_err());
+        assert!(decode_from_slice::<bool>(&[2]).is_err());
+
+        let items = vec!["a".to_string(), "bc".to_string()];
+        let out = encode_to_vec(&items).unwrap();
+        assert_eq!(out, [2, 0, 0, 0, 1, 0, 0, 0, b'a', 2, 0, 0, 0, b'b', b'c']);
+        assert_eq!(decode_from_slice::<Vec<String>>(&out), Ok(items));
+        assert!(decode_from_slice::<Vec<u8>>(&[2, 0, 0, 0, 1]).is_err());
+
+        let mut out = Vec::new();
+        assert!(encode_len(u32::MAX as usize, &mut out).is_ok());
+        assert!(encode_len(u32::MAX as usize + 1, &mut out).is_err());
+        assert_eq!(out, [0xFF; 4]);
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(checksum(b""), 0);
+        assert_eq!(checksum(b"123456789"), 0xCBF4_3926);
+
+        let mut crc = Checksum::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+    }
+}
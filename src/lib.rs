@@ -1,5 +1,11 @@
+mod codec;
 mod model;
+mod pool;
 mod shard;
+mod store;
 
+pub use codec::*;
 pub use model::*;
+pub use pool::*;
 pub use shard::*;
+pub use store::*;
@@ -1,77 +1,410 @@
-use csv;
-use std::{fs::File, rc::Rc};
+mod cli;
+
+use cli::{Command, ErrorsFormat, Input, Options};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    process::ExitCode,
+};
 use txn::*;
 
-fn main() {
-    let mut shard = Shard::new();
+fn main() -> ExitCode {
+    let mut cli_args = std::env::args();
 
-    {
-        // Parse the input and populate the shard with data.
-
-        let mut cli_args = std::env::args();
-
-        // Remember that the first argument is always the name of the program.
-        match cli_args.len() {
-            1 => {
-                // No arguments, read from stdin.
-                let reader: Box<dyn std::io::Read> = Box::new(std::io::stdin());
-                let csv_reader = csv::ReaderBuilder::new()
-                    .trim(csv::Trim::All)
-                    .from_reader(reader);
-                import_csv(&mut shard, csv_reader);
-            }
+    // Remember that the first argument is always the name of the program.
+    let program = cli_args.next().unwrap_or_else(|| "txn".to_string());
+
+    let options = match cli::parse(cli_args) {
+        Ok(Command::Run(options)) => options,
+        Ok(Command::Help) => {
+            print!("{}", cli::usage(&program));
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, cli::usage(&program));
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(&options) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let policy = Policy {
+        disputes: options.disputes,
+        ..Policy::default()
+    };
+    let format = CsvFormat {
+        scale: options.scale,
+        rounding: options.rounding,
+        currencies: options.currencies,
+    };
+
+    if let Some(threads) = options.threads {
+        let mut pool = ShardPool::new(threads, policy);
+        import_inputs(&mut pool, options, format)?;
+        return export_results(&pool.finish(), options, format);
+    }
 
-            2 => {
-                // One argument, read from the given file.
-                let reader: Box<dyn std::io::Read> =
-                    Box::new(File::open(cli_args.nth(1).unwrap()).unwrap());
-                let csv_reader = csv::ReaderBuilder::new()
-                    .trim(csv::Trim::All)
-                    .from_reader(reader);
-                import_csv(&mut shard, csv_reader);
+    match &options.store {
+        Some(path) => {
+            let store = FileStore::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            process(Shard::with_store(store, policy), options, format)
+        }
+        None => match options.cache {
+            Some(capacity) => {
+                let path = std::env::temp_dir().join(format!("txn-{}.spill", std::process::id()));
+                let store = CachedStore::new(capacity, &path)
+                    .map_err(|e| format!("{}: {}", path.display(), e))?;
+                process(Shard::with_store(store, policy), options, format)
             }
+            None => process(Shard::with_policy(policy), options, format),
+        },
+    }
+}
 
-            _ => {
-                // Too many arguments.
-                panic!("Usage: {} [input.csv]", cli_args.nth(0).unwrap());
+fn process<S: Store>(
+    mut shard: Shard<S>,
+    options: &Options,
+    format: CsvFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    import_inputs(&mut shard, options, format)?;
+    export_results(&shard, options, format)
+}
+
+// Parses the input and populates the shard (or pool) with data, one input after another.
+fn import_inputs(
+    sink: &mut impl EventSink,
+    options: &Options,
+    format: CsvFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let stdin = [Input::Stdin];
+    let inputs = if options.inputs.is_empty() {
+        &stdin[..]
+    } else {
+        &options.inputs[..]
+    };
+
+    for input in inputs {
+        let reader: Box<dyn std::io::Read> = match input {
+            Input::Stdin => Box::new(std::io::stdin()),
+            Input::File(path) => {
+                Box::new(File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?)
             }
-        }
+        };
+        import_csv_with(sink, reader, format);
     }
+    Ok(())
+}
 
+fn export_results<S: Store>(
+    shard: &Shard<S>,
+    options: &Options,
+    format: CsvFormat,
+) -> Result<(), Box<dyn std::error::Error>> {
     {
-        // Generate the output and write it to stdout.
-        export_csv(&shard, &mut std::io::stdout());
+        // Generate the output and write it to stdout, or the requested file.
+        let mut writer = open_output(options.output.as_ref(), || Box::new(std::io::stdout()))?;
+        export_csv_with(shard, &mut writer, format)?;
     }
 
     {
-        // Print any errors that occurred during processing.
-        shard.errors().iter().for_each(|err| {
-            eprintln!("{}", err);
-        });
+        // Write any errors that occurred during processing to stderr, or the requested file.
+        let mut writer = open_output(options.errors.as_ref(), || Box::new(std::io::stderr()))?;
+        for err in shard.errors() {
+            match options.errors_format {
+                ErrorsFormat::Text => writeln!(writer, "{}", err)?,
+                ErrorsFormat::Json => writeln!(writer, "{}", error_json(err))?,
+            }
+        }
+        writer.flush()?;
     }
+
+    Ok(())
 }
 
-fn import_csv<R: std::io::Read>(shard: &mut Shard, mut csv_reader: csv::Reader<R>) {
-    for result in csv_reader.deserialize() {
-        let record: Result<Event, _> = result;
-        match record {
+fn open_output(
+    path: Option<&std::path::PathBuf>,
+    default: impl FnOnce() -> Box<dyn Write>,
+) -> Result<BufWriter<Box<dyn Write>>, String> {
+    let writer = match path {
+        Some(path) => {
+            Box::new(File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?)
+        }
+        None => default(),
+    };
+    Ok(BufWriter::new(writer))
+}
+
+// Csv errors also have the line, byte offset and text of the row that could not be read (when they
+// are known), so that the row can be found and fixed.
+fn error_json(err: &ShardError) -> String {
+    let mut json = format!(
+        "{{\"kind\":\"{}\",\"message\":\"{}\"",
+        err.kind(),
+        json_escape(&err.to_string())
+    );
+    if let ShardError::CsvParseError(err) = err {
+        if let Some(position) = err.position {
+            json.push_str(&format!(
+                ",\"line\":{},\"byte\":{}",
+                position.line, position.byte
+            ));
+        }
+        if let Some(record) = &err.record {
+            json.push_str(&format!(",\"record\":\"{}\"", json_escape(record)));
+        }
+    }
+    json.push('}');
+    json
+}
+
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// How amounts are read from, and written to, csv.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CsvFormat {
+    // The number of decimal places that amounts are stored and written with. Amounts in a specified
+    // currency always use the currency's minor unit instead.
+    scale: u32,
+    // How any extra decimal places in the input are handled.
+    rounding: RoundingMode,
+    // Whether the summaries always have a currency column. They also have one whenever a balance is
+    // in a specified currency, as a client's rows for several currencies can't be told apart
+    // without it.
+    currencies: bool,
+}
+
+impl Default for CsvFormat {
+    fn default() -> Self {
+        Self {
+            scale: Amount::DEFAULT_SCALE,
+            rounding: RoundingMode::default(),
+            currencies: false,
+        }
+    }
+}
+
+// Used by the `csv_test!` fixtures, which always read with the default format.
+#[cfg(test)]
+fn import_csv(sink: &mut impl EventSink, input: impl std::io::Read) {
+    import_csv_with(sink, input, CsvFormat::default())
+}
+
+// Reads the csv input, pushing each row to the sink as an event, or as an error if it isn't one.
+fn import_csv_with(sink: &mut impl EventSink, input: impl std::io::Read, format: CsvFormat) {
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(RawInput::new(input));
+
+    // Rows are read before they are deserialized, so that a row that is not a valid event can be
+    // kept along with its error, exactly as it was written.
+    let headers = match csv_reader.byte_headers() {
+        Ok(headers) => headers.clone(),
+        Err(e) => {
+            let row = csv_reader.get_ref().row(&e, csv_reader.position());
+            sink.push_error(ShardError::CsvParseError(CsvError::new(&e, row)));
+            return;
+        }
+    };
+    let mut record = csv::ByteRecord::new();
+    loop {
+        let start = csv_reader.position().byte();
+        csv_reader.get_mut().forget_before(start);
+        match csv_reader.read_byte_record(&mut record) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => {
+                // The row could not be read at all, but the reader can still move on to the next.
+                let row = csv_reader.get_ref().row(&e, csv_reader.position());
+                sink.push_error(ShardError::CsvParseError(CsvError::new(&e, row)));
+                continue;
+            }
+        }
+        // Amounts are parsed straight to the number of decimal places that they are kept with, so
+        // that an amount written with more decimal places than fit (eg: a long run of trailing
+        // zeros) is still read. Anything that fails is read again exactly as it is written, to
+        // report what is wrong with it.
+        let event = record
+            .deserialize::<EventRow>(Some(&headers))
+            .ok()
+            .and_then(|row| {
+                row.parse(|amount, currency| {
+                    parse_amount(amount, currency, format).ok_or("invalid amount")
+                })
+                .ok()
+            });
+        if let Some(event) = event {
+            sink.push_event(event);
+            continue;
+        }
+        match record.deserialize::<Event>(Some(&headers)) {
             Ok(event) => {
-                shard.push_event(event);
+                let rescaled = event.map_amount(|amount| {
+                    if amount.currency().is_unspecified() {
+                        amount.round(format.scale, format.rounding)
+                    } else {
+                        Ok(amount)
+                    }
+                });
+                match rescaled {
+                    Ok(event) => sink.push_event(event),
+                    Err(reason) => sink.push_error(ShardError::InvalidAmount { event, reason }),
+                }
             }
             Err(e) => {
-                shard.push_error(ShardError::CsvParseError(Rc::new(e)));
+                let row = csv_reader.get_ref().row(&e, csv_reader.position());
+                sink.push_error(ShardError::CsvParseError(CsvError::new(&e, row)));
             }
         }
     }
 }
 
-fn export_csv<W: std::io::Write>(shard: &Shard, writer: &mut W) {
-    let output = shard.generate_output_sorted();
+// Passes the input on to a csv reader, keeping what it has read since the start of the current row,
+// so that the row can be kept as it was written when it turns out to be bad. The csv reader reads
+// ahead, so this is the current row along with at most a buffer's worth of what follows it.
+struct RawInput<R> {
+    input: R,
+    kept: Vec<u8>,
+    // The offset of the first kept byte, from the start of the input.
+    start: u64,
+}
+
+impl<R> RawInput<R> {
+    fn new(input: R) -> Self {
+        Self {
+            input,
+            kept: Vec::new(),
+            start: 0,
+        }
+    }
+
+    // Forgets everything before the offset, which is the start of the next row. Dropping the bytes
+    // moves the rest of them, so it only happens once they are at least half of what is kept.
+    fn forget_before(&mut self, offset: u64) {
+        let len = usize::try_from(offset.saturating_sub(self.start))
+            .unwrap_or(usize::MAX)
+            .min(self.kept.len());
+        if len * 2 >= self.kept.len() {
+            self.kept.drain(..len);
+            self.start += len as u64;
+        }
+    }
+
+    // @returns the row that the error is for, from where it starts up to where the reader is (the
+    // start of the next row), if the error has a position
+    //
+    // The line terminators around it are left out: a row that follows a \r\n starts at the \n.
+    fn row(&self, err: &csv::Error, end: &csv::Position) -> Option<&[u8]> {
+        let from = usize::try_from(err.position()?.byte().checked_sub(self.start)?).ok()?;
+        let to = usize::try_from(end.byte().checked_sub(self.start)?).ok()?;
+        let row = self.kept.get(from..to.min(self.kept.len()))?;
+        let terminator = |byte: &u8| matches!(byte, b'\r' | b'\n');
+        let start = row.iter().take_while(|byte| terminator(byte)).count();
+        let end = row.len()
+            - row[start..]
+                .iter()
+                .rev()
+                .take_while(|byte| terminator(byte))
+                .count();
+        Some(&row[start..end])
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for RawInput<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.input.read(buf)?;
+        self.kept.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+}
+
+// @returns the amount parsed with the number of decimal places that the format keeps, or with the
+// currency's minor unit if it is in a specified currency (which are never rounded)
+fn parse_amount(amount: &str, currency: Currency, format: CsvFormat) -> Option<Amount> {
+    let parsed = if currency.is_unspecified() {
+        Amount::parse(amount, format.scale, format.rounding)
+    } else {
+        Amount::parse(amount, currency.minor_units(), RoundingMode::Reject)
+    };
+    parsed.ok()?.in_currency(currency)
+}
+
+// Used by the `csv_test!` fixtures, which write with the default format, along with a currency
+// column if the fixture's output has one.
+#[cfg(test)]
+fn export_csv<S: Store, W: std::io::Write>(
+    shard: &Shard<S>,
+    writer: &mut W,
+    currencies: bool,
+) -> csv::Result<()> {
+    let format = CsvFormat {
+        currencies,
+        ..CsvFormat::default()
+    };
+    export_csv_with(shard, writer, format)
+}
+
+fn export_csv_with<S: Store, W: std::io::Write>(
+    shard: &Shard<S>,
+    writer: &mut W,
+    format: CsvFormat,
+) -> csv::Result<()> {
+    // The accounts are gone through once up front, rather than holding on to the summaries, to find
+    // out whether the currency column is needed before the headers are written.
+    let currencies = format.currencies
+        || shard.summaries().any(|summary| {
+            summary
+                .currency
+                .is_some_and(|currency| !currency.is_unspecified())
+        });
+    let format = CsvFormat {
+        currencies,
+        ..format
+    };
+    write_summaries(shard.summaries(), writer, format)
+}
+
+fn write_summaries<W: std::io::Write>(
+    summaries: impl Iterator<Item = Summary>,
+    writer: &mut W,
+    format: CsvFormat,
+) -> csv::Result<()> {
     let mut csv_writer = csv::Writer::from_writer(writer);
-    for record in output {
-        csv_writer.serialize(record).unwrap();
+    for record in summaries {
+        let mut record = record.with_scale(format.scale).map_err(|e| {
+            csv::Error::from(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                e.to_string(),
+            ))
+        })?;
+        if !format.currencies {
+            record.currency = None;
+        }
+        csv_writer.serialize(record)?;
     }
-    csv_writer.flush().unwrap();
+    csv_writer.flush()?;
+    Ok(())
 }
 
 #[cfg(test)]
@@ -79,10 +412,237 @@ mod tests {
     use super::*;
     use csv_test_proc::csv_test;
 
+    #[test]
+    fn test_json_escape() {
+        assert_eq!(json_escape("plain"), "plain");
+        assert_eq!(
+            json_escape("a \"quoted\" \\ path\n"),
+            "a \\\"quoted\\\" \\\\ path\\n"
+        );
+        assert_eq!(json_escape("\u{1}"), "\\u0001");
+    }
+
+    #[test]
+    fn test_csv_parse_errors() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, x, 1.0
+withdrawal, 1
+";
+        let mut shard = Shard::new();
+        import_csv(&mut shard, input.as_bytes());
+        assert_eq!(shard.events().len(), 1);
+
+        let errors = csv_errors(&shard);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].position, Some(CsvPosition { byte: 44, line: 3 }));
+        // Rows are kept as they were written, without being trimmed.
+        assert_eq!(errors[0].record.as_deref(), Some("deposit, 1, x, 1.0"));
+        // Including a row with too few fields, which can't be read at all.
+        assert_eq!(errors[1].position, Some(CsvPosition { byte: 63, line: 4 }));
+        assert_eq!(errors[1].record.as_deref(), Some("withdrawal, 1"));
+
+        let json = error_json(&shard.errors()[0]);
+        assert!(json.starts_with("{\"kind\":\"CsvParseError\",\"message\":\"CSV parse error: "));
+        assert!(json.ends_with(",\"line\":3,\"byte\":44,\"record\":\"deposit, 1, x, 1.0\"}"));
+    }
+
+    #[test]
+    fn test_csv_parse_errors_raw_rows() {
+        // Enough rows that the reader has to read ahead more than once, with bad rows that are
+        // quoted, end in \r\n, or are not valid utf-8.
+        let mut input = b"type,client,tx,amount\r\n".to_vec();
+        for tx in 1..=5000 {
+            input.extend_from_slice(format!("deposit,1,{},1.0\r\n", tx).as_bytes());
+            if tx % 1000 == 0 {
+                input.extend_from_slice(b"deposit, \"1\" ,\"x,y\",1.0\r\n");
+                input.extend_from_slice(b"dep\xffosit,1,1,1.0\r\n");
+            }
+        }
+        input.extend_from_slice(b"deposit,1");
+
+        let mut shard = Shard::new();
+        import_csv(&mut shard, &input[..]);
+        assert_eq!(shard.events().len(), 5000);
+        let records: Vec<_> = csv_errors(&shard)
+            .into_iter()
+            .map(|err| err.record.as_deref().unwrap())
+            .collect();
+        assert_eq!(records.len(), 11);
+        for pair in records[..10].chunks(2) {
+            assert_eq!(
+                pair,
+                ["deposit, \"1\" ,\"x,y\",1.0", "dep\u{FFFD}osit,1,1,1.0"]
+            );
+        }
+        assert_eq!(records[10], "deposit,1");
+    }
+
+    fn csv_errors(shard: &Shard) -> Vec<&CsvError> {
+        shard
+            .errors()
+            .iter()
+            .map(|err| match err {
+                ShardError::CsvParseError(err) => err,
+                err => panic!("unexpected error: {}", err),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_csv_format_scale() {
+        // Large enough to overflow with 8 decimal places, but not with 2.
+        let large = AmountValue::MAX / 100_000;
+        let input = format!(
+            "type, client, tx, amount
+deposit, 1, 1, 0.12345678
+deposit, 1, 2, 1.5
+withdrawal, 1, 3, 0.00000001
+deposit, 2, 4, {}
+",
+            large
+        );
+        let convert = |scale| {
+            let mut shard = Shard::new();
+            import_csv_with(
+                &mut shard,
+                input.as_bytes(),
+                CsvFormat {
+                    scale,
+                    ..CsvFormat::default()
+                },
+            );
+
+            let mut got = Vec::new();
+            export_csv_with(
+                &shard,
+                &mut got,
+                CsvFormat {
+                    scale,
+                    ..CsvFormat::default()
+                },
+            )
+            .unwrap();
+            (String::from_utf8(got).unwrap(), shard.errors().len())
+        };
+
+        // The withdrawal is truncated to nothing with 2 decimal places, so it is rejected.
+        assert_eq!(
+            convert(2),
+            (
+                format!(
+                    "client,available,held,total,locked
+1,1.62,0.00,1.62,false
+2,{}.00,0.00,{}.00,false
+",
+                    large, large
+                ),
+                1
+            )
+        );
+        // The last deposit does not fit with 8 decimal places.
+        assert_eq!(
+            convert(8),
+            (
+                "client,available,held,total,locked
+1,1.62345677,0.00000000,1.62345677,false
+"
+                .to_string(),
+                1
+            )
+        );
+    }
+
+    #[test]
+    fn test_csv_currency_column() {
+        let export = |input: &str, currencies| {
+            let mut shard = Shard::new();
+            import_csv(&mut shard, input.as_bytes());
+            let mut got = Vec::new();
+            export_csv(&shard, &mut got, currencies).unwrap();
+            String::from_utf8(got).unwrap()
+        };
+
+        // Without the column, the rows of client 1 could not be told apart.
+        let input = "type, client, tx, amount, currency
+deposit, 1, 1, 1.0,
+deposit, 1, 2, 2.50, USD
+";
+        let want = "client,currency,available,held,total,locked
+1,,1.0000,0.0000,1.0000,false
+1,USD,2.50,0.00,2.50,false
+";
+        assert_eq!(export(input, false), want);
+        assert_eq!(export(input, true), want);
+
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+";
+        assert_eq!(
+            export(input, false),
+            "client,available,held,total,locked
+1,1.0000,0.0000,1.0000,false
+"
+        );
+    }
+
+    #[test]
+    fn test_csv_format_rounding() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.00005
+deposit, 1, 2, 1.00015
+deposit, 2, 3, 2.5
+";
+        let convert = |rounding| {
+            let mut shard = Shard::new();
+            let format = CsvFormat {
+                rounding,
+                ..CsvFormat::default()
+            };
+            import_csv_with(&mut shard, input.as_bytes(), format);
+
+            let mut got = Vec::new();
+            export_csv_with(&shard, &mut got, format).unwrap();
+            let errors: Vec<_> = shard.errors().iter().map(|e| e.to_string()).collect();
+            (String::from_utf8(got).unwrap(), errors)
+        };
+
+        let (got, errors) = convert(RoundingMode::Truncate);
+        assert!(got.contains("1,2.0001,"), "{}", got);
+        assert!(errors.is_empty(), "{:?}", errors);
+        let (got, _) = convert(RoundingMode::HalfUp);
+        assert!(got.contains("1,2.0003,"), "{}", got);
+        let (got, _) = convert(RoundingMode::HalfEven);
+        assert!(got.contains("1,2.0002,"), "{}", got);
+
+        let (got, errors) = convert(RoundingMode::Reject);
+        assert_eq!(
+            got,
+            "client,available,held,total,locked\n2,2.5000,0.0000,2.5000,false\n"
+        );
+        assert_eq!(
+            errors,
+            vec![
+                "Event deposit (client 1, tx 1, amount 1.00005) rejected because the amount 1.00005 has more than 4 decimal places",
+                "Event deposit (client 1, tx 2, amount 1.00015) rejected because the amount 1.00015 has more than 4 decimal places",
+            ]
+        );
+    }
+
     csv_test!("1_simple");
     csv_test!("2_reorder_headings");
     csv_test!("3_invalid_rows");
     csv_test!("4_overflow");
     csv_test!("5_hold");
     csv_test!("6_chargeback");
+    csv_test!("7_insufficient_funds");
+    csv_test!("8_locked");
+    csv_test!("9_duplicate_transaction");
+    csv_test!("10_client_mismatch");
+    csv_test!("11_currencies");
+    csv_test!("12_large_amounts");
+    csv_test!("13_transfers");
+    csv_test!("14_partial_disputes");
+    csv_test!("15_refunds");
+    csv_test!("16_lent_accounts");
 }
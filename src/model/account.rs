@@ -1,9 +1,11 @@
-use super::{Amount, AmountOpError};
+use super::{Amount, AmountOpError, Currency, TransactionError};
+use crate::{encode_len, Decode, DecodeError, Encode, EncodeError};
+use std::collections::BTreeMap;
 
-#[derive(Default, Debug, Clone, Copy)]
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub struct Account {
-    total: Amount,
-    held: Amount,
+    // Each currency is kept in a separate balance, they are never mixed.
+    balances: BTreeMap<Currency, Balance>,
     locked: bool,
 }
 
@@ -12,18 +14,30 @@ impl Account {
         Self::default()
     }
 
-    pub fn total(&self) -> Amount {
-        self.total
+    // @returns the balance in the given currency, which is zero if the currency has never been used
+    pub fn balance(&self, currency: Currency) -> Balance {
+        self.balances
+            .get(&currency)
+            .copied()
+            .unwrap_or_else(|| Balance::new(currency))
     }
 
-    pub fn held(&self) -> Amount {
-        self.held
+    // @returns every balance of the account, sorted by currency
+    //
+    // An account that has never held any funds still has a (zero) balance in the unspecified
+    // currency, so that it shows up in the output.
+    pub fn balances(&self) -> impl Iterator<Item = Balance> + '_ {
+        let empty = self
+            .balances
+            .is_empty()
+            .then(|| Balance::new(Currency::UNSPECIFIED));
+        self.balances.values().copied().chain(empty)
     }
 
-    pub fn available(&self) -> Amount {
-        // This should never fail, since `total` should always be greater than or equal to `held`,
-        // and `held` should never be negative.
-        (self.total - self.held).unwrap_or_default()
+    fn balance_mut(&mut self, currency: Currency) -> &mut Balance {
+        self.balances
+            .entry(currency)
+            .or_insert_with(|| Balance::new(currency))
     }
 
     pub fn is_locked(&self) -> bool {
@@ -39,29 +53,45 @@ impl Account {
     }
 
     pub fn deposit(&mut self, amount: Amount) -> Result<(), AmountOpError> {
-        match self.total + amount {
+        let balance = self.balance_mut(amount.currency());
+        match balance.total + amount {
             Ok(value) => {
-                self.total = value;
+                balance.total = value;
                 Ok(())
             }
             Err(e) => Err(e),
         }
     }
 
-    pub fn withdraw(&mut self, amount: Amount) -> Result<(), AmountOpError> {
-        match self.total - amount {
+    // Withdrawals can only be made from the available funds, anything else is rejected and leaves
+    // the account unchanged. So is a negative amount, which would credit the account instead.
+    pub fn withdraw(&mut self, amount: Amount) -> Result<(), TransactionError> {
+        if amount < Amount::zero(amount.currency()) {
+            return Err(TransactionError::NonPositiveAmount { amount });
+        }
+        let balance = self.balance_mut(amount.currency());
+        let available = balance.available();
+        if amount > available {
+            return Err(TransactionError::InsufficientFunds {
+                requested: amount,
+                available,
+            });
+        }
+
+        match balance.total - amount {
             Ok(value) => {
-                self.total = value;
+                balance.total = value;
                 Ok(())
             }
-            Err(e) => Err(e),
+            Err(e) => Err(e.into()),
         }
     }
 
     pub fn hold(&mut self, amount: Amount) -> Result<(), AmountOpError> {
-        match self.held + amount {
+        let balance = self.balance_mut(amount.currency());
+        match balance.held + amount {
             Ok(value) => {
-                self.held = value;
+                balance.held = value;
                 Ok(())
             }
             Err(e) => Err(e),
@@ -69,29 +99,52 @@ impl Account {
     }
 
     pub fn release(&mut self, amount: Amount) -> Result<(), AmountOpError> {
-        match self.held - amount {
+        let balance = self.balance_mut(amount.currency());
+        match balance.held - amount {
             Ok(value) => {
-                self.held = value;
+                balance.held = value;
                 Ok(())
             }
             Err(e) => Err(e),
         }
     }
 
+    // @returns a copy of the account's balances in the given currencies, and whether it is locked,
+    // which `rollback` can put back. This is much cheaper than a copy of the whole account.
+    pub fn checkpoint(&self, currencies: [Currency; 2]) -> Checkpoint {
+        Checkpoint {
+            balances: currencies.map(|currency| (currency, self.balances.get(&currency).copied())),
+            locked: self.locked,
+        }
+    }
+
+    // Undoes any changes made to the account since the checkpoint, as long as they were only to the
+    // balances in its currencies.
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        for (currency, balance) in checkpoint.balances {
+            match balance {
+                Some(balance) => self.balances.insert(currency, balance),
+                None => self.balances.remove(&currency),
+            };
+        }
+        self.locked = checkpoint.locked;
+    }
+
     pub fn chargeback(&mut self, amount: Amount, held: bool) -> Result<(), AmountOpError> {
         self.lock();
 
+        let balance = self.balance_mut(amount.currency());
         match (
-            self.total - amount,
+            balance.total - amount,
             if held {
-                self.held - amount
+                balance.held - amount
             } else {
-                Ok(self.held)
+                Ok(balance.held)
             },
         ) {
             (Ok(new_total), Ok(new_held)) => {
-                self.total = new_total;
-                self.held = new_held;
+                balance.total = new_total;
+                balance.held = new_held;
                 Ok(())
             }
             (Err(e), _) => Err(e),
@@ -99,3 +152,75 @@ impl Account {
         }
     }
 }
+
+// The parts of an account that an event can change, see `Account::checkpoint`. A balance is None if
+// the account had no balance in that currency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    balances: [(Currency, Option<Balance>); 2],
+    locked: bool,
+}
+
+// The funds that an account has in a single currency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Balance {
+    total: Amount,
+    held: Amount,
+}
+
+impl Balance {
+    pub fn new(currency: Currency) -> Self {
+        Self {
+            total: Amount::zero(currency),
+            held: Amount::zero(currency),
+        }
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.total.currency()
+    }
+
+    pub fn total(&self) -> Amount {
+        self.total
+    }
+
+    pub fn held(&self) -> Amount {
+        self.held
+    }
+
+    pub fn available(&self) -> Amount {
+        // This should never fail, since `total` should always be greater than or equal to `held`,
+        // and `held` should never be negative.
+        (self.total - self.held).unwrap_or_else(|_| Amount::zero(self.currency()))
+    }
+}
+
+// An account is written as whether it is locked, followed by the number of balances, and then each
+// balance.
+impl Encode for Account {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        self.locked.encode(out)?;
+        encode_len(self.balances.len(), out)?;
+        for balance in self.balances.values() {
+            balance.total.encode(out)?;
+            balance.held.encode(out)?;
+        }
+        Ok(())
+    }
+}
+
+impl Decode for Account {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let locked = bool::decode(input)?;
+        let mut balances = BTreeMap::new();
+        for _ in 0..u32::decode(input)? {
+            let total = Amount::decode(input)?;
+            let held = Amount::decode(input)?;
+            if held.currency() != total.currency() {
+                return Err(DecodeError::new("balance mixes currencies"));
+            }
+            balances.insert(total.currency(), Balance { total, held });
+        }
+        Ok(Self { balances, locked })
+    }
+}
@@ -1,76 +1,475 @@
 use std::{
     fmt::{Display, Formatter, Result as FmtResult},
+    hash::{Hash, Hasher},
     ops::{Add, Neg, Sub},
+    str::FromStr,
 };
 
+use super::Currency;
+use crate::{Decode, DecodeError, Encode, EncodeError};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+// The integer type that amounts are stored as. Enabling the `i128` feature widens it, for balances
+// (or amounts with many decimal places) that would not fit in an i64.
+#[cfg(not(feature = "i128"))]
+pub type AmountValue = i64;
+#[cfg(feature = "i128")]
+pub type AmountValue = i128;
+
+const TEN: AmountValue = 10;
+
 // A value that will be used to represent the amount of a currency.
 // A currency amount should _never_ be represented as a floating point number, due to potentially accumulating rounding errors.
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct Amount(i64);
+//
+// The value is stored as an integer number of units of `10^-scale`, so that feeds with different
+// precisions (eg: 2 decimal places for cents, or 8 for some crypto currencies) can be represented
+// exactly. Amounts with different scales are still equal if they represent the same number.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Amount {
+    value: AmountValue,
+    scale: u32,
+    currency: Currency,
+}
 
 impl Amount {
-    pub fn new(value: i64) -> Self {
-        Self(value)
+    // The number of decimal places used when none is given.
+    pub const DEFAULT_SCALE: u32 = 4;
+    // The largest number of decimal places that can be represented, `10^MAX_SCALE` still has to fit
+    // in an `AmountValue`.
+    #[cfg(not(feature = "i128"))]
+    pub const MAX_SCALE: u32 = 18;
+    #[cfg(feature = "i128")]
+    pub const MAX_SCALE: u32 = 38;
+
+    // Creates an amount in the unspecified currency, with the default scale.
+    pub fn new(value: AmountValue) -> Self {
+        Self::with_scale(value, Self::DEFAULT_SCALE)
+    }
+
+    // Creates an amount in the unspecified currency, with the given number of decimal places.
+    pub fn with_scale(value: AmountValue, scale: u32) -> Self {
+        debug_assert!(scale <= Self::MAX_SCALE);
+        Self {
+            value,
+            scale,
+            currency: Currency::UNSPECIFIED,
+        }
+    }
+
+    pub fn zero(currency: Currency) -> Self {
+        Self {
+            value: 0,
+            scale: currency.minor_units(),
+            currency,
+        }
+    }
+
+    fn with_value(self, value: AmountValue) -> Self {
+        Self { value, ..self }
+    }
+
+    pub fn currency(&self) -> Currency {
+        self.currency
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    // @returns the same value in the given currency, or None if the value is more precise than the
+    // currency's minor unit allows (eg: a fraction of a cent)
+    //
+    // The amount is rescaled to the currency's minor unit, unless the currency is unspecified.
+    pub fn in_currency(self, currency: Currency) -> Option<Self> {
+        let amount = if currency.is_unspecified() {
+            self
+        } else {
+            self.rescale(currency.minor_units()).ok()?
+        };
+        Some(Self { currency, ..amount })
+    }
+
+    // @returns the same value with the given number of decimal places, or an error if it does not
+    // fit, or if it would lose any of its (non-zero) decimal places
+    pub fn rescale(self, scale: u32) -> Result<Self, RescaleError> {
+        let error = |kind| RescaleError {
+            amount: self,
+            scale,
+            kind,
+        };
+
+        if scale >= self.scale {
+            TEN.checked_pow(scale - self.scale)
+                .and_then(|factor| self.value.checked_mul(factor))
+                .map(|value| Self {
+                    value,
+                    scale,
+                    ..self
+                })
+                .ok_or_else(|| error(RescaleErrorKind::Overflow))
+        } else {
+            let factor = TEN.pow(self.scale - scale);
+            if self.value % factor != 0 {
+                return Err(error(RescaleErrorKind::Inexact));
+            }
+            Ok(Self {
+                value: self.value / factor,
+                scale,
+                ..self
+            })
+        }
+    }
+
+    // @returns the same value with the given number of decimal places, dropping any extra decimal
+    // places, or an error if it does not fit
+    pub fn truncate(self, scale: u32) -> Result<Self, RescaleError> {
+        self.round(scale, RoundingMode::Truncate)
+    }
+
+    // @returns the same value with the given number of decimal places, rounding away any extra
+    // decimal places using the given mode, or an error if it does not fit (or if it would be
+    // rounded when using `RoundingMode::Reject`)
+    pub fn round(self, scale: u32, mode: RoundingMode) -> Result<Self, RescaleError> {
+        if scale >= self.scale {
+            return self.rescale(scale);
+        }
+        let factor = TEN.pow(self.scale - scale);
+        let (quotient, remainder) = (self.value / factor, self.value % factor);
+
+        // The factor is a power of 10 (and at least 10), so half of it is exact.
+        let (remainder, half) = (remainder.unsigned_abs(), factor.unsigned_abs() / 2);
+        let round_away = match mode {
+            RoundingMode::Truncate => false,
+            RoundingMode::HalfUp => remainder >= half,
+            RoundingMode::HalfEven => remainder > half || (remainder == half && quotient % 2 != 0),
+            RoundingMode::Reject => return self.rescale(scale),
+        };
+
+        // The quotient is at most a tenth of the largest value, so this can never overflow.
+        let value = match (round_away, self.value < 0) {
+            (false, _) => quotient,
+            (true, false) => quotient + 1,
+            (true, true) => quotient - 1,
+        };
+        Ok(Self {
+            value,
+            scale,
+            ..self
+        })
     }
 
-    pub fn from_str(s: &str) -> Option<Self> {
-        let negative = s.starts_with('-');
+    // Parses an amount with the given number of decimal places, any extra decimal places are
+    // rounded away using the given mode (or rejected, when using `RoundingMode::Reject`).
+    //
+    // The grammar is strict: `[+-]digits[.digits]`, where `digits` is one or more ASCII digits.
+    // There can be no whitespace, no exponent, and no sign anywhere but at the very start.
+    pub fn parse(s: &str, scale: u32, mode: RoundingMode) -> Result<Self, AmountParseError> {
+        use AmountParseErrorKind::*;
+        let error = |kind| AmountParseError {
+            input: s.to_string(),
+            kind,
+        };
 
-        let s = if negative { &s[1..] } else { s };
-        let mut parts = s.splitn(2, '.');
+        let (negative, unsigned) = match s.as_bytes().first() {
+            None => return Err(error(Empty)),
+            Some(b'-') => (true, &s[1..]),
+            Some(b'+') => (false, &s[1..]),
+            Some(_) => (false, s),
+        };
+        let (integer, fraction) = match unsigned.split_once('.') {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (unsigned, None),
+        };
 
-        // Unwrap will never panic here, because splitting an empty string will return an iterator with one empty string.
-        let value: Result<i64, _> = parts.next().unwrap().parse();
-        if value.is_err() {
-            return None;
+        // Check every character first, so that a stray sign or a second decimal point is reported
+        // as such, rather than as a missing part.
+        let digits = integer.chars().chain(fraction.unwrap_or_default().chars());
+        if let Some(c) = digits.clone().find(|c| !c.is_ascii_digit()) {
+            return Err(error(UnexpectedCharacter(c)));
+        }
+        if integer.is_empty() {
+            return Err(error(MissingInteger));
         }
-        let value = value.unwrap();
-        if value.checked_mul(10000).is_none() {
-            // We store the fractional part as the lowest 4 digits of the integer.
-            // If the integer part is too large, we can't store the fractional part.
-            return None;
+        if fraction == Some("") {
+            return Err(error(MissingFraction));
+        }
+        if scale > Self::MAX_SCALE {
+            return Err(error(Overflow));
         }
 
-        let frac: Result<u64, _> = parts
-            .next()
-            .map_or(Ok(0), |s| format!("{}0000", s)[0..4].parse());
-        if frac.is_err() {
-            return None;
+        // Build the magnitude as an integer number of `10^-scale` units. The fractional part is
+        // padded to exactly `scale` digits, the rest of the digits only decide how it is rounded.
+        let digit = |c: char| c as u128 - '0' as u128;
+        let mut fraction = fraction.unwrap_or_default().chars();
+        let mut magnitude: u128 = 0;
+        for c in integer.chars().chain(
+            fraction
+                .by_ref()
+                .chain(std::iter::repeat('0'))
+                .take(scale as usize),
+        ) {
+            magnitude = magnitude
+                .checked_mul(10)
+                .and_then(|magnitude| magnitude.checked_add(digit(c)))
+                .ok_or_else(|| error(Overflow))?;
         }
-        let mut frac = frac.unwrap();
-        while frac >= 10000 {
-            // We have too many fractional decimal places.
-            // Truncate the last digit until we have exactly 4.
-            // Should this be rounded instead?
-            frac /= 10;
+
+        // Compare the dropped digits to half of a unit in the last decimal place that is kept.
+        let first_dropped = fraction.next().map(digit);
+        let rest_dropped = fraction.any(|c| c != '0');
+        let round_away = match mode {
+            RoundingMode::Truncate => false,
+            RoundingMode::HalfUp => first_dropped >= Some(5),
+            RoundingMode::HalfEven => {
+                first_dropped > Some(5)
+                    || (first_dropped == Some(5) && (rest_dropped || !magnitude.is_multiple_of(2)))
+            }
+            RoundingMode::Reject => {
+                if first_dropped.is_some_and(|d| d != 0) || rest_dropped {
+                    return Err(error(TooPrecise { scale }));
+                }
+                false
+            }
+        };
+        if round_away {
+            magnitude += 1;
         }
 
-        if !negative {
-            (10000 * value).checked_add(frac as i64).map(Self::new)
+        let value = if !negative {
+            AmountValue::try_from(magnitude)
         } else {
-            (-10000 * value).checked_sub(frac as i64).map(Self::new)
+            // The magnitude of the smallest value is one more than the largest value, so it can't
+            // be negated after it has been converted.
+            magnitude.checked_sub(1).map_or(Ok(0), |magnitude| {
+                AmountValue::try_from(magnitude).map(|m| -m - 1)
+            })
+        };
+        let value = value.map_err(|_| error(Overflow))?;
+        Ok(Self::with_scale(value, scale))
+    }
+
+    // Parses an amount, keeping every decimal place that is given (up to `MAX_SCALE`, any extra
+    // decimal places are truncated).
+    pub fn parse_exact(s: &str) -> Result<Self, AmountParseError> {
+        let scale = s
+            .split_once('.')
+            .map_or(0, |(_, fraction)| fraction.chars().count());
+        Self::parse(
+            s,
+            (scale as u32).min(Self::MAX_SCALE),
+            RoundingMode::Truncate,
+        )
+    }
+
+    // @returns the amount multiplied by `num / den`, rounded to the amount's scale using the given
+    // mode (eg: converting between currencies at a given rate)
+    //
+    // The product of the amount and `num` is computed as an i128, so it can be larger than an amount
+    // can hold, as long as the result is not (this is not the case with the `i128` feature).
+    pub fn checked_mul_ratio(
+        self,
+        num: AmountValue,
+        den: AmountValue,
+        mode: RoundingMode,
+    ) -> Result<Self, AmountOpError> {
+        let error = |kind| AmountOpError {
+            lhs: self,
+            rhs: Some(Operand::Ratio(num, den)),
+            op: "*",
+            kind,
+        };
+
+        let product = wide(self.value)
+            .checked_mul(wide(num))
+            .ok_or_else(|| error(AmountOpErrorKind::Overflow))?;
+        let value = div_round(product, wide(den), mode).map_err(error)?;
+        AmountValue::try_from(value)
+            .map(|value| self.with_value(value))
+            .map_err(|_| error(AmountOpErrorKind::Overflow))
+    }
+
+    // @returns the given number of basis points (hundredths of a percent) of the amount, rounded to
+    // the amount's scale using the given mode, eg: 250 bps of 10.00 is 0.25
+    pub fn percent(self, bps: AmountValue, mode: RoundingMode) -> Result<Self, AmountOpError> {
+        self.checked_mul_ratio(bps, 10_000, mode)
+    }
+
+    // @returns the amount split into one part for each of the weights, in proportion to them
+    //
+    // The parts always add up to exactly the original amount. Each part is first rounded towards
+    // zero, and then the units that are left over are handed out, one at a time, to the parts that
+    // lost the most to rounding (the earliest part wins a tie).
+    pub fn allocate(self, weights: &[u64]) -> Result<Vec<Self>, AmountOpError> {
+        let total: u128 = weights.iter().map(|weight| u128::from(*weight)).sum();
+        let error = |kind| AmountOpError {
+            lhs: self,
+            rhs: Some(Operand::Weights(total)),
+            op: "allocated by",
+            kind,
+        };
+        if total == 0 {
+            return Err(error(AmountOpErrorKind::DivisionByZero));
+        }
+        let total = i128::try_from(total).map_err(|_| error(AmountOpErrorKind::Overflow))?;
+
+        let mut parts = Vec::with_capacity(weights.len());
+        let mut remainders = Vec::with_capacity(weights.len());
+        let mut left_over = wide(self.value);
+        for (i, weight) in weights.iter().enumerate() {
+            let product = wide(self.value)
+                .checked_mul(i128::from(*weight))
+                .ok_or_else(|| error(AmountOpErrorKind::Overflow))?;
+            let part = product / total;
+            parts.push(part);
+            remainders.push((i, (product % total).unsigned_abs()));
+            left_over -= part;
         }
+
+        // At most one unit is left over for each part, so every part gets at most one of them.
+        remainders.sort_by(|(a_index, a), (b_index, b)| b.cmp(a).then(a_index.cmp(b_index)));
+        let unit = left_over.signum();
+        for (i, _) in remainders
+            .into_iter()
+            .take(left_over.unsigned_abs() as usize)
+        {
+            parts[i] += unit;
+        }
+
+        // Every part is between zero and the original value, so it always fits.
+        Ok(parts
+            .into_iter()
+            .map(|part| self.with_value(part as AmountValue))
+            .collect())
+    }
+
+    // @returns the values of both amounts with the same (larger) scale, or None if either of them
+    // does not fit at that scale
+    fn aligned(self, rhs: Self) -> Option<(AmountValue, AmountValue, u32)> {
+        let scale = self.scale.max(rhs.scale);
+        let lhs = self.rescale(scale).ok()?;
+        let rhs = rhs.rescale(scale).ok()?;
+        Some((lhs.value, rhs.value, scale))
     }
 
-    pub fn to_string(&self) -> String {
-        // Maximum length of a formatted number is 21 characters.
-        // i64::max() == 9,223,372,036,854,775,807 (19 characters)
-        // 19 + 1 (dot) + 1 (negative sign) = 21
-        let mut s = String::with_capacity(21);
-        let (whole, frac) = (self.0 / 10000, self.0 % 10000);
-        s.push_str(&whole.to_string());
-        s.push('.');
-        s.push_str(&format!("{:04}", frac.abs()));
+    // Compares the values of two amounts that may have different scales, without rescaling either of
+    // them (which could overflow).
+    fn cmp_value(&self, other: &Self) -> std::cmp::Ordering {
+        if self.scale > other.scale {
+            return other.cmp_value(self).reverse();
+        }
 
-        s
+        // Split the more precise value into the part that has the same scale as `self`, and the
+        // rest, eg: comparing 1.2 to 1.25 compares 12 to 12, and then 0 to 5.
+        let (quotient, remainder) = match TEN.checked_pow(other.scale - self.scale) {
+            Some(factor) => (other.value / factor, other.value % factor),
+            None => (0, other.value),
+        };
+        self.value.cmp(&quotient).then(0.cmp(&remainder))
     }
 }
 
+// Widens a value to an i128, so that multiplying two of them can't overflow (unless the values are
+// already stored as an i128).
+#[allow(clippy::useless_conversion)] // not useless when amounts are stored as an i64
+fn wide(value: AmountValue) -> i128 {
+    i128::from(value)
+}
+
+// @returns `num / den`, rounded with the given mode
+fn div_round(num: i128, den: i128, mode: RoundingMode) -> Result<i128, AmountOpErrorKind> {
+    if den == 0 {
+        return Err(AmountOpErrorKind::DivisionByZero);
+    }
+    let quotient = num.checked_div(den).ok_or(AmountOpErrorKind::Overflow)?;
+    let remainder = (num % den).unsigned_abs();
+    if remainder == 0 {
+        return Ok(quotient);
+    }
+
+    // Compare the remainder to half of the denominator, without overflowing.
+    let other_half = den.unsigned_abs() - remainder;
+    let round_away = match mode {
+        RoundingMode::Truncate => false,
+        RoundingMode::HalfUp => remainder >= other_half,
+        RoundingMode::HalfEven => {
+            remainder > other_half || (remainder == other_half && quotient % 2 != 0)
+        }
+        RoundingMode::Reject => return Err(AmountOpErrorKind::Inexact),
+    };
+
+    // There is a remainder, so the denominator is at least 2, and this can never overflow.
+    Ok(match (round_away, (num < 0) != (den < 0)) {
+        (false, _) => quotient,
+        (true, false) => quotient + 1,
+        (true, true) => quotient - 1,
+    })
+}
+
+// Parses an amount with the default number of decimal places, any extra decimal places are
+// truncated.
+impl FromStr for Amount {
+    type Err = AmountParseError;
+
+    fn from_str(s: &str) -> Result<Self, AmountParseError> {
+        Self::parse(s, Self::DEFAULT_SCALE, RoundingMode::Truncate)
+    }
+}
+
+impl PartialEq for Amount {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Amount {}
+
+impl PartialOrd for Amount {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Amount {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.cmp_value(other)
+            .then(self.currency.cmp(&other.currency))
+    }
+}
+
+impl Hash for Amount {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Equal amounts must hash the same, regardless of their scale, so remove any trailing zeros.
+        let (mut value, mut scale) = (self.value, self.scale);
+        while scale > 0 && value % 10 == 0 {
+            value /= 10;
+            scale -= 1;
+        }
+        (value, scale, self.currency).hash(state);
+    }
+}
+
+// Formats the amount with as many decimal places as its scale, or as many as the precision that is
+// given (eg: `{:.2}`), dropping or padding decimal places as needed.
 impl Display for Amount {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-        write!(f, "{}", self.to_string())
+        // Split the magnitude rather than the value, so that the sign is not lost when the whole
+        // part is zero (eg: -0.5).
+        let unit = 10u128.pow(self.scale);
+        #[allow(clippy::useless_conversion)] // not useless when amounts are stored as an i64
+        let magnitude = u128::from(self.value.unsigned_abs());
+        let (whole, frac) = (magnitude / unit, magnitude % unit);
+        let sign = if self.value < 0 { "-" } else { "" };
+        match f.precision().unwrap_or(self.scale as usize) {
+            0 => write!(f, "{}{}", sign, whole),
+            digits => {
+                let frac: String = format!("{:0width$}", frac, width = self.scale as usize)
+                    .chars()
+                    .chain(std::iter::repeat('0'))
+                    .take(digits)
+                    .collect();
+                write!(f, "{}{}.{}", sign, whole, frac)
+            }
+        }
     }
 }
 
@@ -80,7 +479,7 @@ impl<'de> Deserialize<'de> for Amount {
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        Amount::from_str(&s).ok_or_else(|| serde::de::Error::custom("invalid amount"))
+        Amount::parse_exact(&s).map_err(serde::de::Error::custom)
     }
 }
 
@@ -93,17 +492,53 @@ impl Serialize for Amount {
     }
 }
 
+// The value is always written as an i128, so that amounts can be read back with or without the `i128`
+// feature, as long as they fit.
+pub(crate) fn encode_value(value: AmountValue, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+    wide(value).encode(out)
+}
+
+pub(crate) fn decode_value(input: &mut &[u8]) -> Result<AmountValue, DecodeError> {
+    AmountValue::try_from(i128::decode(input)?).map_err(|_| DecodeError::new("amount is too large"))
+}
+
+impl Encode for Amount {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        encode_value(self.value, out)?;
+        self.scale.encode(out)?;
+        self.currency.encode(out)?;
+        Ok(())
+    }
+}
+
+impl Decode for Amount {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let value = decode_value(input)?;
+        let scale = u32::decode(input)?;
+        if scale > Self::MAX_SCALE {
+            return Err(DecodeError::new("amount has too many decimal places"));
+        }
+        let currency = Currency::decode(input)?;
+        Ok(Self {
+            value,
+            scale,
+            currency,
+        })
+    }
+}
+
 impl Neg for Amount {
     type Output = Result<Self, AmountOpError>;
 
     fn neg(self) -> Result<Self, AmountOpError> {
-        if let Some(value) = self.0.checked_neg() {
-            Ok(Self::new(value))
+        if let Some(value) = self.value.checked_neg() {
+            Ok(self.with_value(value))
         } else {
             Err(AmountOpError {
                 lhs: self,
                 rhs: None,
                 op: "-",
+                kind: AmountOpErrorKind::Overflow,
             })
         }
     }
@@ -113,14 +548,25 @@ impl Add<Amount> for Amount {
     type Output = Result<Self, AmountOpError>;
 
     fn add(self, rhs: Self) -> Result<Self, AmountOpError> {
-        if let Some(value) = self.0.checked_add(rhs.0) {
-            Ok(Self::new(value))
-        } else {
-            Err(AmountOpError {
-                lhs: self,
-                rhs: Some(rhs),
-                op: "+",
+        let error = |kind| AmountOpError {
+            lhs: self,
+            rhs: Some(Operand::Amount(rhs)),
+            op: "+",
+            kind,
+        };
+
+        if self.currency != rhs.currency {
+            Err(error(AmountOpErrorKind::CurrencyMismatch))
+        } else if let Some(value) = self.aligned(rhs).and_then(|(lhs, rhs, scale)| {
+            Some(Self {
+                value: lhs.checked_add(rhs)?,
+                scale,
+                ..self
             })
+        }) {
+            Ok(value)
+        } else {
+            Err(error(AmountOpErrorKind::Overflow))
         }
     }
 }
@@ -129,50 +575,712 @@ impl Sub<Amount> for Amount {
     type Output = Result<Self, AmountOpError>;
 
     fn sub(self, rhs: Self) -> Result<Self, AmountOpError> {
-        if let Some(value) = self.0.checked_sub(rhs.0) {
-            Ok(Self::new(value))
-        } else {
-            Err(AmountOpError {
-                lhs: self,
-                rhs: Some(rhs),
-                op: "-",
+        let error = |kind| AmountOpError {
+            lhs: self,
+            rhs: Some(Operand::Amount(rhs)),
+            op: "-",
+            kind,
+        };
+
+        if self.currency != rhs.currency {
+            Err(error(AmountOpErrorKind::CurrencyMismatch))
+        } else if let Some(value) = self.aligned(rhs).and_then(|(lhs, rhs, scale)| {
+            Some(Self {
+                value: lhs.checked_sub(rhs)?,
+                scale,
+                ..self
             })
+        }) {
+            Ok(value)
+        } else {
+            Err(error(AmountOpErrorKind::Overflow))
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct AmountOpError {
     pub lhs: Amount,
-    pub rhs: Option<Amount>,
+    pub rhs: Option<Operand>,
     pub op: &'static str,
+    pub kind: AmountOpErrorKind,
+}
+
+// The right hand side of an operation on an `Amount`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Amount(Amount),
+    // A numerator and a denominator.
+    Ratio(AmountValue, AmountValue),
+    // The sum of the weights that an amount is allocated by.
+    Weights(u128),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmountOpErrorKind {
+    // The result does not fit in an `Amount`.
+    Overflow,
+    // The two amounts are in different currencies.
+    CurrencyMismatch,
+    // The denominator (or the sum of the weights) is zero.
+    DivisionByZero,
+    // The result would have to be rounded, but `RoundingMode::Reject` was used.
+    Inexact,
+}
+
+impl Display for AmountOpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let reason = match self.kind {
+            AmountOpErrorKind::Overflow => "arithmetic overflow",
+            AmountOpErrorKind::CurrencyMismatch => "mismatched currencies",
+            AmountOpErrorKind::DivisionByZero => "division by zero",
+            AmountOpErrorKind::Inexact => "inexact result",
+        };
+        let lhs = Money(self.lhs);
+        match self.rhs {
+            Some(Operand::Amount(rhs)) => {
+                write!(f, "{}: {} {} {}", reason, lhs, self.op, Money(rhs))
+            }
+            Some(Operand::Ratio(num, den)) => {
+                write!(f, "{}: {} {} {}/{}", reason, lhs, self.op, num, den)
+            }
+            Some(Operand::Weights(total)) => write!(
+                f,
+                "{}: {} {} weights summing to {}",
+                reason, lhs, self.op, total
+            ),
+            None => write!(f, "{}: {}{}", reason, self.op, lhs),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AmountParseError {
+    pub input: String,
+    pub kind: AmountParseErrorKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmountParseErrorKind {
+    // There is nothing to parse.
+    Empty,
+    // There are no digits before the decimal point (eg: `.5`, or `-`).
+    MissingInteger,
+    // There are no digits after the decimal point (eg: `1.`).
+    MissingFraction,
+    // Something other than a digit, a leading sign, or a single decimal point (eg: `1.-5`).
+    UnexpectedCharacter(char),
+    // The value is too large to be represented.
+    Overflow,
+    // The value has more (non-zero) decimal places than the scale allows, and is not being rounded.
+    TooPrecise { scale: u32 },
+}
+
+impl Display for AmountParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "invalid amount {:?}: ", self.input)?;
+        match self.kind {
+            AmountParseErrorKind::Empty => write!(f, "it is empty"),
+            AmountParseErrorKind::MissingInteger => {
+                write!(f, "there are no digits before the decimal point")
+            }
+            AmountParseErrorKind::MissingFraction => {
+                write!(f, "there are no digits after the decimal point")
+            }
+            AmountParseErrorKind::UnexpectedCharacter(c) => {
+                write!(f, "unexpected character {:?}", c)
+            }
+            AmountParseErrorKind::Overflow => write!(f, "it is too large"),
+            AmountParseErrorKind::TooPrecise { scale } => {
+                write!(f, "it has more than {} decimal places", scale)
+            }
+        }
+    }
+}
+
+// How extra decimal places are handled, when an amount has more of them than are being kept.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    // Drop the extra decimal places (rounding towards zero).
+    #[default]
+    Truncate,
+    // Round to the nearest value, with halves rounded away from zero.
+    HalfUp,
+    // Round to the nearest value, with halves rounded to the nearest even value (banker's
+    // rounding).
+    HalfEven,
+    // Refuse to drop any (non-zero) decimal places.
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RescaleError {
+    pub amount: Amount,
+    pub scale: u32,
+    pub kind: RescaleErrorKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RescaleErrorKind {
+    // The value is too large to be represented with that many decimal places.
+    Overflow,
+    // The value has more (non-zero) decimal places than the scale allows.
+    Inexact,
+}
+
+impl Display for RescaleError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self.kind {
+            RescaleErrorKind::Overflow => write!(
+                f,
+                "amount {} is too large for {} decimal places",
+                Money(self.amount),
+                self.scale
+            ),
+            RescaleErrorKind::Inexact => write!(
+                f,
+                "amount {} has more than {} decimal places",
+                Money(self.amount),
+                self.scale
+            ),
+        }
+    }
+}
+
+// Formats an amount along with its currency code, if it has one.
+struct Money(Amount);
+
+impl Display for Money {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self.0.currency {
+            currency if currency.is_unspecified() => write!(f, "{}", self.0),
+            currency => write!(f, "{} {}", self.0, currency),
+        }
+    }
+}
+
+// The operations that an `AmountOpError` can be for, so that the name of the operation can be decoded
+// back to a `&'static str`. The position of each operation is its encoding, so new ones must be added
+// at the end.
+const OPERATIONS: [&str; 5] = ["+", "-", "*", "allocated by", "charged"];
+
+impl Encode for AmountOpError {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        self.lhs.encode(out)?;
+        self.rhs.encode(out)?;
+        // An unknown operation would be a bug, it is written as an invalid one rather than panicking.
+        let op = OPERATIONS.iter().position(|op| *op == self.op);
+        op.map_or(u8::MAX, |op| op as u8).encode(out)?;
+        self.kind.encode(out)?;
+        Ok(())
+    }
+}
+
+impl Decode for AmountOpError {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Self {
+            lhs: Decode::decode(input)?,
+            rhs: Decode::decode(input)?,
+            op: OPERATIONS
+                .get(usize::from(u8::decode(input)?))
+                .ok_or(DecodeError::new("invalid amount operation"))?,
+            kind: Decode::decode(input)?,
+        })
+    }
+}
+
+impl Encode for Operand {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        match *self {
+            Self::Amount(amount) => {
+                0u8.encode(out)?;
+                amount.encode(out)?;
+            }
+            Self::Ratio(num, den) => {
+                1u8.encode(out)?;
+                encode_value(num, out)?;
+                encode_value(den, out)?;
+            }
+            Self::Weights(total) => {
+                2u8.encode(out)?;
+                total.encode(out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Decode for Operand {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode(input)? {
+            0 => Ok(Self::Amount(Decode::decode(input)?)),
+            1 => Ok(Self::Ratio(decode_value(input)?, decode_value(input)?)),
+            2 => Ok(Self::Weights(Decode::decode(input)?)),
+            _ => Err(DecodeError::new("invalid operand")),
+        }
+    }
+}
+
+impl Encode for AmountOpErrorKind {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        let tag: u8 = match self {
+            Self::Overflow => 0,
+            Self::CurrencyMismatch => 1,
+            Self::DivisionByZero => 2,
+            Self::Inexact => 3,
+        };
+        tag.encode(out)
+    }
+}
+
+impl Decode for AmountOpErrorKind {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode(input)? {
+            0 => Ok(Self::Overflow),
+            1 => Ok(Self::CurrencyMismatch),
+            2 => Ok(Self::DivisionByZero),
+            3 => Ok(Self::Inexact),
+            _ => Err(DecodeError::new("invalid amount error")),
+        }
+    }
+}
+
+impl Encode for RescaleError {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        self.amount.encode(out)?;
+        self.scale.encode(out)?;
+        let kind: u8 = match self.kind {
+            RescaleErrorKind::Overflow => 0,
+            RescaleErrorKind::Inexact => 1,
+        };
+        kind.encode(out)?;
+        Ok(())
+    }
+}
+
+impl Decode for RescaleError {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Self {
+            amount: Decode::decode(input)?,
+            scale: Decode::decode(input)?,
+            kind: match u8::decode(input)? {
+                0 => RescaleErrorKind::Overflow,
+                1 => RescaleErrorKind::Inexact,
+                _ => return Err(DecodeError::new("invalid rescale error")),
+            },
+        })
+    }
 }
 
 #[cfg(test)]
+#[allow(clippy::zero_prefixed_literal, clippy::inconsistent_digit_grouping)]
 mod tests {
     use super::*;
+    use crate::{decode_from_slice, encode_to_vec};
+    use AmountParseErrorKind::*;
 
     #[test]
     fn test_amount_from_str() {
-        assert_eq!(Amount::from_str("0"), Some(Amount(0))); // zero
-        assert_eq!(Amount::from_str("123"), Some(Amount(123_0000))); // simple int
-        assert_eq!(Amount::from_str("456.7891"), Some(Amount(456_7891))); // simple float
-        assert_eq!(Amount::from_str("1.5"), Some(Amount(1_5000))); // less than 4 decimal places
-        assert_eq!(Amount::from_str("-987"), Some(Amount(-987_0000))); // simple negative
-        assert_eq!(Amount::from_str("-1000.0001"), Some(Amount(-1000_0001))); // simple negative float
-        assert_eq!(Amount::from_str("-0.0001"), Some(Amount(-0_0001))); // negative isn't lost, when integer part is zero
+        assert_eq!(Amount::from_str("0"), Ok(Amount::new(0))); // zero
+        assert_eq!(Amount::from_str("123"), Ok(Amount::new(123_0000))); // simple int
+        assert_eq!(Amount::from_str("456.7891"), Ok(Amount::new(456_7891))); // simple float
+        assert_eq!(Amount::from_str("1.5"), Ok(Amount::new(1_5000))); // less than 4 decimal places
+        assert_eq!(Amount::from_str("-987"), Ok(Amount::new(-987_0000))); // simple negative
+        assert_eq!(Amount::from_str("-1000.0001"), Ok(Amount::new(-1000_0001))); // simple negative float
+        assert_eq!(Amount::from_str("-0.0001"), Ok(Amount::new(-0_0001))); // negative isn't lost, when integer part is zero
         assert_eq!(
             Amount::from_str("922337203685477.5807"),
-            Some(Amount(922337203685477_5807))
+            Ok(Amount::new(922337203685477_5807))
         ); // max i64
         assert_eq!(
             Amount::from_str("-922337203685477.5808"),
-            Some(Amount(-922337203685477_5808))
+            Ok(Amount::new(-922337203685477_5808))
         ); // min i64
 
-        assert_eq!(Amount::from_str("922337203685477.5808"), None); // overflow
-        assert_eq!(Amount::from_str("duck"), None); // not a number
-        assert_eq!(Amount::from_str("-duck.42"), None); // the duck put on a disguise
-        assert_eq!(Amount::from_str("-42.duck"), None); // the duck tried a different disguise
+        assert_eq!(Amount::from_str("+5"), Ok(Amount::new(5_0000))); // explicit positive
+        assert_eq!(Amount::from_str("007.50"), Ok(Amount::new(7_5000))); // leading zeros
+
+        // The largest value ends in a 7 for both i64 and i128, so replacing it with an 8 overflows.
+        let max = Amount::new(AmountValue::MAX).to_string();
+        let overflow = format!("{}8", &max[..max.len() - 1]);
+        assert_eq!(Amount::from_str(&max), Ok(Amount::new(AmountValue::MAX)));
+
+        let kind = |s: &str| Amount::from_str(s).unwrap_err().kind;
+        assert_eq!(kind(&overflow), Overflow);
+        assert_eq!(kind("99999999999999999999999999999999999999999"), Overflow); // even for i128
+        assert_eq!(kind("duck"), UnexpectedCharacter('d')); // not a number
+        assert_eq!(kind("-duck.42"), UnexpectedCharacter('d')); // the duck put on a disguise
+        assert_eq!(kind("-42.duck"), UnexpectedCharacter('d')); // the duck tried a different disguise
+        assert_eq!(kind("1.12ab"), UnexpectedCharacter('a')); // bad characters after 4 digits
+        assert_eq!(kind("1.12345a"), UnexpectedCharacter('a')); // even the ones that are dropped
+        assert_eq!(kind(""), Empty);
+        assert_eq!(kind("1."), MissingFraction);
+        assert_eq!(kind(".5"), MissingInteger);
+        assert_eq!(kind("-"), MissingInteger);
+        assert_eq!(kind("+-1"), UnexpectedCharacter('-'));
+        assert_eq!(kind("--1"), UnexpectedCharacter('-'));
+        assert_eq!(kind("1.-5"), UnexpectedCharacter('-'));
+        assert_eq!(kind("1.2.3"), UnexpectedCharacter('.'));
+        assert_eq!(kind(" 1"), UnexpectedCharacter(' '));
+        assert_eq!(kind("1 "), UnexpectedCharacter(' '));
+        assert_eq!(kind("1e5"), UnexpectedCharacter('e'));
+        assert_eq!(kind("1.ab€"), UnexpectedCharacter('a'));
+
+        assert_eq!(
+            Amount::from_str("1.-5").unwrap_err().to_string(),
+            "invalid amount \"1.-5\": unexpected character '-'"
+        );
+    }
+
+    // A small xorshift generator, so that the property tests below are reproducible and don't need
+    // any extra dependencies.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_amount_round_trip() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let mut amounts = vec![
+            Amount::new(0),
+            Amount::new(-0_0001), // the whole part is zero, but the sign still has to be kept
+            Amount::with_scale(-5, 1),
+            Amount::with_scale(AmountValue::MAX, 0),
+            Amount::with_scale(AmountValue::MIN, 0),
+            Amount::with_scale(AmountValue::MAX, Amount::MAX_SCALE),
+            Amount::with_scale(AmountValue::MIN, Amount::MAX_SCALE),
+        ];
+        for _ in 0..10_000 {
+            let scale = (rng.next() % (Amount::MAX_SCALE as u64 + 1)) as u32;
+            // Shift by a random number of bits, so that small values are as likely as large ones.
+            let value = (rng.next() as i128) << 64 | rng.next() as i128;
+            let value = value as AmountValue >> (rng.next() % AmountValue::BITS as u64);
+            amounts.push(Amount::with_scale(value, scale));
+        }
+
+        for amount in amounts {
+            let s = amount.to_string();
+            let parsed = Amount::parse(&s, amount.scale(), RoundingMode::Reject);
+            assert_eq!(parsed, Ok(amount), "{}", s);
+            assert_eq!(parsed.unwrap().scale(), amount.scale(), "{}", s);
+
+            let exact = Amount::parse_exact(&s).unwrap();
+            assert_eq!((exact, exact.scale()), (amount, amount.scale()), "{}", s);
+            assert_eq!(exact.to_string(), s);
+        }
+    }
+
+    #[test]
+    fn test_amount_parse_never_panics() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        let alphabet = ['0', '1', '5', '9', '+', '-', '.', ' ', 'e', '€'];
+        for _ in 0..10_000 {
+            let len = rng.next() % 12;
+            let s: String = (0..len)
+                .map(|_| alphabet[(rng.next() % alphabet.len() as u64) as usize])
+                .collect();
+
+            // Anything that is accepted has to format back to an equal amount.
+            if let Ok(amount) = Amount::parse_exact(&s) {
+                assert_eq!(
+                    Amount::parse_exact(&amount.to_string()),
+                    Ok(amount),
+                    "{}",
+                    s
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_amount_scale() {
+        let max = Amount::new(AmountValue::MAX).to_string();
+        assert_eq!(
+            Amount::parse("1.5", 2, RoundingMode::Truncate),
+            Ok(Amount::with_scale(1_50, 2))
+        ); // padded
+        assert_eq!(
+            Amount::parse("1.239", 2, RoundingMode::Truncate),
+            Ok(Amount::with_scale(1_23, 2))
+        ); // truncated
+        assert_eq!(
+            Amount::parse("0.12345678", 8, RoundingMode::Truncate),
+            Ok(Amount::with_scale(0_12345678, 8))
+        );
+        assert_eq!(
+            Amount::parse("-7.9", 0, RoundingMode::Truncate),
+            Ok(Amount::with_scale(-7, 0))
+        );
+        assert_eq!(
+            Amount::parse(&max, 5, RoundingMode::Truncate)
+                .unwrap_err()
+                .kind,
+            Overflow
+        ); // overflow
+        assert_eq!(
+            Amount::parse("1", Amount::MAX_SCALE + 1, RoundingMode::Truncate)
+                .unwrap_err()
+                .kind,
+            Overflow
+        );
+        assert_eq!(Amount::parse_exact("1.50"), Ok(Amount::with_scale(1_50, 2)));
+
+        // The same number is equal, regardless of its scale.
+        assert_eq!(Amount::with_scale(1_50, 2), Amount::new(1_5000));
+        assert!(Amount::with_scale(1_51, 2) > Amount::new(1_5099));
+
+        let amount = Amount::with_scale(1_50, 2);
+        assert_eq!(amount.rescale(4).unwrap().to_string(), "1.5000");
+        assert_eq!(amount.rescale(1).unwrap().to_string(), "1.5");
+        assert_eq!(
+            amount.rescale(0).unwrap_err().kind,
+            RescaleErrorKind::Inexact
+        );
+        assert_eq!(amount.truncate(0).unwrap().to_string(), "1");
+        assert_eq!(
+            Amount::new(AmountValue::MAX).rescale(5).unwrap_err().kind,
+            RescaleErrorKind::Overflow
+        );
+
+        // Adding amounts with different scales keeps the larger scale.
+        let sum = (amount + Amount::with_scale(0_00000001, 8)).unwrap();
+        assert_eq!(sum.scale(), 8);
+        assert_eq!(sum.to_string(), "1.50000001");
+        assert_eq!(format!("{:.2}", sum), "1.50");
+        assert_eq!(format!("{:.10}", sum), "1.5000000100");
+        assert_eq!(
+            (Amount::with_scale(AmountValue::MAX, 0) + Amount::new(0))
+                .unwrap_err()
+                .kind,
+            AmountOpErrorKind::Overflow
+        );
+    }
+
+    #[test]
+    fn test_amount_rounding() {
+        use RoundingMode::*;
+        let max = Amount::new(AmountValue::MAX).to_string();
+
+        let parse = |s, mode| {
+            Amount::parse(s, 2, mode)
+                .map(|amount| amount.to_string())
+                .map_err(|e| e.kind)
+        };
+        let cases = [
+            // input, truncate, half-up, half-even
+            ("1.234", "1.23", "1.23", "1.23"),
+            ("1.235", "1.23", "1.24", "1.24"),
+            ("1.245", "1.24", "1.25", "1.24"), // a half rounds to the even neighbour
+            ("1.2451", "1.24", "1.25", "1.25"), // not quite a half
+            ("1.2450000", "1.24", "1.25", "1.24"),
+            ("1.239", "1.23", "1.24", "1.24"),
+            ("-1.235", "-1.23", "-1.24", "-1.24"), // away from zero
+            ("-1.225", "-1.22", "-1.23", "-1.22"),
+            ("9.995", "9.99", "10.00", "10.00"),
+        ];
+        for (input, truncated, half_up, half_even) in cases {
+            assert_eq!(
+                parse(input, Truncate).as_deref(),
+                Ok(truncated),
+                "{}",
+                input
+            );
+            assert_eq!(parse(input, HalfUp).as_deref(), Ok(half_up), "{}", input);
+            assert_eq!(
+                parse(input, HalfEven).as_deref(),
+                Ok(half_even),
+                "{}",
+                input
+            );
+            assert_eq!(
+                parse(input, Reject),
+                Err(TooPrecise { scale: 2 }),
+                "{}",
+                input
+            );
+
+            // Rounding an amount that has already been parsed gives the same result.
+            let exact = Amount::parse_exact(input).unwrap();
+            let round = |mode| exact.round(2, mode).map(|amount| amount.to_string());
+            assert_eq!(
+                round(Truncate).ok().as_deref(),
+                Some(truncated),
+                "{}",
+                input
+            );
+            assert_eq!(round(HalfUp).ok().as_deref(), Some(half_up), "{}", input);
+            assert_eq!(
+                round(HalfEven).ok().as_deref(),
+                Some(half_even),
+                "{}",
+                input
+            );
+            assert_eq!(round(Reject).unwrap_err().kind, RescaleErrorKind::Inexact);
+        }
+
+        // Nothing is rejected if only zeros would be dropped.
+        assert_eq!(parse("1.2300", Reject).as_deref(), Ok("1.23"));
+        assert_eq!(
+            Amount::with_scale(1_2300, 4).round(2, Reject).unwrap(),
+            Amount::with_scale(1_23, 2)
+        );
+        assert_eq!(
+            Amount::with_scale(1_2345, 4)
+                .round(2, Reject)
+                .unwrap_err()
+                .to_string(),
+            "amount 1.2345 has more than 2 decimal places"
+        );
+        assert_eq!(
+            Amount::parse(&format!("{}5", max), 4, HalfUp)
+                .unwrap_err()
+                .kind,
+            Overflow
+        );
+    }
+
+    #[test]
+    fn test_amount_mul_ratio() {
+        use RoundingMode::*;
+        let dollars = |value| Amount::with_scale(value, 2);
+
+        // 10.00 at a rate of 1.0825 is 10.825
+        let rate = |mode| dollars(10_00).checked_mul_ratio(1_0825, 1_0000, mode);
+        assert_eq!(rate(Truncate).unwrap(), dollars(10_82));
+        assert_eq!(rate(HalfUp).unwrap(), dollars(10_83));
+        assert_eq!(rate(HalfEven).unwrap(), dollars(10_82));
+        assert_eq!(rate(Reject).unwrap_err().kind, AmountOpErrorKind::Inexact);
+        assert_eq!(
+            dollars(-10_00).checked_mul_ratio(1_0825, 1_0000, HalfUp),
+            Ok(dollars(-10_83))
+        );
+        assert_eq!(
+            dollars(10_00).checked_mul_ratio(1, -3, HalfUp),
+            Ok(dollars(-3_33))
+        );
+        assert_eq!(
+            dollars(7_00).checked_mul_ratio(1, 2, Reject),
+            Ok(dollars(3_50))
+        );
+
+        // The intermediate product can be larger than an amount, as long as the result is not.
+        let max = Amount::with_scale(AmountValue::MAX, 2);
+        #[cfg(not(feature = "i128"))]
+        assert_eq!(max.checked_mul_ratio(3, 3, Reject), Ok(max));
+        let err = max.checked_mul_ratio(3, 2, Truncate).unwrap_err();
+        assert_eq!(err.kind, AmountOpErrorKind::Overflow);
+        let err = dollars(1_00).checked_mul_ratio(1, 0, Truncate).unwrap_err();
+        assert_eq!(err.kind, AmountOpErrorKind::DivisionByZero);
+        assert_eq!(err.to_string(), "division by zero: 1.00 * 1/0");
+
+        assert_eq!(dollars(10_00).percent(250, Reject), Ok(dollars(0_25)));
+        assert_eq!(dollars(0_99).percent(150, HalfUp), Ok(dollars(0_01))); // 0.01485
+        assert_eq!(dollars(0_99).percent(150, Truncate), Ok(dollars(0_01)));
+        assert_eq!(dollars(0_33).percent(5000, HalfEven), Ok(dollars(16))); // 0.165
+        assert_eq!(dollars(0_33).percent(5000, HalfUp), Ok(dollars(0_17)));
+    }
+
+    #[test]
+    fn test_amount_allocate() {
+        let dollars = |value| Amount::with_scale(value, 2);
+        let allocate = |value, weights: &[u64]| {
+            dollars(value).allocate(weights).map(|parts| {
+                parts
+                    .iter()
+                    .map(|part| part.to_string())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(
+            allocate(100_00, &[1, 1, 1]).unwrap(),
+            ["33.34", "33.33", "33.33"]
+        );
+        assert_eq!(
+            allocate(-100_00, &[1, 1, 1]).unwrap(),
+            ["-33.34", "-33.33", "-33.33"]
+        );
+        assert_eq!(allocate(0_05, &[3, 7]).unwrap(), ["0.02", "0.03"]); // 0.015 and 0.035
+        assert_eq!(
+            allocate(0_01, &[1, 1, 1]).unwrap(),
+            ["0.01", "0.00", "0.00"]
+        );
+        assert_eq!(allocate(10_00, &[0, 1]).unwrap(), ["0.00", "10.00"]);
+        assert_eq!(allocate(10_00, &[1]).unwrap(), ["10.00"]);
+
+        let err = dollars(10_00).allocate(&[]).unwrap_err();
+        assert_eq!(err.kind, AmountOpErrorKind::DivisionByZero);
+        assert_eq!(
+            err.to_string(),
+            "division by zero: 10.00 allocated by weights summing to 0"
+        );
+        assert!(dollars(10_00).allocate(&[0, 0]).is_err());
+
+        // The parts always add up to exactly the original amount.
+        let mut rng = Rng(0xdead_beef_cafe_f00d);
+        for _ in 0..1_000 {
+            let amount = Amount::with_scale(rng.next() as i32 as AmountValue, 2);
+            let weights: Vec<u64> = (0..1 + rng.next() % 10)
+                .map(|_| rng.next() % 1_000_000)
+                .collect();
+            let Ok(parts) = amount.allocate(&weights) else {
+                continue; // all of the weights were zero
+            };
+            assert_eq!(parts.len(), weights.len());
+            let sum = parts
+                .iter()
+                .try_fold(Amount::with_scale(0, 2), |sum, part| sum + *part)
+                .unwrap();
+            assert_eq!(sum, amount, "{:?}", weights);
+        }
+    }
+
+    #[test]
+    fn test_amount_currency() {
+        let usd = Currency::from_code("USD").unwrap();
+        let jpy = Currency::from_code("JPY").unwrap();
+
+        let dollars = Amount::new(1_5000).in_currency(usd).unwrap();
+        assert_eq!(dollars.to_string(), "1.50");
+        assert_eq!(Amount::new(1_5050).in_currency(usd), None); // fraction of a cent
+        assert_eq!(
+            Amount::new(-100_0000).in_currency(jpy).unwrap().to_string(),
+            "-100"
+        );
+        assert_eq!(Amount::new(100_5000).in_currency(jpy), None);
+
+        let yen = Amount::new(100_0000).in_currency(jpy).unwrap();
+        let err = (dollars + yen).unwrap_err();
+        assert_eq!(err.kind, AmountOpErrorKind::CurrencyMismatch);
+        assert_eq!(err.to_string(), "mismatched currencies: 1.50 USD + 100 JPY");
+        assert_eq!((dollars - dollars).unwrap(), Amount::zero(usd));
+    }
+
+    #[test]
+    fn test_amount_codec() {
+        let usd = Currency::from_code("USD").unwrap();
+        for amount in [
+            Amount::new(-12_3456),
+            Amount::with_scale(AmountValue::MAX, Amount::MAX_SCALE),
+            Amount::new(1_5000).in_currency(usd).unwrap(),
+        ] {
+            let decoded: Amount = decode_from_slice(&encode_to_vec(&amount).unwrap()).unwrap();
+            assert_eq!(decoded.to_string(), amount.to_string());
+            assert_eq!(decoded.currency(), amount.currency());
+        }
+
+        let mut bytes = encode_to_vec(&Amount::new(1)).unwrap();
+        bytes[16] = Amount::MAX_SCALE as u8 + 1;
+        assert_eq!(
+            decode_from_slice::<Amount>(&bytes),
+            Err(DecodeError::new("amount has too many decimal places"))
+        );
     }
 }
@@ -0,0 +1,136 @@
+use super::Amount;
+use crate::{take, Decode, DecodeError, Encode, EncodeError};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+// The currencies that are supported, along with the number of decimal places of their minor unit.
+const CURRENCIES: &[(&str, u32)] = &[
+    ("AUD", 2),
+    ("BTC", 8),
+    ("CAD", 2),
+    ("CHF", 2),
+    ("EUR", 2),
+    ("GBP", 2),
+    ("JPY", 0),
+    ("KRW", 0),
+    ("USD", 2),
+];
+
+// An ISO 4217 currency code. The default is the unspecified currency, which is used for events that
+// do not name a currency, keeping the behaviour from before currencies were supported.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Currency(Option<[u8; 3]>);
+
+impl Currency {
+    pub const UNSPECIFIED: Self = Self(None);
+
+    // @returns the currency with the given code, if it is supported
+    pub fn from_code(code: &str) -> Option<Self> {
+        let code = code.to_ascii_uppercase();
+        CURRENCIES
+            .iter()
+            .find(|(known, _)| *known == code)
+            .map(|(known, _)| {
+                let mut bytes = [0; 3];
+                bytes.copy_from_slice(known.as_bytes());
+                Self(Some(bytes))
+            })
+    }
+
+    pub fn is_unspecified(&self) -> bool {
+        self.0.is_none()
+    }
+
+    pub fn code(&self) -> &str {
+        match &self.0 {
+            // The code is always one of the ASCII codes from `CURRENCIES`.
+            Some(bytes) => std::str::from_utf8(bytes).unwrap_or_default(),
+            None => "",
+        }
+    }
+
+    // @returns the number of decimal places used by the currency's minor unit (eg: 2 for cents)
+    //
+    // The unspecified currency uses the default scale of an `Amount`.
+    pub fn minor_units(&self) -> u32 {
+        CURRENCIES
+            .iter()
+            .find(|(known, _)| *known == self.code())
+            .map_or(Amount::DEFAULT_SCALE, |(_, minor_units)| *minor_units)
+    }
+}
+
+impl Display for Currency {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        if s.is_empty() {
+            return Ok(Self::UNSPECIFIED);
+        }
+        Currency::from_code(&s).ok_or_else(|| serde::de::Error::custom("unsupported currency"))
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.code())
+    }
+}
+
+impl Encode for Currency {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        match &self.0 {
+            None => out.push(0),
+            Some(bytes) => {
+                out.push(1);
+                out.extend_from_slice(bytes);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Decode for Currency {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode(input)? {
+            0 => Ok(Self::UNSPECIFIED),
+            1 => {
+                let bytes: [u8; 3] = take(input)?;
+                std::str::from_utf8(&bytes)
+                    .ok()
+                    .and_then(Self::from_code)
+                    .ok_or(DecodeError::new("unsupported currency"))
+            }
+            _ => Err(DecodeError::new("invalid currency")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_currency() {
+        let usd = Currency::from_code("USD").unwrap();
+        assert_eq!(usd.code(), "USD");
+        assert_eq!(usd.minor_units(), 2);
+        assert_eq!(Currency::from_code("usd"), Some(usd));
+        assert_eq!(Currency::from_code("JPY").unwrap().minor_units(), 0);
+        assert_eq!(Currency::UNSPECIFIED.code(), "");
+        assert_eq!(Currency::UNSPECIFIED.minor_units(), 4);
+        assert_eq!(Currency::from_code("XXX"), None); // not supported
+        assert_eq!(Currency::from_code("US"), None); // not a code at all
+    }
+}
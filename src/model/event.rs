@@ -1,5 +1,10 @@
-use super::Amount;
+use super::{Amount, Currency};
+use crate::{Decode, DecodeError, Encode, EncodeError};
 use serde::{Deserialize, Deserializer};
+use std::{
+    borrow::Cow,
+    fmt::{Display, Formatter, Result as FmtResult},
+};
 
 // Make these types easily swappable.
 pub type ClientId = u16;
@@ -9,7 +14,7 @@ pub type TransactionId = u32;
 // Option<Amount> field instead) is a bit overkill, but in the case that more event types need to be
 // handled -- ones that do not have as similar a structure to the existing ones, using an enum will
 // be more future-proof, requiring less refactoring.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Event {
     Deposit {
         client: ClientId,
@@ -21,17 +26,42 @@ pub enum Event {
         tx: TransactionId,
         amount: Amount,
     },
+    // Moves funds from one client's account to another's. The transaction belongs to the sender,
+    // who is the one that can dispute it.
+    Transfer {
+        from: ClientId,
+        to: ClientId,
+        tx: TransactionId,
+        amount: Amount,
+    },
+    // Gives back some or all of a deposit, without going through a dispute. The refund is a
+    // transaction of its own, the deposit that it is for is the original transaction.
+    Refund {
+        client: ClientId,
+        tx: TransactionId,
+        original_tx: TransactionId,
+        amount: Amount,
+    },
+    // The dispute related events can name part of the transaction's amount, or leave the amount out
+    // to act on all of it (see `TransactionState`).
     Dispute {
         client: ClientId,
         tx: TransactionId,
+        amount: Option<Amount>,
     },
     Resolve {
         client: ClientId,
         tx: TransactionId,
+        amount: Option<Amount>,
     },
     Chargeback {
         client: ClientId,
         tx: TransactionId,
+        amount: Option<Amount>,
+    },
+    // An administrative event, lifting the lock placed on an account by a chargeback.
+    Unlock {
+        client: ClientId,
     },
 }
 
@@ -40,19 +70,40 @@ impl Event {
         match self {
             Self::Deposit { client, .. } => *client,
             Self::Withdrawal { client, .. } => *client,
+            Self::Transfer { from, .. } => *from,
+            Self::Refund { client, .. } => *client,
             Self::Dispute { client, .. } => *client,
             Self::Resolve { client, .. } => *client,
             Self::Chargeback { client, .. } => *client,
+            Self::Unlock { client } => *client,
         }
     }
 
-    pub fn transaction(&self) -> TransactionId {
+    // @returns the transaction that the event refers to, or None for events that act on the account
+    // as a whole
+    pub fn transaction(&self) -> Option<TransactionId> {
         match self {
-            Self::Deposit { tx, .. } => *tx,
-            Self::Withdrawal { tx, .. } => *tx,
-            Self::Dispute { tx, .. } => *tx,
-            Self::Resolve { tx, .. } => *tx,
-            Self::Chargeback { tx, .. } => *tx,
+            Self::Deposit { tx, .. } => Some(*tx),
+            Self::Withdrawal { tx, .. } => Some(*tx),
+            Self::Transfer { tx, .. } => Some(*tx),
+            Self::Refund { tx, .. } => Some(*tx),
+            Self::Dispute { tx, .. } => Some(*tx),
+            Self::Resolve { tx, .. } => Some(*tx),
+            Self::Chargeback { tx, .. } => Some(*tx),
+            Self::Unlock { .. } => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Deposit { .. } => "deposit",
+            Self::Withdrawal { .. } => "withdrawal",
+            Self::Transfer { .. } => "transfer",
+            Self::Refund { .. } => "refund",
+            Self::Dispute { .. } => "dispute",
+            Self::Resolve { .. } => "resolve",
+            Self::Chargeback { .. } => "chargeback",
+            Self::Unlock { .. } => "unlock",
         }
     }
 
@@ -60,57 +111,309 @@ impl Event {
         match self {
             Self::Deposit { amount, .. } => Some(*amount),
             Self::Withdrawal { amount, .. } => Some(*amount),
-            _ => None,
+            Self::Transfer { amount, .. } => Some(*amount),
+            Self::Refund { amount, .. } => Some(*amount),
+            Self::Dispute { amount, .. } => *amount,
+            Self::Resolve { amount, .. } => *amount,
+            Self::Chargeback { amount, .. } => *amount,
+            Self::Unlock { .. } => None,
+        }
+    }
+
+    // @returns the event with its amount (if it has one) replaced by the result of `f`
+    pub fn map_amount<E>(self, f: impl FnOnce(Amount) -> Result<Amount, E>) -> Result<Self, E> {
+        match self {
+            Self::Deposit { client, tx, amount } => Ok(Self::Deposit {
+                client,
+                tx,
+                amount: f(amount)?,
+            }),
+            Self::Withdrawal { client, tx, amount } => Ok(Self::Withdrawal {
+                client,
+                tx,
+                amount: f(amount)?,
+            }),
+            Self::Transfer {
+                from,
+                to,
+                tx,
+                amount,
+            } => Ok(Self::Transfer {
+                from,
+                to,
+                tx,
+                amount: f(amount)?,
+            }),
+            Self::Refund {
+                client,
+                tx,
+                original_tx,
+                amount,
+            } => Ok(Self::Refund {
+                client,
+                tx,
+                original_tx,
+                amount: f(amount)?,
+            }),
+            Self::Dispute { client, tx, amount } => Ok(Self::Dispute {
+                client,
+                tx,
+                amount: amount.map(f).transpose()?,
+            }),
+            Self::Resolve { client, tx, amount } => Ok(Self::Resolve {
+                client,
+                tx,
+                amount: amount.map(f).transpose()?,
+            }),
+            Self::Chargeback { client, tx, amount } => Ok(Self::Chargeback {
+                client,
+                tx,
+                amount: amount.map(f).transpose()?,
+            }),
+            Self::Unlock { .. } => Ok(self),
         }
     }
 }
 
-// serde doesn't support deserializing tagged enums from csv, so we have to do it manually.
-impl<'de> Deserialize<'de> for Event {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        #[derive(Deserialize)]
-        struct EventData {
-            r#type: String,
-            client: ClientId,
-            tx: TransactionId,
-            amount: Option<Amount>,
+impl Display for Event {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{} (client {}", self.name(), self.client())?;
+        if let Self::Transfer { to, .. } = self {
+            write!(f, " to client {}", to)?;
+        }
+        if let Some(tx) = self.transaction() {
+            write!(f, ", tx {}", tx)?;
+        }
+        if let Self::Refund { original_tx, .. } = self {
+            write!(f, " refunding tx {}", original_tx)?;
         }
+        if let Some(amount) = self.amount() {
+            write!(f, ", amount {}", amount)?;
+            if !amount.currency().is_unspecified() {
+                write!(f, " {}", amount.currency())?;
+            }
+        }
+        write!(f, ")")
+    }
+}
 
-        let data = EventData::deserialize(deserializer)?;
+// A row of csv input, with its amount left as it is written. Deserializing an `Event` parses the
+// amount with every decimal place that it is written with, `EventRow::parse` leaves that to the
+// caller instead (eg: to parse it straight to the number of decimal places that it is kept with).
+//
+// serde doesn't support deserializing tagged enums from csv, so we have to do it manually.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventRow<'a> {
+    #[serde(borrow)]
+    r#type: Cow<'a, str>,
+    client: ClientId,
+    tx: Option<TransactionId>,
+    #[serde(borrow)]
+    amount: Option<Cow<'a, str>>,
+    // The currency column is optional, leaving it out (or empty) means the amount is in the
+    // unspecified currency.
+    currency: Option<Currency>,
+    // Only used by transfers and refunds, so these columns are optional as well.
+    to_client: Option<ClientId>,
+    original_tx: Option<TransactionId>,
+}
 
-        match data.r#type.as_str() {
-            "deposit" => Ok(Self::Deposit {
-                client: data.client,
-                tx: data.tx,
-                amount: data.amount.map_or_else(
-                    || Err(serde::de::Error::custom("missing required field: amount")),
-                    |value| Ok(value),
-                )?,
+impl EventRow<'_> {
+    // @returns the event that the row describes, with its amount (if it has one) parsed by
+    // `parse_amount`, which is given the amount as written and the currency that it is in
+    pub fn parse<E: From<&'static str>>(
+        &self,
+        parse_amount: impl Fn(&str, Currency) -> Result<Amount, E>,
+    ) -> Result<Event, E> {
+        let optional_amount = || {
+            self.amount
+                .as_deref()
+                .map(|amount| parse_amount(amount, self.currency.unwrap_or_default()))
+                .transpose()
+        };
+        let amount = || optional_amount()?.ok_or(E::from("missing required field: amount"));
+        let tx = || self.tx.ok_or(E::from("missing required field: tx"));
+        let client = self.client;
+
+        match &*self.r#type {
+            "deposit" => Ok(Event::Deposit {
+                client,
+                tx: tx()?,
+                amount: amount()?,
+            }),
+            "withdrawal" => Ok(Event::Withdrawal {
+                client,
+                tx: tx()?,
+                amount: amount()?,
             }),
-            "withdrawal" => Ok(Self::Withdrawal {
-                client: data.client,
-                tx: data.tx,
-                amount: data.amount.map_or_else(
-                    || Err(serde::de::Error::custom("missing required field: amount")),
-                    |value| Ok(value),
-                )?,
+            "transfer" => Ok(Event::Transfer {
+                from: client,
+                to: self
+                    .to_client
+                    .ok_or(E::from("missing required field: to_client"))?,
+                tx: tx()?,
+                amount: amount()?,
             }),
-            "dispute" => Ok(Self::Dispute {
-                client: data.client,
-                tx: data.tx,
+            "refund" => Ok(Event::Refund {
+                client,
+                tx: tx()?,
+                original_tx: self
+                    .original_tx
+                    .ok_or(E::from("missing required field: original_tx"))?,
+                amount: amount()?,
             }),
-            "resolve" => Ok(Self::Resolve {
-                client: data.client,
-                tx: data.tx,
+            "dispute" => Ok(Event::Dispute {
+                client,
+                tx: tx()?,
+                amount: optional_amount()?,
             }),
-            "chargeback" => Ok(Self::Chargeback {
-                client: data.client,
-                tx: data.tx,
+            "resolve" => Ok(Event::Resolve {
+                client,
+                tx: tx()?,
+                amount: optional_amount()?,
             }),
-            _ => Err(serde::de::Error::custom("invalid event type")),
+            "chargeback" => Ok(Event::Chargeback {
+                client,
+                tx: tx()?,
+                amount: optional_amount()?,
+            }),
+            "unlock" => Ok(Event::Unlock { client }),
+            _ => Err(E::from("invalid event type")),
         }
     }
 }
+
+impl<'de> Deserialize<'de> for Event {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let row = EventRow::deserialize(deserializer)?;
+        row.parse(|amount, currency| {
+            let amount = Amount::parse_exact(amount).map_err(|e| e.to_string())?;
+            amount.in_currency(currency).ok_or_else(|| {
+                format!(
+                    "amount {} has more decimal places than {} allows",
+                    amount, currency
+                )
+            })
+        })
+        .map_err(serde::de::Error::custom)
+    }
+}
+
+// An event is written as a tag for its kind, followed by its fields.
+impl Encode for Event {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        match *self {
+            Self::Deposit { client, tx, amount } => {
+                0u8.encode(out)?;
+                client.encode(out)?;
+                tx.encode(out)?;
+                amount.encode(out)?;
+            }
+            Self::Withdrawal { client, tx, amount } => {
+                1u8.encode(out)?;
+                client.encode(out)?;
+                tx.encode(out)?;
+                amount.encode(out)?;
+            }
+            Self::Transfer {
+                from,
+                to,
+                tx,
+                amount,
+            } => {
+                2u8.encode(out)?;
+                from.encode(out)?;
+                to.encode(out)?;
+                tx.encode(out)?;
+                amount.encode(out)?;
+            }
+            Self::Refund {
+                client,
+                tx,
+                original_tx,
+                amount,
+            } => {
+                3u8.encode(out)?;
+                client.encode(out)?;
+                tx.encode(out)?;
+                original_tx.encode(out)?;
+                amount.encode(out)?;
+            }
+            Self::Dispute { client, tx, amount } => {
+                4u8.encode(out)?;
+                client.encode(out)?;
+                tx.encode(out)?;
+                amount.encode(out)?;
+            }
+            Self::Resolve { client, tx, amount } => {
+                5u8.encode(out)?;
+                client.encode(out)?;
+                tx.encode(out)?;
+                amount.encode(out)?;
+            }
+            Self::Chargeback { client, tx, amount } => {
+                6u8.encode(out)?;
+                client.encode(out)?;
+                tx.encode(out)?;
+                amount.encode(out)?;
+            }
+            Self::Unlock { client } => {
+                7u8.encode(out)?;
+                client.encode(out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Decode for Event {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let event = match u8::decode(input)? {
+            0 => Self::Deposit {
+                client: Decode::decode(input)?,
+                tx: Decode::decode(input)?,
+                amount: Decode::decode(input)?,
+            },
+            1 => Self::Withdrawal {
+                client: Decode::decode(input)?,
+                tx: Decode::decode(input)?,
+                amount: Decode::decode(input)?,
+            },
+            2 => Self::Transfer {
+                from: Decode::decode(input)?,
+                to: Decode::decode(input)?,
+                tx: Decode::decode(input)?,
+                amount: Decode::decode(input)?,
+            },
+            3 => Self::Refund {
+                client: Decode::decode(input)?,
+                tx: Decode::decode(input)?,
+                original_tx: Decode::decode(input)?,
+                amount: Decode::decode(input)?,
+            },
+            4 => Self::Dispute {
+                client: Decode::decode(input)?,
+                tx: Decode::decode(input)?,
+                amount: Decode::decode(input)?,
+            },
+            5 => Self::Resolve {
+                client: Decode::decode(input)?,
+                tx: Decode::decode(input)?,
+                amount: Decode::decode(input)?,
+            },
+            6 => Self::Chargeback {
+                client: Decode::decode(input)?,
+                tx: Decode::decode(input)?,
+                amount: Decode::decode(input)?,
+            },
+            7 => Self::Unlock {
+                client: Decode::decode(input)?,
+            },
+            _ => return Err(DecodeError::new("invalid event type")),
+        };
+        Ok(event)
+    }
+}
@@ -1,11 +1,15 @@
 mod account;
 mod amount;
+mod currency;
 mod event;
+mod policy;
 mod summary;
 mod transaction;
 
 pub use account::*;
 pub use amount::*;
+pub use currency::*;
 pub use event::*;
+pub use policy::*;
 pub use summary::*;
 pub use transaction::*;
@@ -0,0 +1,337 @@
+use super::{
+    decode_value, encode_value, Amount, AmountOpError, AmountOpErrorKind, AmountValue, ClientId,
+    Event, Operand, RoundingMode,
+};
+use crate::{Decode, DecodeError, Encode, EncodeError};
+
+// The rules that a shard follows when applying events.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Policy {
+    pub locked: LockedPolicy,
+    pub disputes: DisputeMode,
+    pub withdrawal_disputes: WithdrawalDisputes,
+    // The fee charged on every deposit and withdrawal, see `FeeSchedule`.
+    pub fees: FeeSchedule,
+    // The account that fees are credited to. Its own deposits and withdrawals are free.
+    pub house: ClientId,
+}
+
+// How strictly the order of dispute related events is enforced. See `TransactionState` for the
+// lifecycle of a transaction.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum DisputeMode {
+    // Events may arrive in any order, as can happen when multiple shards are involved. Each event is
+    // applied as it arrives, depending on which of the others have arrived before it (eg: a resolve
+    // that arrives before the dispute cancels it out). A dispute that arrives before the deposit is
+    // applied once the deposit does. See `Transaction::tolerate` for the details.
+    #[default]
+    Tolerant,
+
+    // Events must arrive in the order of the transaction lifecycle, anything else is an error.
+    Strict,
+
+    // Events may arrive in any order, but are applied in the order of the transaction lifecycle. An
+    // event that arrives before the transaction is ready for it (eg: a resolve before the dispute)
+    // is remembered, and applied once the transaction is ready. Early events of the same kind are
+    // remembered as one, for the sum of their amounts (or all of what is left, if one of them has
+    // no amount).
+    Deferred,
+}
+
+// What is still allowed to happen to an account after it has been locked by a chargeback.
+//
+// An `Event::Unlock` is always allowed, as it is the only way to undo the lock.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LockedPolicy {
+    // The account is frozen, every event is rejected.
+    #[default]
+    RejectAll,
+
+    // Funds can still be added to the account, either by depositing them or by resolving a dispute
+    // that was holding them, but nothing else.
+    AllowDepositsAndResolves,
+}
+
+impl LockedPolicy {
+    // @returns whether the event may be applied to a locked account
+    pub fn allows(&self, ev: &Event) -> bool {
+        match (self, ev) {
+            (_, Event::Unlock { .. }) => true,
+            (Self::RejectAll, _) => false,
+            (Self::AllowDepositsAndResolves, Event::Deposit { .. } | Event::Resolve { .. }) => true,
+            (Self::AllowDepositsAndResolves, _) => false,
+        }
+    }
+}
+
+// What happens when a withdrawal, rather than a deposit, is disputed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum WithdrawalDisputes {
+    // The withdrawal is held like a deposit would be, except that its amount is negative. This
+    // leaves `held` negative, and `available` larger than `total`, while the dispute is open.
+    #[default]
+    Legacy,
+
+    // Only deposits can be disputed, disputing a withdrawal is an error.
+    NotDisputable,
+
+    // The withdrawn funds are returned to the account, but held, while the dispute is open. If it
+    // is resolved they are withdrawn again, and if it is charged back they are released to the
+    // client (and the account is locked, as with any chargeback).
+    Reversal,
+}
+
+// How the fee for a deposit or withdrawal is worked out from its amount. The fee is always in the
+// same currency as the amount, a fee in the unspecified currency is charged in whichever currency
+// the amount is in.
+//
+// A deposit credits the client with the amount less the fee (which never takes more than the
+// amount), while a withdrawal debits the client with the amount plus the fee. Either way, the fee
+// is credited to the house account, so the total of all the accounts is unchanged. Fees are not
+// refunded if the transaction is later disputed or charged back.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum FeeSchedule {
+    // No fee is charged.
+    #[default]
+    Free,
+
+    // The same fee is charged, whatever the amount.
+    Flat(Amount),
+
+    // A share of the amount, in basis points (hundredths of a percent), rounded half up.
+    BasisPoints(AmountValue),
+
+    // The schedule of the last tier that the amount reaches. The tiers must be sorted by the amount
+    // that they start from, and amounts below the first tier are free.
+    Tiered(Vec<FeeTier>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeeTier {
+    pub from: Amount,
+    pub fees: FeeSchedule,
+}
+
+impl FeeSchedule {
+    // @returns the fee for a deposit or withdrawal of the given amount
+    pub fn fee(&self, amount: Amount) -> Result<Amount, AmountOpError> {
+        match self {
+            Self::Free => Ok(Amount::zero(amount.currency())),
+            Self::Flat(fee) => convert(*fee, amount),
+            Self::BasisPoints(bps) => amount.percent(*bps, RoundingMode::HalfUp),
+            Self::Tiered(tiers) => {
+                let mut schedule = &Self::Free;
+                for tier in tiers {
+                    if convert(tier.from, amount)? > amount {
+                        break;
+                    }
+                    schedule = &tier.fees;
+                }
+                schedule.fee(amount)
+            }
+        }
+    }
+}
+
+// @returns the part of a fee schedule given by `value` in the currency of the amount that the fee is
+// for, or an error if it is in another currency (or is more precise than the currency allows)
+fn convert(value: Amount, amount: Amount) -> Result<Amount, AmountOpError> {
+    let currency = amount.currency();
+    if value.currency().is_unspecified() || value.currency() == currency {
+        if let Some(value) = value.in_currency(currency) {
+            return Ok(value);
+        }
+    }
+    Err(AmountOpError {
+        lhs: amount,
+        rhs: Some(Operand::Amount(value)),
+        op: "charged",
+        kind: AmountOpErrorKind::CurrencyMismatch,
+    })
+}
+
+impl Encode for Policy {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        let locked: u8 = match self.locked {
+            LockedPolicy::RejectAll => 0,
+            LockedPolicy::AllowDepositsAndResolves => 1,
+        };
+        let disputes: u8 = match self.disputes {
+            DisputeMode::Tolerant => 0,
+            DisputeMode::Strict => 1,
+            DisputeMode::Deferred => 2,
+        };
+        let withdrawal_disputes: u8 = match self.withdrawal_disputes {
+            WithdrawalDisputes::Legacy => 0,
+            WithdrawalDisputes::NotDisputable => 1,
+            WithdrawalDisputes::Reversal => 2,
+        };
+        locked.encode(out)?;
+        disputes.encode(out)?;
+        withdrawal_disputes.encode(out)?;
+        self.fees.encode(out)?;
+        self.house.encode(out)?;
+        Ok(())
+    }
+}
+
+impl Decode for Policy {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let invalid = || DecodeError::new("invalid policy");
+        Ok(Self {
+            locked: match u8::decode(input)? {
+                0 => LockedPolicy::RejectAll,
+                1 => LockedPolicy::AllowDepositsAndResolves,
+                _ => return Err(invalid()),
+            },
+            disputes: match u8::decode(input)? {
+                0 => DisputeMode::Tolerant,
+                1 => DisputeMode::Strict,
+                2 => DisputeMode::Deferred,
+                _ => return Err(invalid()),
+            },
+            withdrawal_disputes: match u8::decode(input)? {
+                0 => WithdrawalDisputes::Legacy,
+                1 => WithdrawalDisputes::NotDisputable,
+                2 => WithdrawalDisputes::Reversal,
+                _ => return Err(invalid()),
+            },
+            fees: Decode::decode(input)?,
+            house: Decode::decode(input)?,
+        })
+    }
+}
+
+impl Encode for FeeSchedule {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        match self {
+            Self::Free => 0u8.encode(out)?,
+            Self::Flat(fee) => {
+                1u8.encode(out)?;
+                fee.encode(out)?;
+            }
+            Self::BasisPoints(bps) => {
+                2u8.encode(out)?;
+                encode_value(*bps, out)?;
+            }
+            Self::Tiered(tiers) => {
+                3u8.encode(out)?;
+                tiers.encode(out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Decode for FeeSchedule {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode(input)? {
+            0 => Ok(Self::Free),
+            1 => Ok(Self::Flat(Decode::decode(input)?)),
+            2 => Ok(Self::BasisPoints(decode_value(input)?)),
+            3 => Ok(Self::Tiered(Decode::decode(input)?)),
+            _ => Err(DecodeError::new("invalid fee schedule")),
+        }
+    }
+}
+
+impl Encode for FeeTier {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        self.from.encode(out)?;
+        self.fees.encode(out)?;
+        Ok(())
+    }
+}
+
+impl Decode for FeeTier {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Self {
+            from: Decode::decode(input)?,
+            fees: Decode::decode(input)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Currency;
+
+    #[test]
+    fn test_fee_schedule() {
+        let amount = Amount::new(100_0000);
+        assert_eq!(FeeSchedule::Free.fee(amount), Ok(Amount::new(0)));
+        assert_eq!(
+            FeeSchedule::Flat(Amount::new(5000)).fee(amount),
+            Ok(Amount::new(5000))
+        );
+        // 25 bps of 100 is 0.25, and of 0.0002 is 0.0000005, which is rounded away.
+        assert_eq!(
+            FeeSchedule::BasisPoints(25).fee(amount),
+            Ok(Amount::new(2500))
+        );
+        assert_eq!(
+            FeeSchedule::BasisPoints(25).fee(Amount::new(2)),
+            Ok(Amount::new(0))
+        );
+
+        let tiered = FeeSchedule::Tiered(vec![
+            FeeTier {
+                from: Amount::new(10_0000),
+                fees: FeeSchedule::Flat(Amount::new(1_0000)),
+            },
+            FeeTier {
+                from: Amount::new(1000_0000),
+                fees: FeeSchedule::BasisPoints(10),
+            },
+        ]);
+        assert_eq!(tiered.fee(Amount::new(9_9999)), Ok(Amount::new(0)));
+        assert_eq!(tiered.fee(Amount::new(10_0000)), Ok(Amount::new(1_0000)));
+        assert_eq!(tiered.fee(Amount::new(5000_0000)), Ok(Amount::new(5_0000)));
+    }
+
+    #[test]
+    fn test_fee_currency() {
+        let usd = Currency::from_code("USD").unwrap();
+        let eur = Currency::from_code("EUR").unwrap();
+        let amount = Amount::with_scale(10_00, 2).in_currency(usd).unwrap();
+
+        // A fee without a currency is charged in the amount's currency.
+        let fee = FeeSchedule::Flat(Amount::new(5000)).fee(amount).unwrap();
+        assert_eq!(fee.currency(), usd);
+        assert_eq!(fee.scale(), 2);
+        assert_eq!(fee.to_string(), "0.50");
+
+        let fee = Amount::with_scale(50, 2).in_currency(eur).unwrap();
+        assert_eq!(
+            FeeSchedule::Flat(fee).fee(amount).map_err(|err| err.kind),
+            Err(AmountOpErrorKind::CurrencyMismatch)
+        );
+        // A fraction of a cent can not be charged.
+        assert!(FeeSchedule::Flat(Amount::new(1)).fee(amount).is_err());
+    }
+
+    #[test]
+    fn test_policy_codec() {
+        let policy = Policy {
+            locked: LockedPolicy::AllowDepositsAndResolves,
+            disputes: DisputeMode::Strict,
+            withdrawal_disputes: WithdrawalDisputes::Reversal,
+            fees: FeeSchedule::Tiered(vec![
+                FeeTier {
+                    from: Amount::new(0),
+                    fees: FeeSchedule::Flat(Amount::new(1000)),
+                },
+                FeeTier {
+                    from: Amount::new(100_0000),
+                    fees: FeeSchedule::BasisPoints(25),
+                },
+            ]),
+            house: 7,
+        };
+        assert_eq!(
+            crate::decode_from_slice::<Policy>(&crate::encode_to_vec(&policy).unwrap()),
+            Ok(policy)
+        );
+    }
+}
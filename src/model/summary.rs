@@ -1,23 +1,59 @@
-use super::{Amount, ClientId};
+use super::{Account, Amount, ClientId, Currency, RescaleError};
 use serde::Serialize;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct Summary {
     pub client: ClientId,
+    // The currency of the balance that the summary is for. Left out entirely (including from the
+    // csv headers) when it is None, which is how the output leaves out the currency column unless it
+    // is asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
     pub available: Amount,
     pub held: Amount,
     pub total: Amount,
     pub locked: bool,
 }
 
+impl Summary {
+    // @returns one summary for each of the account's balances, sorted by currency
+    pub fn for_account(client: ClientId, account: &Account) -> impl Iterator<Item = Self> + '_ {
+        account.balances().map(move |balance| Self {
+            client,
+            currency: Some(balance.currency()),
+            available: balance.available(),
+            held: balance.held(),
+            total: balance.total(),
+            locked: account.is_locked(),
+        })
+    }
+
+    // @returns the summary with its amounts rescaled to the given number of decimal places, dropping
+    // any extra ones, unless they are in a specified currency (which always uses its minor unit)
+    pub fn with_scale(self, scale: u32) -> Result<Self, RescaleError> {
+        if self
+            .currency
+            .is_some_and(|currency| !currency.is_unspecified())
+        {
+            return Ok(self);
+        }
+        Ok(Self {
+            available: self.available.truncate(scale)?,
+            held: self.held.truncate(scale)?,
+            total: self.total.truncate(scale)?,
+            ..self
+        })
+    }
+}
+
 impl PartialOrd for Summary {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.client.partial_cmp(&other.client)
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for Summary {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.client.cmp(&other.client)
+        (self.client, self.currency).cmp(&(other.client, other.currency))
     }
 }
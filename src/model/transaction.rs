@@ -1,111 +1,1522 @@
-use super::{Account, Amount, AmountOpError, Event};
+use super::{
+    Account, Amount, AmountOpError, ClientId, DisputeMode, Event, Policy, TransactionId,
+    WithdrawalDisputes,
+};
+use crate::{Decode, DecodeError, Encode, EncodeError};
+use std::fmt::{Display, Formatter, Result as FmtResult};
 
-#[derive(Default, Debug, Clone, Copy)]
+// Where a transaction is in its lifecycle. The legal transitions are:
+//
+// - `Pending` -> `Settled`, when the deposit, withdrawal or transfer arrives
+// - `Settled` -> `Disputed`, when the transaction is disputed, and its amount is held
+// - `Disputed` -> `Resolved`, when the dispute is resolved, and the held amount is released
+// - `Disputed` -> `ChargedBack`, when the disputed amount is charged back, and the account locked
+// - `Disputed`, `Resolved` or `ChargedBack` -> `Disputed`, when more of the amount is disputed
+//
+// A dispute can name only part of the amount, in which case only that part is held. A resolve or
+// chargeback can also name only part of what is disputed, leaving the rest disputed until the other
+// one arrives (eg: a partial chargeback, followed by a resolve of what is left). The transaction
+// stays `Disputed` until nothing is left disputed, and ends up `ChargedBack` if any of it was.
+//
+// A transaction can be disputed again, for as long as what is disputed and what has been charged
+// back together are no more than the amount (less what has been refunded). A dispute without an
+// amount is for all of what is left, and is not allowed once nothing is.
+//
+// This is enforced by `DisputeMode::Strict`, and followed by `DisputeMode::Deferred`. With
+// `DisputeMode::Tolerant` the state only reports what has happened to the transaction so far, as
+// the events are applied in whatever order they arrive in.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionState {
+    // The deposit or withdrawal has not arrived yet, so the amount is unknown.
+    #[default]
+    Pending,
+    Settled,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+impl Display for TransactionState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let name = match self {
+            Self::Pending => "pending",
+            Self::Settled => "settled",
+            Self::Disputed => "disputed",
+            Self::Resolved => "resolved",
+            Self::ChargedBack => "charged back",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Transaction {
+    client: ClientId,
+    // The deposit, withdrawal or transfer event that gave this transaction its amount, if it has
+    // arrived.
+    origin: Option<Event>,
+    amount: Option<Amount>,
+    // The fee that was credited to the house account for the deposit or withdrawal, if any.
+    fee: Option<Amount>,
+    state: TransactionState,
+    // The dispute related events that have arrived. With `DisputeMode::Strict` they are always
+    // applied as they arrive. With `DisputeMode::Deferred` they can arrive before the state they
+    // apply to has been reached, in which case they are remembered here, and applied once it is.
+    // With `DisputeMode::Tolerant` they are applied right away, depending on which of the others
+    // have arrived before them.
+    dispute: Option<Request>,
+    resolve: Option<Request>,
+    chargeback: Option<Request>,
+    // The part of the amount that is currently disputed, the part that has been charged back, and
+    // the part that has been refunded. All have the same sign as the amount.
+    disputed_amount: Amount,
+    charged_back_amount: Amount,
+    refunded_amount: Amount,
+}
+
+// The other accounts and transactions that an event can touch, besides the transaction that it is
+// for and the account of its client.
+#[derive(Debug, Default)]
+pub struct Related<'a> {
+    // The account on the other side of a transfer, which is needed for the transfer itself, and for
+    // any dispute of it.
+    pub recipient: Option<&'a mut Account>,
+    // The account that fees are credited to. No fee is charged without it, which is the case for the
+    // house's own transactions.
+    pub house: Option<&'a mut Account>,
+    // The transaction that a refund gives money back for.
+    pub refunded: Option<&'a mut Transaction>,
+}
+
+// A dispute related event that has arrived, along with the amount that it names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Request {
+    // None when the event is for everything that it can apply to.
     amount: Option<Amount>,
-    disputed: bool,
-    resolved: bool,
-    chargebacked: bool,
+    applied: bool,
 }
 
 impl Transaction {
-    pub fn new() -> Self {
-        Self::default()
+    pub fn new(client: ClientId) -> Self {
+        Self {
+            client,
+            origin: None,
+            amount: None,
+            fee: None,
+            state: TransactionState::Pending,
+            dispute: None,
+            resolve: None,
+            chargeback: None,
+            disputed_amount: Amount::default(),
+            charged_back_amount: Amount::default(),
+            refunded_amount: Amount::default(),
+        }
+    }
+
+    pub fn client(&self) -> ClientId {
+        self.client
     }
 
     pub fn amount(&self) -> Amount {
         self.amount.unwrap_or_default()
     }
 
+    pub fn fee(&self) -> Option<Amount> {
+        self.fee
+    }
+
+    pub fn origin(&self) -> Option<Event> {
+        self.origin
+    }
+
+    pub fn state(&self) -> TransactionState {
+        self.state
+    }
+
     pub fn is_disputed(&self) -> bool {
-        self.disputed
+        self.dispute.is_some()
     }
 
     pub fn is_resolved(&self) -> bool {
-        self.resolved
+        self.resolve.is_some()
     }
 
     pub fn is_chargebacked(&self) -> bool {
-        self.chargebacked
+        self.chargeback.is_some()
     }
 
-    pub fn apply(&mut self, ev: Event, account: &mut Account) -> Result<(), AmountOpError> {
-        match ev {
-            Event::Deposit { amount, .. } => {
-                // This breaks our assumption that this isn't an externally facing service, and that
-                // all of the input is valid.
-                if self.amount.is_some() {
-                    panic!("Transaction already has an amount");
+    // @returns the part of the amount that is currently disputed
+    pub fn disputed_amount(&self) -> Amount {
+        self.disputed_amount
+    }
+
+    // @returns the part of the amount that has been charged back
+    pub fn charged_back_amount(&self) -> Amount {
+        self.charged_back_amount
+    }
+
+    // @returns the part of the amount that has been given back by refunds
+    pub fn refunded_amount(&self) -> Amount {
+        self.refunded_amount
+    }
+
+    // Applies the event to the transaction and the account that it belongs to, along with any of the
+    // related accounts and transactions that it touches. If the event fails, none of them are
+    // changed.
+    pub fn apply(
+        &mut self,
+        ev: Event,
+        account: &mut Account,
+        related: Related<'_>,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        // An event only touches the balances in its own currency, and in the transaction's (which
+        // is the same one, unless the event is in the wrong currency), so only those are kept to
+        // undo a failed event with.
+        let currency = self.amount().currency();
+        let currencies = [
+            ev.amount().map_or(currency, |amount| amount.currency()),
+            currency,
+        ];
+        let Related {
+            mut recipient,
+            mut house,
+            refunded,
+        } = related;
+        let checkpoints = (
+            account.checkpoint(currencies),
+            recipient
+                .as_deref()
+                .map(|other| other.checkpoint(currencies)),
+            house.as_deref().map(|other| other.checkpoint(currencies)),
+        );
+
+        let mut transaction = *self;
+        let mut refund = refunded.as_deref().copied();
+        let result = transaction.apply_event(
+            ev,
+            account,
+            Related {
+                recipient: recipient.as_deref_mut(),
+                house: house.as_deref_mut(),
+                refunded: refund.as_mut(),
+            },
+            policy,
+        );
+        if result.is_err() {
+            account.rollback(checkpoints.0);
+            if let (Some(other), Some(checkpoint)) = (recipient, checkpoints.1) {
+                other.rollback(checkpoint);
+            }
+            if let (Some(other), Some(checkpoint)) = (house, checkpoints.2) {
+                other.rollback(checkpoint);
+            }
+            return result;
+        }
+
+        *self = transaction;
+        if let (Some(refunded), Some(refund)) = (refunded, refund) {
+            *refunded = refund;
+        }
+        Ok(())
+    }
+
+    fn apply_event(
+        &mut self,
+        ev: Event,
+        account: &mut Account,
+        related: Related<'_>,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        let Related {
+            mut recipient,
+            house,
+            refunded,
+        } = related;
+
+        if ev.client() != self.client {
+            match ev {
+                // The deposit or withdrawal is what decides who the transaction belongs to. Any
+                // facts that arrived before it were sent by a different client, and are dropped
+                // (the shard reports them, see `Transaction::requests`). If it has already arrived,
+                // this is a duplicate, which is reported below.
+                Event::Deposit { .. }
+                | Event::Withdrawal { .. }
+                | Event::Transfer { .. }
+                | Event::Refund { .. } => {
+                    if self.origin.is_none() {
+                        *self = Self::new(ev.client());
+                    }
                 }
+                _ => return Err(TransactionError::ClientMismatch { owner: self.client }),
+            }
+        }
 
-                let new_amount = amount;
-                account.deposit(amount)?;
+        if account.is_locked() && !policy.locked.allows(&ev) {
+            return Err(TransactionError::AccountLocked {
+                client: self.client,
+            });
+        }
+
+        match ev {
+            Event::Deposit { amount, .. } | Event::Withdrawal { amount, .. } => {
+                if let Some(original) = self.origin {
+                    return Err(TransactionError::DuplicateTransaction { original });
+                }
+                let zero = Amount::zero(amount.currency());
+                if amount <= zero {
+                    return Err(TransactionError::NonPositiveAmount { amount });
+                }
 
-                if self.disputed && !self.resolved {
-                    if self.chargebacked {
-                        account.chargeback(new_amount, false)?;
-                    } else {
-                        account.hold(new_amount)?;
+                let fee = match house {
+                    Some(house) => {
+                        let fee = policy.fees.fee(amount)?;
+                        // A deposit can not cost more than it brings in.
+                        let fee = if let Event::Deposit { .. } = ev {
+                            fee.min(amount)
+                        } else {
+                            fee
+                        };
+                        // Leave the house account alone when there is nothing to credit to it.
+                        if fee != zero {
+                            // Crediting the fee is allowed whenever a deposit into the house
+                            // account would be.
+                            let credit = Event::Deposit {
+                                client: policy.house,
+                                tx: ev.transaction().unwrap_or_default(),
+                                amount: fee,
+                            };
+                            if house.is_locked() && !policy.locked.allows(&credit) {
+                                return Err(TransactionError::AccountLocked {
+                                    client: policy.house,
+                                });
+                            }
+                            house.deposit(fee)?;
+                        }
+                        (fee != zero).then_some(fee)
                     }
+                    None => None,
+                };
+                let charged = fee.unwrap_or(zero);
+
+                // A deposit's transaction is for what the client was credited with, so that a
+                // dispute or chargeback never takes back the fee as well.
+                let new_amount = if let Event::Deposit { .. } = ev {
+                    let credited = (amount - charged)?;
+                    account.deposit(credited)?;
+                    credited
+                } else {
+                    account.withdraw((amount + charged)?)?;
+                    (-amount)?
+                };
+
+                self.settle(ev, new_amount);
+                self.fee = fee;
+            }
+            Event::Transfer {
+                from,
+                to,
+                tx,
+                amount,
+            } => {
+                if let Some(original) = self.origin {
+                    return Err(TransactionError::DuplicateTransaction { original });
+                }
+                if amount <= Amount::zero(amount.currency()) {
+                    return Err(TransactionError::NonPositiveAmount { amount });
+                }
+                // The sender and the recipient can not be the same account, which is also the only
+                // way for the recipient to be missing.
+                let Some(recipient) = recipient.as_deref_mut().filter(|_| from != to) else {
+                    return Err(TransactionError::InvalidTransfer { to });
+                };
+                // Receiving a transfer is allowed whenever a deposit would be.
+                let credit = Event::Deposit {
+                    client: to,
+                    tx,
+                    amount,
+                };
+                if recipient.is_locked() && !policy.locked.allows(&credit) {
+                    return Err(TransactionError::AccountLocked { client: to });
                 }
 
-                self.amount = Some(new_amount);
+                account.withdraw(amount)?;
+                recipient.deposit(amount)?;
+
+                self.settle(ev, amount);
             }
-            Event::Withdrawal { amount, .. } => {
-                // This breaks our assumption that this isn't an externally facing service, and that
-                // all of the input is valid.
-                if self.amount.is_some() {
-                    panic!("Transaction already has an amount");
+            Event::Refund {
+                original_tx,
+                amount,
+                ..
+            } => {
+                if let Some(original) = self.origin {
+                    return Err(TransactionError::DuplicateTransaction { original });
+                }
+                // Only a deposit that has arrived can be refunded.
+                let Some(refunded) = refunded
+                    .filter(|refunded| matches!(refunded.origin, Some(Event::Deposit { .. })))
+                else {
+                    return Err(TransactionError::NotRefundable { original_tx });
+                };
+                if refunded.client != self.client {
+                    return Err(TransactionError::ClientMismatch {
+                        owner: refunded.client,
+                    });
                 }
 
-                let new_amount = (-amount)?;
-                if !self.chargebacked {
-                    account.withdraw(amount)?;
+                // Whatever is disputed, or has been charged back or refunded already, is not left
+                // to refund.
+                let amount = refunded.portion(Some(amount), refunded.undisputed()?)?;
+                account.withdraw(amount)?;
+                refunded.refunded_amount = (refunded.refunded_amount + amount)?;
+
+                self.settle(ev, (-amount)?);
+            }
+            Event::Dispute { amount, .. } => self.step(
+                Step::Dispute,
+                amount,
+                account,
+                recipient.as_deref_mut(),
+                policy,
+            )?,
+            Event::Resolve { amount, .. } => self.step(
+                Step::Resolve,
+                amount,
+                account,
+                recipient.as_deref_mut(),
+                policy,
+            )?,
+            Event::Chargeback { amount, .. } => self.step(
+                Step::Chargeback,
+                amount,
+                account,
+                recipient.as_deref_mut(),
+                policy,
+            )?,
+            Event::Unlock { .. } => {
+                // Unlocking acts on the account as a whole, and is handled by the shard. There is
+                // nothing for the transaction to do.
+            }
+        }
+
+        let current = match ev {
+            Event::Dispute { .. } => Some(Step::Dispute),
+            Event::Resolve { .. } => Some(Step::Resolve),
+            Event::Chargeback { .. } => Some(Step::Chargeback),
+            Event::Unlock { .. } => return Ok(()),
+            _ => None,
+        };
+        match policy.disputes {
+            DisputeMode::Tolerant if current.is_none() => {
+                self.tolerate_origin(account, recipient, policy)?
+            }
+            DisputeMode::Deferred => self.catch_up(current, account, recipient, policy)?,
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    // Records the event that gave the transaction its amount.
+    fn settle(&mut self, ev: Event, amount: Amount) {
+        let zero = Amount::zero(amount.currency());
+        self.origin = Some(ev);
+        self.amount = Some(amount);
+        self.state = TransactionState::Settled;
+        self.disputed_amount = zero;
+        self.charged_back_amount = zero;
+        self.refunded_amount = zero;
+    }
+
+    // Records that a dispute related event arrived. In strict mode it must be legal right away, in
+    // deferred mode it is applied by `catch_up`, once the transaction is ready for it, and in
+    // tolerant mode it is applied by `tolerate`.
+    fn step(
+        &mut self,
+        step: Step,
+        amount: Option<Amount>,
+        account: &mut Account,
+        recipient: Option<&mut Account>,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        let applied = match policy.disputes {
+            DisputeMode::Strict => {
+                self.transition(step, amount, account, recipient, policy)?;
+                true
+            }
+            DisputeMode::Tolerant => {
+                return self.tolerate(step, amount, account, recipient, policy);
+            }
+            DisputeMode::Deferred => {
+                // Report a dispute that can never be applied right away, if it is already known to
+                // be one. Otherwise `catch_up` will skip it once the withdrawal arrives.
+                if let (Step::Dispute, false) = (step, self.is_disputable(policy)) {
+                    return Err(TransactionError::NotDisputable);
                 }
+                // An event that arrives while another of its kind is still waiting to be applied
+                // waits along with it, for the amounts that both of them name.
+                if let Some(waiting) = self.request(step).filter(|request| !request.applied) {
+                    let amount = match (waiting.amount, amount) {
+                        (Some(waiting), Some(amount)) => Some((waiting + amount)?),
+                        _ => None,
+                    };
+                    *self.request_mut(step) = Some(Request {
+                        amount,
+                        applied: false,
+                    });
+                    return Ok(());
+                }
+                false
+            }
+        };
 
-                if self.disputed && !self.resolved {
-                    if self.chargebacked {
-                        account.chargeback(new_amount, false)?;
-                    } else {
-                        account.hold(new_amount)?;
-                    }
+        *self.request_mut(step) = Some(Request { amount, applied });
+        Ok(())
+    }
+
+    fn request(&self, step: Step) -> Option<Request> {
+        match step {
+            Step::Dispute => self.dispute,
+            Step::Resolve => self.resolve,
+            Step::Chargeback => self.chargeback,
+        }
+    }
+
+    fn request_mut(&mut self, step: Step) -> &mut Option<Request> {
+        match step {
+            Step::Dispute => &mut self.dispute,
+            Step::Resolve => &mut self.resolve,
+            Step::Chargeback => &mut self.chargeback,
+        }
+    }
+
+    // @returns the part of `limit` that the event names, with the same sign as the transaction's
+    // amount, or all of it if the event does not name an amount
+    fn portion(
+        &self,
+        requested: Option<Amount>,
+        limit: Amount,
+    ) -> Result<Amount, TransactionError> {
+        let Some(requested) = requested else {
+            return Ok(limit);
+        };
+        let negative = self.amount() < Amount::zero(self.amount().currency());
+        let available = if negative { (-limit)? } else { limit };
+
+        let remaining = (available - requested)?;
+        if requested <= Amount::zero(requested.currency())
+            || remaining < Amount::zero(remaining.currency())
+        {
+            return Err(TransactionError::ExceedsAmount {
+                requested,
+                available,
+            });
+        }
+        Ok(if negative { (-requested)? } else { requested })
+    }
+
+    // @returns the part of the amount that has not been refunded, and is not disputed or charged
+    // back, which is what is left to dispute or refund
+    fn undisputed(&self) -> Result<Amount, TransactionError> {
+        let left = (self.amount() - self.refunded_amount)?;
+        Ok(((left - self.disputed_amount)? - self.charged_back_amount)?)
+    }
+
+    // @returns the state that the transaction is in after a resolve or chargeback, which is only
+    // final once nothing is left disputed
+    fn settle_dispute(&self) -> TransactionState {
+        if self.disputed_amount != Amount::zero(self.disputed_amount.currency()) {
+            TransactionState::Disputed
+        } else if self.charged_back_amount != Amount::zero(self.charged_back_amount.currency()) {
+            TransactionState::ChargedBack
+        } else {
+            TransactionState::Resolved
+        }
+    }
+
+    // A refund takes money out of the account just like a withdrawal does, and is disputed like one.
+    fn is_withdrawal(&self) -> bool {
+        matches!(
+            self.origin,
+            Some(Event::Withdrawal { .. } | Event::Refund { .. })
+        )
+    }
+
+    fn is_disputable(&self, policy: &Policy) -> bool {
+        !self.is_withdrawal() || policy.withdrawal_disputes != WithdrawalDisputes::NotDisputable
+    }
+
+    // Moves the transaction to the next state in its lifecycle, updating the account to match.
+    fn transition(
+        &mut self,
+        step: Step,
+        requested: Option<Amount>,
+        account: &mut Account,
+        recipient: Option<&mut Account>,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        if let (Step::Dispute, false) = (step, self.is_disputable(policy)) {
+            return Err(TransactionError::NotDisputable);
+        }
+
+        self.state = match (self.state, step) {
+            (TransactionState::Pending, _) => {
+                return Err(TransactionError::InvalidTransition {
+                    from: TransactionState::Pending,
+                })
+            }
+            (from, Step::Dispute) => {
+                let left = self.undisputed()?;
+                if requested.is_none() && left == Amount::zero(left.currency()) {
+                    return Err(TransactionError::InvalidTransition { from });
                 }
+                let amount = self.portion(requested, left)?;
+                self.affect(Effect::Hold, amount, account, recipient, policy)?;
+                self.disputed_amount = (self.disputed_amount + amount)?;
+                TransactionState::Disputed
+            }
+            (TransactionState::Disputed, Step::Resolve) => {
+                let amount = self.portion(requested, self.disputed_amount)?;
+                self.affect(Effect::Release, amount, account, recipient, policy)?;
+                self.disputed_amount = (self.disputed_amount - amount)?;
+                self.settle_dispute()
+            }
+            (TransactionState::Disputed, Step::Chargeback) => {
+                let amount = self.portion(requested, self.disputed_amount)?;
+                let effect = Effect::Chargeback { held: true };
+                self.affect(effect, amount, account, recipient, policy)?;
+                self.disputed_amount = (self.disputed_amount - amount)?;
+                self.charged_back_amount = (self.charged_back_amount + amount)?;
+                self.settle_dispute()
+            }
+            (from, _) => return Err(TransactionError::InvalidTransition { from }),
+        };
 
-                self.amount = Some(new_amount);
+        Ok(())
+    }
+
+    // Changes the accounts for part of the transaction's amount being held, released or charged
+    // back.
+    //
+    // For a transfer the funds are held in the recipient's account, and a chargeback returns them to
+    // the sender, locking the recipient's account (as it is the one being charged back). A withdrawal
+    // that is disputed as a reversal has the withdrawn funds returned but held, and a chargeback
+    // releases them to the client.
+    fn affect(
+        &self,
+        effect: Effect,
+        amount: Amount,
+        account: &mut Account,
+        recipient: Option<&mut Account>,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        if let Some(Event::Transfer { to, .. }) = self.origin {
+            let Some(recipient) = recipient else {
+                return Err(TransactionError::InvalidTransfer { to });
+            };
+            match effect {
+                Effect::Hold => recipient.hold(amount)?,
+                Effect::Release => recipient.release(amount)?,
+                Effect::Chargeback { held } => {
+                    recipient.chargeback(amount, held)?;
+                    account.deposit(amount)?;
+                }
             }
-            Event::Dispute { .. } => {
-                self.disputed = true;
+            return Ok(());
+        }
 
-                if let Some(amount) = self.amount {
-                    match (self.resolved, self.chargebacked) {
-                        (false, false) => account.hold(amount)?,
-                        (true, false) => account.release(amount)?,
-                        (_, true) => account.chargeback(amount, false)?,
-                    }
+        if self.is_withdrawal() && policy.withdrawal_disputes == WithdrawalDisputes::Reversal {
+            let withdrawn = (-amount)?;
+            match effect {
+                Effect::Hold => {
+                    account.deposit(withdrawn)?;
+                    account.hold(withdrawn)?;
+                }
+                Effect::Release => {
+                    account.release(withdrawn)?;
+                    account.withdraw(withdrawn)?;
+                }
+                Effect::Chargeback { held: true } => {
+                    account.release(withdrawn)?;
+                    account.lock();
+                }
+                Effect::Chargeback { held: false } => {
+                    account.deposit(withdrawn)?;
+                    account.lock();
                 }
             }
-            Event::Resolve { .. } => {
-                self.resolved = true;
+            return Ok(());
+        }
 
-                if let Some(amount) = self.amount {
-                    match (self.disputed, self.chargebacked) {
-                        (true, false) => account.release(amount)?,
-                        _ => {}
-                    }
+        match effect {
+            Effect::Hold => account.hold(amount)?,
+            Effect::Release => account.release(amount)?,
+            Effect::Chargeback { held } => account.chargeback(amount, held)?,
+        }
+        Ok(())
+    }
+
+    // Applies a dispute related event with `DisputeMode::Tolerant`. The event acts on the accounts
+    // as soon as the transaction has an amount, depending only on which of the other events have
+    // arrived before it:
+    //
+    // - a dispute holds the amount, unless it was resolved already, in which case the amount is
+    //   released instead, or charged back already, in which case it is charged back
+    // - a resolve releases the amount, if it is disputed and has not been charged back
+    // - a chargeback charges the amount back, if it is disputed and has not been resolved
+    //
+    // So a resolve that arrives before the dispute cancels it out, and disputing a resolved
+    // transaction again releases the amount again. Every event acts on the amount that it names,
+    // or on all of what it can act on.
+    fn tolerate(
+        &mut self,
+        step: Step,
+        requested: Option<Amount>,
+        account: &mut Account,
+        recipient: Option<&mut Account>,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        if let (Step::Dispute, false) = (step, self.is_disputable(policy)) {
+            return Err(TransactionError::NotDisputable);
+        }
+
+        // A partial resolve or chargeback only counts once nothing is left disputed.
+        let open = self.is_open();
+        let seen = (
+            self.dispute.is_some(),
+            self.resolve.is_some() && !open,
+            self.chargeback.is_some() && !open,
+        );
+        let effect = match (step, seen) {
+            (Step::Dispute, (_, false, false)) => Some(Effect::Hold),
+            (Step::Dispute, (_, true, false)) => Some(Effect::Release),
+            (Step::Dispute, (_, _, true)) => Some(Effect::Chargeback { held: false }),
+            (Step::Resolve, (true, _, false)) => Some(Effect::Release),
+            (Step::Chargeback, (true, false, _)) => Some(Effect::Chargeback { held: true }),
+            _ => None,
+        };
+        let applied = self.origin.is_some();
+        if let (Some(effect), true) = (effect, applied) {
+            // A resolve or chargeback acts on what the dispute is holding. A dispute that releases
+            // acts on what has not been refunded, any other dispute on what is left to dispute.
+            let limit = match (step, effect) {
+                (Step::Dispute, Effect::Release) => (self.amount() - self.refunded_amount)?,
+                (Step::Dispute, _) => self.undisputed()?,
+                (Step::Resolve | Step::Chargeback, _) => self.disputed_amount,
+            };
+            let amount = self.portion(requested, limit)?;
+            self.affect(effect, amount, account, recipient, policy)?;
+            self.record(effect, amount)?;
+        }
+
+        let request = self.request_mut(step);
+        if request.is_none() {
+            *request = Some(Request {
+                amount: requested,
+                applied,
+            });
+        }
+        self.state = self.tolerant_state();
+        Ok(())
+    }
+
+    // Applies the disputes that arrived before the transaction's amount did, with
+    // `DisputeMode::Tolerant`. An open dispute holds the amount, or charges it back if a chargeback
+    // has arrived as well. Like with `catch_up`, a dispute that is not allowed, or that names more
+    // than the amount, is left unapplied.
+    fn tolerate_origin(
+        &mut self,
+        account: &mut Account,
+        recipient: Option<&mut Account>,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        if let (Some(dispute), None, true) =
+            (self.dispute, self.resolve, self.is_disputable(policy))
+        {
+            let effect = match self.chargeback {
+                Some(_) => Effect::Chargeback { held: false },
+                None => Effect::Hold,
+            };
+            match self.portion(dispute.amount, self.amount()) {
+                Ok(amount) => {
+                    self.affect(effect, amount, account, recipient, policy)?;
+                    self.record(effect, amount)?;
                 }
+                Err(TransactionError::ExceedsAmount { .. }) => {}
+                Err(err) => return Err(err),
             }
-            Event::Chargeback { .. } => {
-                self.chargebacked = true;
+        }
 
-                if let Some(amount) = self.amount {
-                    match (self.disputed, self.resolved) {
-                        (true, false) => account.chargeback(amount, true)?,
-                        _ => {}
-                    }
+        for request in [&mut self.dispute, &mut self.resolve, &mut self.chargeback]
+            .into_iter()
+            .flatten()
+        {
+            request.applied = true;
+        }
+        self.state = self.tolerant_state();
+        Ok(())
+    }
+
+    // Keeps track of how much of the amount is disputed and charged back, after the accounts have
+    // been changed by `affect`.
+    fn record(&mut self, effect: Effect, amount: Amount) -> Result<(), TransactionError> {
+        match effect {
+            Effect::Hold => self.disputed_amount = (self.disputed_amount + amount)?,
+            Effect::Release => self.disputed_amount = (self.disputed_amount - amount)?,
+            Effect::Chargeback { held } => {
+                if held {
+                    self.disputed_amount = (self.disputed_amount - amount)?;
                 }
+                self.charged_back_amount = (self.charged_back_amount + amount)?;
+            }
+        }
+        Ok(())
+    }
+
+    // @returns whether part of the amount is disputed. Releasing what was never held (see
+    // `tolerate`) leaves less than nothing disputed, which is not open.
+    fn is_open(&self) -> bool {
+        let zero = Amount::zero(self.amount().currency());
+        if self.amount() < zero {
+            self.disputed_amount < zero
+        } else {
+            self.disputed_amount > zero
+        }
+    }
+
+    // @returns the state that the transaction has reached with `DisputeMode::Tolerant`, which
+    // follows from what has happened to its amount so far
+    fn tolerant_state(&self) -> TransactionState {
+        let zero = Amount::zero(self.amount().currency());
+        if self.origin.is_none() {
+            TransactionState::Pending
+        } else if self.is_open() {
+            TransactionState::Disputed
+        } else if self.charged_back_amount != zero {
+            TransactionState::ChargedBack
+        } else if self.dispute.is_some() && self.resolve.is_some() {
+            TransactionState::Resolved
+        } else {
+            TransactionState::Settled
+        }
+    }
+
+    // Applies any of the events that arrived before the transaction was ready for them, in the
+    // order of the transaction lifecycle. A dispute that is not allowed is left unapplied, as is an
+    // earlier event that names more than it can apply to. The current event (the one being applied)
+    // is reported if it does that instead.
+    fn catch_up(
+        &mut self,
+        current: Option<Step>,
+        account: &mut Account,
+        mut recipient: Option<&mut Account>,
+        policy: &Policy,
+    ) -> Result<(), TransactionError> {
+        let pending = |request: Option<Request>| request.is_some_and(|request| !request.applied);
+        loop {
+            let step = match self.state {
+                TransactionState::Pending => return Ok(()),
+                _ if pending(self.dispute) && self.is_disputable(policy) => Step::Dispute,
+                TransactionState::Disputed if pending(self.resolve) => Step::Resolve,
+                TransactionState::Disputed if pending(self.chargeback) => Step::Chargeback,
+                _ => return Ok(()),
+            };
+
+            let Some(request) = self.request_mut(step) else {
+                return Ok(());
+            };
+            request.applied = true;
+            let amount = request.amount;
+            match self.transition(step, amount, account, recipient.as_deref_mut(), policy) {
+                Err(
+                    TransactionError::ExceedsAmount { .. }
+                    | TransactionError::InvalidTransition { .. },
+                ) if Some(step) != current => continue,
+                result => result?,
             }
         }
+    }
+
+    // @returns the dispute related events that have arrived for the transaction (whether or not they
+    // have been applied yet), in the order of the transaction lifecycle
+    pub fn requests(&self, tx: TransactionId) -> impl Iterator<Item = Event> {
+        let client = self.client;
+        [
+            self.dispute.map(|request| Event::Dispute {
+                client,
+                tx,
+                amount: request.amount,
+            }),
+            self.resolve.map(|request| Event::Resolve {
+                client,
+                tx,
+                amount: request.amount,
+            }),
+            self.chargeback.map(|request| Event::Chargeback {
+                client,
+                tx,
+                amount: request.amount,
+            }),
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    // @returns whether the fact carried by the event has already been applied to this transaction
+    pub fn has_seen(&self, ev: &Event) -> bool {
+        match ev {
+            Event::Deposit { .. }
+            | Event::Withdrawal { .. }
+            | Event::Transfer { .. }
+            | Event::Refund { .. } => self.origin.is_some(),
+            Event::Dispute { .. } => self.is_disputed(),
+            Event::Resolve { .. } => self.is_resolved(),
+            Event::Chargeback { .. } => self.is_chargebacked(),
+            Event::Unlock { .. } => false,
+        }
+    }
+}
+
+// The events that move a transaction through its lifecycle, once it has been settled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Step {
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+// What a dispute related event does to the accounts. A chargeback is either of funds that are
+// held, or (when the dispute arrived after the chargeback) of funds that are still available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Effect {
+    Hold,
+    Release,
+    Chargeback { held: bool },
+}
+
+// The reasons that an event can fail to be applied to a transaction.
+#[derive(Debug, Clone, Copy)]
+pub enum TransactionError {
+    // The arithmetic required by the event would have overflowed.
+    Overflow(AmountOpError),
+
+    // A deposit, withdrawal or transfer was for an amount that is zero or negative (which would move
+    // the funds the other way around, without checking that there are enough of them).
+    NonPositiveAmount {
+        amount: Amount,
+    },
+
+    // A withdrawal asked for more than the account has available.
+    InsufficientFunds {
+        requested: Amount,
+        available: Amount,
+    },
+
+    // The account has been locked, and the policy does not allow this kind of event on it. This is
+    // the recipient's account, rather than the sender's, when receiving a transfer is not allowed.
+    AccountLocked {
+        client: ClientId,
+    },
+
+    // A deposit or withdrawal reused the id of a transaction that already has an amount.
+    DuplicateTransaction {
+        original: Event,
+    },
+
+    // The event was sent by a different client than the one that owns the transaction.
+    ClientMismatch {
+        owner: ClientId,
+    },
+
+    // A dispute, resolve or chargeback named more than what is left of the transaction for it to
+    // apply to (or an amount that is not positive).
+    ExceedsAmount {
+        requested: Amount,
+        available: Amount,
+    },
+
+    // A refund was for a transaction that does not exist, or that is not a deposit (yet).
+    NotRefundable {
+        original_tx: TransactionId,
+    },
+
+    // A transfer was sent to the same client that sent it.
+    InvalidTransfer {
+        to: ClientId,
+    },
+
+    // The transaction is a withdrawal, and the policy does not allow withdrawals to be disputed.
+    NotDisputable,
+
+    // The event is not allowed in the state that the transaction is in. This does not happen with
+    // `DisputeMode::Tolerant`.
+    InvalidTransition {
+        from: TransactionState,
+    },
+}
+
+impl From<AmountOpError> for TransactionError {
+    fn from(err: AmountOpError) -> Self {
+        Self::Overflow(err)
+    }
+}
+
+impl Encode for TransactionState {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        let tag: u8 = match self {
+            Self::Pending => 0,
+            Self::Settled => 1,
+            Self::Disputed => 2,
+            Self::Resolved => 3,
+            Self::ChargedBack => 4,
+        };
+        tag.encode(out)
+    }
+}
+
+impl Decode for TransactionState {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        match u8::decode(input)? {
+            0 => Ok(Self::Pending),
+            1 => Ok(Self::Settled),
+            2 => Ok(Self::Disputed),
+            3 => Ok(Self::Resolved),
+            4 => Ok(Self::ChargedBack),
+            _ => Err(DecodeError::new("invalid transaction state")),
+        }
+    }
+}
+
+impl Encode for Request {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        self.amount.encode(out)?;
+        self.applied.encode(out)?;
+        Ok(())
+    }
+}
+
+impl Decode for Request {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Self {
+            amount: Decode::decode(input)?,
+            applied: Decode::decode(input)?,
+        })
+    }
+}
 
+impl Encode for Transaction {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        self.client.encode(out)?;
+        self.origin.encode(out)?;
+        self.amount.encode(out)?;
+        self.fee.encode(out)?;
+        self.state.encode(out)?;
+        self.dispute.encode(out)?;
+        self.resolve.encode(out)?;
+        self.chargeback.encode(out)?;
+        self.disputed_amount.encode(out)?;
+        self.charged_back_amount.encode(out)?;
+        self.refunded_amount.encode(out)?;
         Ok(())
     }
 }
+
+impl Decode for Transaction {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Self {
+            client: Decode::decode(input)?,
+            origin: Decode::decode(input)?,
+            amount: Decode::decode(input)?,
+            fee: Decode::decode(input)?,
+            state: Decode::decode(input)?,
+            dispute: Decode::decode(input)?,
+            resolve: Decode::decode(input)?,
+            chargeback: Decode::decode(input)?,
+            disputed_amount: Decode::decode(input)?,
+            charged_back_amount: Decode::decode(input)?,
+            refunded_amount: Decode::decode(input)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AmountValue, Currency, LockedPolicy};
+
+    const TX: u32 = 1;
+
+    fn deposit(amount: AmountValue) -> Event {
+        Event::Deposit {
+            client: 1,
+            tx: TX,
+            amount: Amount::new(amount),
+        }
+    }
+
+    const DISPUTE: Event = Event::Dispute {
+        client: 1,
+        tx: TX,
+        amount: None,
+    };
+    const RESOLVE: Event = Event::Resolve {
+        client: 1,
+        tx: TX,
+        amount: None,
+    };
+    const CHARGEBACK: Event = Event::Chargeback {
+        client: 1,
+        tx: TX,
+        amount: None,
+    };
+
+    fn policy(disputes: DisputeMode) -> Policy {
+        Policy {
+            disputes,
+            ..Policy::default()
+        }
+    }
+
+    #[test]
+    fn test_strict_lifecycle() {
+        let policy = policy(DisputeMode::Strict);
+        let mut account = Account::new();
+        let mut transaction = Transaction::new(1);
+        assert_eq!(transaction.state(), TransactionState::Pending);
+
+        transaction
+            .apply(deposit(100), &mut account, Related::default(), &policy)
+            .unwrap();
+        assert_eq!(transaction.state(), TransactionState::Settled);
+
+        transaction
+            .apply(DISPUTE, &mut account, Related::default(), &policy)
+            .unwrap();
+        assert_eq!(transaction.state(), TransactionState::Disputed);
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).held(),
+            Amount::new(100)
+        );
+
+        transaction
+            .apply(RESOLVE, &mut account, Related::default(), &policy)
+            .unwrap();
+        assert_eq!(transaction.state(), TransactionState::Resolved);
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).held(),
+            Amount::new(0)
+        );
+
+        // Nothing is disputed, so there is nothing to resolve or charge back.
+        for ev in [RESOLVE, CHARGEBACK] {
+            assert!(matches!(
+                transaction.apply(ev, &mut account, Related::default(), &policy),
+                Err(TransactionError::InvalidTransition {
+                    from: TransactionState::Resolved
+                })
+            ));
+        }
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).held(),
+            Amount::new(0)
+        );
+
+        // But it can be disputed again.
+        transaction
+            .apply(DISPUTE, &mut account, Related::default(), &policy)
+            .unwrap();
+        assert_eq!(transaction.state(), TransactionState::Disputed);
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).held(),
+            Amount::new(100)
+        );
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).total(),
+            Amount::new(100)
+        );
+    }
+
+    #[test]
+    fn test_strict_rejects_out_of_order() {
+        let policy = policy(DisputeMode::Strict);
+        let mut account = Account::new();
+        let mut transaction = Transaction::new(1);
+
+        assert!(matches!(
+            transaction.apply(DISPUTE, &mut account, Related::default(), &policy),
+            Err(TransactionError::InvalidTransition {
+                from: TransactionState::Pending
+            })
+        ));
+        assert!(!transaction.is_disputed());
+
+        transaction
+            .apply(deposit(100), &mut account, Related::default(), &policy)
+            .unwrap();
+        for ev in [RESOLVE, CHARGEBACK] {
+            assert!(matches!(
+                transaction.apply(ev, &mut account, Related::default(), &policy),
+                Err(TransactionError::InvalidTransition {
+                    from: TransactionState::Settled
+                })
+            ));
+        }
+
+        transaction
+            .apply(DISPUTE, &mut account, Related::default(), &policy)
+            .unwrap();
+        transaction
+            .apply(CHARGEBACK, &mut account, Related::default(), &policy)
+            .unwrap();
+        assert_eq!(transaction.state(), TransactionState::ChargedBack);
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).total(),
+            Amount::new(0)
+        );
+        assert!(account.is_locked());
+    }
+
+    #[test]
+    fn test_tolerant_resolve_before_dispute() {
+        let policy = policy(DisputeMode::Tolerant);
+        let mut account = Account::new();
+        let mut transaction = Transaction::new(1);
+
+        // A dispute and resolve that arrive before the deposit cancel out.
+        for ev in [RESOLVE, DISPUTE, deposit(100)] {
+            transaction
+                .apply(ev, &mut account, Related::default(), &policy)
+                .unwrap();
+        }
+        assert_eq!(transaction.state(), TransactionState::Resolved);
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).held(),
+            Amount::new(0)
+        );
+
+        // Once the amount is known, a dispute of a resolved transaction releases it, whether or not
+        // it was ever held. This is how tolerant mode has always behaved, `DisputeMode::Deferred`
+        // applies the events in order instead.
+        let mut account = Account::new();
+        let mut transaction = Transaction::new(1);
+        for ev in [deposit(100), DISPUTE, RESOLVE, DISPUTE] {
+            transaction
+                .apply(ev, &mut account, Related::default(), &policy)
+                .unwrap();
+        }
+        assert_eq!(transaction.state(), TransactionState::Resolved);
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).held(),
+            Amount::new(-100)
+        );
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).total(),
+            Amount::new(100)
+        );
+    }
+
+    #[test]
+    fn test_deferred_resolve_before_dispute() {
+        let policy = policy(DisputeMode::Deferred);
+        let mut account = Account::new();
+        let mut transaction = Transaction::new(1);
+
+        transaction
+            .apply(deposit(100), &mut account, Related::default(), &policy)
+            .unwrap();
+        transaction
+            .apply(RESOLVE, &mut account, Related::default(), &policy)
+            .unwrap();
+        assert_eq!(transaction.state(), TransactionState::Settled);
+
+        // The dispute and resolve cancel out, instead of releasing funds that were never held.
+        transaction
+            .apply(DISPUTE, &mut account, Related::default(), &policy)
+            .unwrap();
+        assert_eq!(transaction.state(), TransactionState::Resolved);
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).held(),
+            Amount::new(0)
+        );
+
+        // Disputing again holds the deposit again.
+        transaction
+            .apply(DISPUTE, &mut account, Related::default(), &policy)
+            .unwrap();
+        assert_eq!(transaction.state(), TransactionState::Disputed);
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).held(),
+            Amount::new(100)
+        );
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).total(),
+            Amount::new(100)
+        );
+    }
+
+    #[test]
+    fn test_tolerant_events_before_deposit() {
+        for disputes in [DisputeMode::Tolerant, DisputeMode::Deferred] {
+            let policy = policy(disputes);
+            let mut account = Account::new();
+            let mut transaction = Transaction::new(1);
+
+            transaction
+                .apply(CHARGEBACK, &mut account, Related::default(), &policy)
+                .unwrap();
+            transaction
+                .apply(DISPUTE, &mut account, Related::default(), &policy)
+                .unwrap();
+            assert_eq!(transaction.state(), TransactionState::Pending);
+
+            transaction
+                .apply(deposit(100), &mut account, Related::default(), &policy)
+                .unwrap();
+            assert_eq!(transaction.state(), TransactionState::ChargedBack);
+            assert_eq!(
+                account.balance(Currency::UNSPECIFIED).total(),
+                Amount::new(0)
+            );
+            assert!(account.is_locked());
+        }
+    }
+
+    #[test]
+    fn test_failed_event_changes_nothing() {
+        let policy = policy(DisputeMode::Tolerant);
+        let mut account = Account::new();
+        let mut transaction = Transaction::new(1);
+
+        transaction
+            .apply(DISPUTE, &mut account, Related::default(), &policy)
+            .unwrap();
+        account.hold(Amount::new(AmountValue::MAX)).unwrap();
+
+        // The deposit succeeds, but holding it for the early dispute overflows.
+        assert!(transaction
+            .apply(deposit(100), &mut account, Related::default(), &policy)
+            .is_err());
+        assert_eq!(transaction.state(), TransactionState::Pending);
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).total(),
+            Amount::new(0)
+        );
+
+        // A failed withdrawal in a currency that the account has never used does not leave an empty
+        // balance behind.
+        let usd = Currency::from_code("USD").unwrap();
+        let before = account.clone();
+        let withdrawal = Event::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: Amount::with_scale(1_00, 2).in_currency(usd).unwrap(),
+        };
+        assert!(Transaction::new(1)
+            .apply(withdrawal, &mut account, Related::default(), &policy)
+            .is_err());
+        assert_eq!(account, before);
+    }
+
+    #[test]
+    fn test_non_positive_amounts() {
+        let policy = policy(DisputeMode::Tolerant);
+        let mut account = Account::new();
+        Transaction::new(1)
+            .apply(deposit(100), &mut account, Related::default(), &policy)
+            .unwrap();
+        let before = account.clone();
+
+        // A negative withdrawal would credit the account without checking its funds, and a negative
+        // deposit would take funds out of it (even more than it has).
+        let withdrawal = Event::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: Amount::new(-20),
+        };
+        for event in [withdrawal, deposit(-200), deposit(0)] {
+            let mut transaction = Transaction::new(1);
+            assert!(matches!(
+                transaction.apply(event, &mut account, Related::default(), &policy),
+                Err(TransactionError::NonPositiveAmount { .. })
+            ));
+            assert_eq!(transaction.state(), TransactionState::Pending);
+            assert_eq!(account, before);
+        }
+
+        // Nor can an account be credited by withdrawing a negative amount from it directly.
+        assert!(matches!(
+            account.withdraw(Amount::new(-20)),
+            Err(TransactionError::NonPositiveAmount { .. })
+        ));
+        assert_eq!(account, before);
+    }
+
+    #[test]
+    fn test_partial_dispute() {
+        let policy = policy(DisputeMode::Strict);
+        let mut account = Account::new();
+        let mut transaction = Transaction::new(1);
+        let amount = |value| Some(Amount::new(value));
+
+        transaction
+            .apply(deposit(100), &mut account, Related::default(), &policy)
+            .unwrap();
+        assert!(matches!(
+            transaction.apply(
+                Event::Dispute {
+                    client: 1,
+                    tx: TX,
+                    amount: amount(101),
+                },
+                &mut account,
+                Related::default(),
+                &policy
+            ),
+            Err(TransactionError::ExceedsAmount { .. })
+        ));
+
+        let dispute = Event::Dispute {
+            client: 1,
+            tx: TX,
+            amount: amount(30),
+        };
+        transaction
+            .apply(dispute, &mut account, Related::default(), &policy)
+            .unwrap();
+        assert_eq!(transaction.disputed_amount(), Amount::new(30));
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).held(),
+            Amount::new(30)
+        );
+
+        // Charging back part of the dispute leaves the rest disputed.
+        let chargeback = Event::Chargeback {
+            client: 1,
+            tx: TX,
+            amount: amount(10),
+        };
+        transaction
+            .apply(chargeback, &mut account, Related::default(), &policy)
+            .unwrap();
+        assert_eq!(transaction.state(), TransactionState::Disputed);
+        assert_eq!(transaction.disputed_amount(), Amount::new(20));
+        assert_eq!(transaction.charged_back_amount(), Amount::new(10));
+        assert!(account.is_locked());
+
+        // The resolve is for what is left, and can not be for more than that.
+        let policy = Policy {
+            locked: LockedPolicy::AllowDepositsAndResolves,
+            ..policy
+        };
+        let resolve = |value| Event::Resolve {
+            client: 1,
+            tx: TX,
+            amount: amount(value),
+        };
+        assert!(matches!(
+            transaction.apply(resolve(21), &mut account, Related::default(), &policy),
+            Err(TransactionError::ExceedsAmount { .. })
+        ));
+        transaction
+            .apply(resolve(20), &mut account, Related::default(), &policy)
+            .unwrap();
+        assert_eq!(transaction.state(), TransactionState::ChargedBack);
+        assert_eq!(transaction.disputed_amount(), Amount::new(0));
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).total(),
+            Amount::new(90)
+        );
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).held(),
+            Amount::new(0)
+        );
+    }
+
+    #[test]
+    fn test_repeated_partial_disputes() {
+        let policy = policy(DisputeMode::Strict);
+        let mut account = Account::new();
+        let mut transaction = Transaction::new(1);
+        let dispute = |value: Option<AmountValue>| Event::Dispute {
+            client: 1,
+            tx: TX,
+            amount: value.map(Amount::new),
+        };
+
+        transaction
+            .apply(deposit(100), &mut account, Related::default(), &policy)
+            .unwrap();
+        for value in [30, 70] {
+            transaction
+                .apply(
+                    dispute(Some(value)),
+                    &mut account,
+                    Related::default(),
+                    &policy,
+                )
+                .unwrap();
+        }
+        assert_eq!(transaction.disputed_amount(), Amount::new(100));
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).held(),
+            Amount::new(100)
+        );
+
+        // Everything is disputed, so another dispute is for more than is left.
+        assert!(matches!(
+            transaction.apply(dispute(Some(1)), &mut account, Related::default(), &policy),
+            Err(TransactionError::ExceedsAmount { .. })
+        ));
+        assert!(matches!(
+            transaction.apply(dispute(None), &mut account, Related::default(), &policy),
+            Err(TransactionError::InvalidTransition {
+                from: TransactionState::Disputed
+            })
+        ));
+
+        // What is resolved can be disputed again.
+        let resolve = Event::Resolve {
+            client: 1,
+            tx: TX,
+            amount: Some(Amount::new(50)),
+        };
+        transaction
+            .apply(resolve, &mut account, Related::default(), &policy)
+            .unwrap();
+        transaction
+            .apply(dispute(None), &mut account, Related::default(), &policy)
+            .unwrap();
+        assert_eq!(transaction.disputed_amount(), Amount::new(100));
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).held(),
+            Amount::new(100)
+        );
+    }
+
+    #[test]
+    fn test_tolerant_partial_dispute_too_large() {
+        let policy = policy(DisputeMode::Tolerant);
+        let mut account = Account::new();
+        let mut transaction = Transaction::new(1);
+
+        // The dispute turns out to be for more than the deposit, so it is left unapplied, rather
+        // than rejecting the deposit.
+        let dispute = Event::Dispute {
+            client: 1,
+            tx: TX,
+            amount: Some(Amount::new(200)),
+        };
+        transaction
+            .apply(dispute, &mut account, Related::default(), &policy)
+            .unwrap();
+        transaction
+            .apply(deposit(100), &mut account, Related::default(), &policy)
+            .unwrap();
+        assert_eq!(transaction.state(), TransactionState::Settled);
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).held(),
+            Amount::new(0)
+        );
+    }
+}
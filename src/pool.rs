@@ -0,0 +1,572 @@
+use crate::{
+    Account, ClientId, Event, EventSink, FeeSchedule, LoggedEvent, MemoryStore, Policy, Shard,
+    ShardError, TransactionId,
+};
+use std::{
+    collections::HashMap,
+    panic,
+    sync::mpsc::{self, Receiver, Sender, SyncSender},
+    thread::{self, JoinHandle},
+};
+
+// Jobs are sent to a worker in batches of up to `BATCH_LEN`, as sending each one by itself costs
+// more than most events take to apply. How many batches can be waiting for a worker before pushing
+// more events blocks.
+const BATCH_LEN: usize = 256;
+const QUEUE_LEN: usize = 16;
+
+// Processes events on several threads at once, each of which owns a `Shard`. The result is the same
+// as pushing the events to a single shard, in the same order.
+//
+// Every client belongs to one of the workers (its partition), which keeps the client's account. Each
+// transaction is kept by the worker that the event creating it was sent to, and every later event
+// for the transaction is sent to the same worker, so that events keep their order for a client and
+// for a transaction. An event usually only touches accounts of its own worker, but the recipient of
+// a transfer, the house account that fees are credited to, or the client itself (when it names
+// another client's transaction) may belong to another worker. That worker then lends the account for
+// the event, waiting until it is given back before it moves on to its next event.
+//
+// Lending keeps the result correct, but the two workers wait for each other while it happens. When
+// fees are charged, every deposit and withdrawal borrows the house account, so the workers mostly
+// take turns.
+//
+// The pool remembers which worker keeps each transaction (and the recipient of each transfer that
+// crosses workers), as an event for a transaction can come from any client. That takes some memory
+// for every transaction, on top of the shards themselves.
+#[derive(Debug)]
+pub struct ShardPool {
+    workers: Vec<(SyncSender<Vec<Job>>, JoinHandle<Finished>)>,
+    // The jobs of each worker that have not been sent yet.
+    batches: Vec<Vec<Job>>,
+    policy: Policy,
+    // The worker that keeps each transaction.
+    homes: HashMap<TransactionId, u16>,
+    // The recipients of each transfer whose recipient belongs to another worker than the transfer.
+    // There can be more than one, when a transfer is repeated with the same transaction id (only the
+    // first one that succeeds is kept by the shard, but that is not known until it is applied).
+    recipients: HashMap<TransactionId, Vec<ClientId>>,
+    // Errors pushed to the pool itself, rather than found by a worker.
+    errors: Vec<(u64, ShardError)>,
+    // The position of the next event (or error) in the order they were pushed.
+    seq: u64,
+}
+
+#[derive(Debug)]
+enum Job {
+    Event {
+        seq: u64,
+        event: Event,
+        loans: Vec<Loan>,
+    },
+    Lend {
+        seq: u64,
+        clients: Vec<ClientId>,
+        accounts: Sender<Vec<Option<Account>>>,
+        returned: Receiver<Vec<Option<Account>>>,
+    },
+}
+
+// The accounts that are borrowed from another worker for an event, all at once, as the worker cannot
+// move on to a second loan for the same event while it waits for the first one to be given back.
+// None stands for an account that does not exist yet.
+#[derive(Debug)]
+struct Loan {
+    clients: Vec<ClientId>,
+    accounts: Receiver<Vec<Option<Account>>>,
+    returned: Sender<Vec<Option<Account>>>,
+}
+
+// What a worker hands back once there are no more events, along with the position of each of the
+// shard's events and errors in the order that they were pushed to the pool.
+#[derive(Debug)]
+struct Finished {
+    shard: Shard,
+    event_seqs: Vec<u64>,
+    error_seqs: Vec<u64>,
+}
+
+impl ShardPool {
+    // Starts a pool with the given number of worker threads (at least one, and at most one for each
+    // client id), whose shards all follow the given policy.
+    pub fn new(threads: usize, policy: Policy) -> Self {
+        let threads = threads.clamp(1, usize::from(ClientId::MAX) + 1);
+        let workers = (0..threads)
+            .map(|_| {
+                let (sender, jobs) = mpsc::sync_channel(QUEUE_LEN);
+                let shard = Shard::with_policy(policy.clone());
+                (sender, thread::spawn(move || work(shard, jobs)))
+            })
+            .collect();
+        Self {
+            workers,
+            batches: (0..threads).map(|_| Vec::new()).collect(),
+            policy,
+            homes: HashMap::new(),
+            recipients: HashMap::new(),
+            errors: Vec::new(),
+            seq: 0,
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    // @returns the worker that the client belongs to
+    fn partition(&self, client: ClientId) -> u16 {
+        (usize::from(client) % self.workers.len()) as u16
+    }
+
+    pub fn push_event(&mut self, event: Event) {
+        let seq = self.next_seq();
+        let client = event.client();
+
+        // The worker that keeps the transaction handles the event. A new transaction is kept by the
+        // client's worker, except for a refund, which needs the transaction that it refunds.
+        let home = match event.transaction() {
+            Some(tx) => {
+                let new_home = match event {
+                    Event::Refund { original_tx, .. } => self.homes.get(&original_tx).copied(),
+                    _ => None,
+                };
+                let new_home = new_home.unwrap_or_else(|| self.partition(client));
+                *self.homes.entry(tx).or_insert(new_home)
+            }
+            None => self.partition(client),
+        };
+
+        if let Event::Transfer { to, tx, .. } = event {
+            if self.partition(to) != home {
+                let recipients = self.recipients.entry(tx).or_default();
+                if !recipients.contains(&to) {
+                    recipients.push(to);
+                }
+            }
+        }
+
+        // Any account that the event touches, but belongs to another worker, is lent by that worker
+        // for the event. Most events only touch accounts of their own worker, so nothing is
+        // allocated for them.
+        let recipients: &[ClientId] = match event {
+            Event::Transfer { ref to, .. } => std::slice::from_ref(to),
+            Event::Dispute { tx, .. }
+            | Event::Resolve { tx, .. }
+            | Event::Chargeback { tx, .. } => self.recipients.get(&tx).map_or(&[], Vec::as_slice),
+            _ => &[],
+        };
+        let house = Some(self.policy.house).filter(|_| {
+            self.policy.fees != FeeSchedule::Free
+                && matches!(event, Event::Deposit { .. } | Event::Withdrawal { .. })
+        });
+        // The accounts are grouped by the worker that lends them.
+        let mut borrowed: Vec<(u16, Vec<ClientId>)> = Vec::new();
+        for other in std::iter::once(client)
+            .chain(recipients.iter().copied())
+            .chain(house)
+        {
+            let lender = self.partition(other);
+            if lender == home {
+                continue;
+            }
+            match borrowed.iter_mut().find(|(worker, _)| *worker == lender) {
+                Some((_, clients)) if clients.contains(&other) => {}
+                Some((_, clients)) => clients.push(other),
+                None => borrowed.push((lender, vec![other])),
+            }
+        }
+        let mut loans = Vec::with_capacity(borrowed.len());
+        for (lender, clients) in &borrowed {
+            let (lend, accounts) = mpsc::channel();
+            let (returned, give_back) = mpsc::channel();
+            self.send(
+                *lender,
+                Job::Lend {
+                    seq,
+                    clients: clients.clone(),
+                    accounts: lend,
+                    returned: give_back,
+                },
+            );
+            loans.push(Loan {
+                clients: clients.clone(),
+                accounts,
+                returned,
+            });
+        }
+        self.send(home, Job::Event { seq, event, loans });
+
+        // The workers wait for each other during a loan, so none of them can be left waiting for
+        // a batch that has not been sent.
+        if !borrowed.is_empty() {
+            for (lender, _) in borrowed {
+                self.flush(lender);
+            }
+            self.flush(home);
+        }
+    }
+
+    pub fn push_error(&mut self, err: ShardError) {
+        let seq = self.next_seq();
+        self.errors.push((seq, err));
+    }
+
+    fn next_seq(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq - 1
+    }
+
+    fn send(&mut self, worker: u16, job: Job) {
+        let batch = &mut self.batches[usize::from(worker)];
+        batch.push(job);
+        if batch.len() >= BATCH_LEN {
+            self.flush(worker);
+        }
+    }
+
+    fn flush(&mut self, worker: u16) {
+        let batch = std::mem::take(&mut self.batches[usize::from(worker)]);
+        if !batch.is_empty() {
+            // A worker only stops once the pool is finished with it, unless it has panicked, which
+            // is reported by `ShardPool::finish`.
+            let _ = self.workers[usize::from(worker)].0.send(batch);
+        }
+    }
+
+    // Waits for the workers to apply every event, and merges their shards into a single shard. Its
+    // event log and errors are in the order that the events were pushed to the pool.
+    pub fn finish(mut self) -> Shard {
+        for worker in 0..self.workers.len() {
+            self.flush(worker as u16);
+        }
+        let mut store = MemoryStore::new();
+        let mut log = Vec::new();
+        let mut errors = self.errors;
+
+        let handles: Vec<_> = self
+            .workers
+            .into_iter()
+            .map(|(sender, handle)| {
+                drop(sender);
+                handle
+            })
+            .collect();
+        for handle in handles {
+            let finished = handle
+                .join()
+                .unwrap_or_else(|payload| panic::resume_unwind(payload));
+            let (shard_store, shard_log, shard_errors) = finished.shard.into_parts();
+            store.extend(shard_store);
+            log.extend(finished.event_seqs.into_iter().zip(shard_log));
+            errors.extend(finished.error_seqs.into_iter().zip(shard_errors));
+        }
+
+        log.sort_by_key(|(seq, _)| *seq);
+        errors.sort_by_key(|(seq, _)| *seq);
+        let log = log
+            .into_iter()
+            .enumerate()
+            .map(|(seq, (_, logged))| LoggedEvent {
+                seq: seq as u64,
+                event: logged.event,
+            })
+            .collect();
+        let errors = errors.into_iter().map(|(_, err)| err).collect();
+        Shard::from_parts(store, self.policy, log, errors)
+    }
+}
+
+impl EventSink for ShardPool {
+    fn push_event(&mut self, event: Event) {
+        ShardPool::push_event(self, event)
+    }
+
+    fn push_error(&mut self, err: ShardError) {
+        ShardPool::push_error(self, err)
+    }
+}
+
+// Runs a worker, until the pool stops sending it jobs.
+fn work(mut shard: Shard, jobs: Receiver<Vec<Job>>) -> Finished {
+    let mut event_seqs = Vec::new();
+    let mut error_seqs = Vec::new();
+    for job in jobs.into_iter().flatten() {
+        let (events, errors) = (shard.events().len(), shard.errors().len());
+        let seq = match job {
+            Job::Event { seq, event, loans } => {
+                let mut borrowed = HashMap::new();
+                for loan in &loans {
+                    let mut accounts = loan.accounts.recv().unwrap_or_default().into_iter();
+                    for &client in &loan.clients {
+                        borrowed.insert(client, accounts.next().flatten());
+                    }
+                }
+                shard.push_event_borrowing(event, &mut borrowed);
+                for loan in loans {
+                    let accounts = loan
+                        .clients
+                        .iter()
+                        .map(|client| borrowed.remove(client).flatten())
+                        .collect();
+                    let _ = loan.returned.send(accounts);
+                }
+                seq
+            }
+            Job::Lend {
+                seq,
+                clients,
+                accounts,
+                returned,
+            } => {
+                let _ = accounts.send(
+                    clients
+                        .iter()
+                        .map(|client| shard.get_account(*client))
+                        .collect(),
+                );
+                for (client, account) in
+                    clients.into_iter().zip(returned.recv().unwrap_or_default())
+                {
+                    if let Some(account) = account {
+                        shard.put_account(client, account);
+                    }
+                }
+                seq
+            }
+        };
+        event_seqs.extend((events..shard.events().len()).map(|_| seq));
+        error_seqs.extend((errors..shard.errors().len()).map(|_| seq));
+    }
+    Finished {
+        shard,
+        event_seqs,
+        error_seqs,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Amount, AmountValue, DisputeMode};
+
+    fn deposit(client: ClientId, tx: TransactionId, amount: i64) -> Event {
+        Event::Deposit {
+            client,
+            tx,
+            amount: Amount::new(AmountValue::from(amount)),
+        }
+    }
+
+    fn transfer(from: ClientId, to: ClientId, tx: TransactionId, amount: i64) -> Event {
+        Event::Transfer {
+            from,
+            to,
+            tx,
+            amount: Amount::new(AmountValue::from(amount)),
+        }
+    }
+
+    // Pushes the events to a single shard and to pools of different sizes, which must all agree.
+    fn check(policy: Policy, events: &[Event]) -> Shard {
+        let shard = Shard::replay_with_policy(policy.clone(), events.iter().copied());
+        for threads in [1, 2, 3, 8] {
+            let mut pool = ShardPool::new(threads, policy.clone());
+            events.iter().for_each(|event| pool.push_event(*event));
+            let merged = pool.finish();
+            assert_eq!(
+                merged.generate_output_sorted(),
+                shard.generate_output_sorted()
+            );
+            assert_eq!(merged.events(), shard.events());
+            assert_eq!(
+                merged
+                    .errors()
+                    .iter()
+                    .map(|err| err.to_string())
+                    .collect::<Vec<_>>(),
+                shard
+                    .errors()
+                    .iter()
+                    .map(|err| err.to_string())
+                    .collect::<Vec<_>>()
+            );
+            for tx in 1..=10 {
+                assert_eq!(merged.get_transaction(tx), shard.get_transaction(tx));
+            }
+        }
+        shard
+    }
+
+    #[test]
+    fn test_pool_matches_single_shard() {
+        let shard = check(
+            Policy {
+                disputes: DisputeMode::Strict,
+                ..Policy::default()
+            },
+            &[
+                deposit(1, 1, 10_0000),
+                deposit(2, 2, 5_0000),
+                // Client 2 can only afford this with the funds that client 1 sends it.
+                transfer(1, 2, 3, 6_0000),
+                Event::Withdrawal {
+                    client: 2,
+                    tx: 4,
+                    amount: Amount::new(11_0000),
+                },
+                Event::Dispute {
+                    client: 1,
+                    tx: 3,
+                    amount: None,
+                },
+                Event::Chargeback {
+                    client: 1,
+                    tx: 3,
+                    amount: None,
+                },
+                // Transactions are shared by all clients, wherever they are kept.
+                deposit(3, 1, 1_0000),
+                Event::Dispute {
+                    client: 3,
+                    tx: 2,
+                    amount: None,
+                },
+                Event::Refund {
+                    client: 4,
+                    tx: 5,
+                    original_tx: 1,
+                    amount: Amount::new(1_0000),
+                },
+                Event::Unlock { client: 1 },
+                transfer(1, 5, 6, 1_0000),
+                // A failed event still leaves its transaction behind, which a client of another
+                // worker sees.
+                Event::Withdrawal {
+                    client: 4,
+                    tx: 7,
+                    amount: Amount::new(100_0000),
+                },
+                deposit(3, 7, 1_0000),
+            ],
+        );
+        assert_eq!(
+            shard
+                .errors()
+                .iter()
+                .map(ShardError::kind)
+                .collect::<Vec<_>>(),
+            [
+                "DuplicateTransaction",
+                "DisputeClientMismatch",
+                "RefundClientMismatch",
+                "InsufficientFunds"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pool_lends_several_accounts_of_one_worker() {
+        // The transfer is sent to the worker of client 1, which keeps transaction 1, and borrows
+        // both of the other clients from the same worker.
+        let shard = check(
+            Policy::default(),
+            &[
+                deposit(1, 1, 10_0000),
+                deposit(2, 2, 10_0000),
+                transfer(2, 4, 1, 5_0000),
+                transfer(2, 4, 3, 5_0000),
+            ],
+        );
+        assert_eq!(shard.errors()[0].kind(), "DuplicateTransaction");
+    }
+
+    #[test]
+    fn test_pool_fees() {
+        let mut events: Vec<_> = (1..=10)
+            .map(|tx| deposit(tx as ClientId % 4, tx, 10_0000))
+            .collect();
+        events.push(Event::Withdrawal {
+            client: 99,
+            tx: 11,
+            amount: Amount::new(1_0000),
+        });
+        check(
+            Policy {
+                fees: FeeSchedule::BasisPoints(100),
+                house: 99,
+                ..Policy::default()
+            },
+            &events,
+        );
+    }
+
+    // Runs a large generated input on a single shard and on a pool, which must agree. The time that
+    // each one takes is printed (run with `--release -- --nocapture` to compare them).
+    #[test]
+    fn test_pool_large_input() {
+        // Mostly deposits and withdrawals, with a dispute of an earlier deposit for every tenth
+        // event, and a transfer to another client for every hundredth one.
+        let events: Vec<Event> = (1..=200_000)
+            .map(|tx: TransactionId| {
+                let client = (tx % 1000) as ClientId;
+                match tx % 100 {
+                    7 => transfer(client, client + 1, tx, 1_0000),
+                    _ if tx.is_multiple_of(10) => Event::Dispute {
+                        client: ((tx - 5) % 1000) as ClientId,
+                        tx: tx - 5,
+                        amount: None,
+                    },
+                    _ if tx % 10 == 8 => Event::Withdrawal {
+                        client,
+                        tx,
+                        amount: Amount::new(2_0000),
+                    },
+                    _ => deposit(client, tx, 1_0000),
+                }
+            })
+            .collect();
+
+        let start = std::time::Instant::now();
+        let shard = Shard::replay_with_policy(Policy::default(), events.iter().copied());
+        let single = start.elapsed();
+        let start = std::time::Instant::now();
+        let mut pool = ShardPool::new(4, Policy::default());
+        events.iter().for_each(|event| pool.push_event(*event));
+        let merged = pool.finish();
+        let pooled = start.elapsed();
+        eprintln!("single shard: {single:?}, pool of 4 threads: {pooled:?}");
+
+        assert_eq!(
+            merged.generate_output_sorted(),
+            shard.generate_output_sorted()
+        );
+        assert_eq!(merged.events(), shard.events());
+        assert_eq!(merged.errors().len(), shard.errors().len());
+    }
+
+    #[test]
+    fn test_pool_errors_keep_their_order() {
+        let mut pool = ShardPool::new(2, Policy::default());
+        pool.push_event(Event::Withdrawal {
+            client: 1,
+            tx: 1,
+            amount: Amount::new(1),
+        });
+        pool.push_error(ShardError::StorageError(std::sync::Arc::new(
+            std::io::Error::other("oops"),
+        )));
+        pool.push_event(Event::Withdrawal {
+            client: 2,
+            tx: 2,
+            amount: Amount::new(1),
+        });
+        let shard = pool.finish();
+        assert_eq!(
+            shard
+                .errors()
+                .iter()
+                .map(ShardError::kind)
+                .collect::<Vec<_>>(),
+            ["InsufficientFunds", "StorageError", "InsufficientFunds"]
+        );
+    }
+}
@@ -1,17 +1,28 @@
-use crate::{Account, AmountOpError, ClientId, Event, Summary, Transaction, TransactionId};
+use crate::{
+    take, Account, Amount, AmountOpError, Changes, Checksum, ClientId, Decode, DecodeError, Encode,
+    EncodeError, Event, FeeSchedule, InMemory, MemoryStore, Policy, Related, RescaleError, Store,
+    Summary, Transaction, TransactionError, TransactionId, TransactionState,
+};
 use std::{
-    collections::{BinaryHeap, HashMap},
+    collections::HashMap,
     fmt::{Display, Formatter, Result as FmtResult},
-    rc::Rc,
+    io::{self, Read, Write},
+    mem::{self, Discriminant},
+    sync::Arc,
 };
 
-// In the future, ideally it would be nice to be able to have multiple shards, and to be able to
-// merge or reconcile them. But that seems out of scope of this project.
+// Multiple shards can process events independently of each other, and then be brought back in sync
+// using `Shard::reconcile`.
+//
+// The accounts and transactions are kept in a `Store`, which is in memory unless the shard is
+// created with another one (see `Shard::with_store`).
 #[derive(Default, Debug, Clone)]
-pub struct Shard {
-    accounts: HashMap<ClientId, Account>,
-    transactions: HashMap<TransactionId, Transaction>,
+pub struct Shard<S = MemoryStore> {
+    store: S,
+    // An append-only log of every event that was successfully applied to this shard, in order.
+    log: Vec<LoggedEvent>,
     errors: Vec<ShardError>,
+    policy: Policy,
 }
 
 impl Shard {
@@ -19,14 +30,66 @@ impl Shard {
         Self::default()
     }
 
+    pub fn with_policy(policy: Policy) -> Self {
+        Self::with_store(MemoryStore::new(), policy)
+    }
+
+    // Rebuilds a shard by applying the given events in order. Replaying the log of another shard
+    // (see `Shard::events`) produces a shard with identical accounts and transactions, as long as
+    // both shards follow the same policy.
+    pub fn replay(events: impl IntoIterator<Item = Event>) -> Self {
+        Self::replay_with_policy(Policy::default(), events)
+    }
+
+    pub fn replay_with_policy(policy: Policy, events: impl IntoIterator<Item = Event>) -> Self {
+        let mut shard = Self::with_policy(policy);
+        events.into_iter().for_each(|event| shard.push_event(event));
+        shard
+    }
+
+    // Rebuilds a shard from a snapshot written by `Shard::snapshot`.
+    pub fn restore(reader: impl Read) -> io::Result<Self> {
+        Self::restore_with_store(reader, MemoryStore::new())
+    }
+}
+
+impl<S: Store> Shard<S> {
+    // Creates a shard that keeps its accounts and transactions in the given store, starting from
+    // whatever the store already holds. The event log and errors always start out empty.
+    pub fn with_store(store: S, policy: Policy) -> Self {
+        Self {
+            store,
+            log: Vec::new(),
+            errors: Vec::new(),
+            policy,
+        }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
     // @returns a snapshot of the account, in its current state, if it exists
     pub fn get_account(&self, client: ClientId) -> Option<Account> {
-        self.accounts.get(&client).copied()
+        self.store.account(client).cloned()
     }
 
     // @returns a snapshot of the transaction, in its current state, if it exists
+    //
+    // A transaction that cannot be loaded from the store is reported as not existing.
     pub fn get_transaction(&self, tx: TransactionId) -> Option<Transaction> {
-        self.transactions.get(&tx).copied()
+        self.store.transaction(tx).ok().flatten()
+    }
+
+    // @returns every event that has been applied to this shard, in the order they were applied
+    //
+    // Events that failed (and were recorded as an error instead) are not included.
+    pub fn events(&self) -> &[LoggedEvent] {
+        &self.log
+    }
+
+    pub fn policy(&self) -> &Policy {
+        &self.policy
     }
 
     pub fn errors(&self) -> &[ShardError] {
@@ -34,17 +97,227 @@ impl Shard {
     }
 
     pub fn push_event(&mut self, event: Event) {
+        self.push_event_borrowing(event, &mut HashMap::new())
+    }
+
+    // Pushes an event that may touch accounts which are kept by another shard, and have been lent to
+    // this one for the event (see `ShardPool`). The borrowed accounts (None for an account that does
+    // not exist yet) are used instead of the ones in the store, and are updated instead of the store.
+    pub(crate) fn push_event_borrowing(
+        &mut self,
+        event: Event,
+        borrowed: &mut HashMap<ClientId, Option<Account>>,
+    ) {
+        match self.apply_event(event, borrowed) {
+            Ok(()) => self.log.push(LoggedEvent {
+                seq: self.log.len() as u64,
+                event,
+            }),
+            Err(err) => self.push_error(err),
+        }
+    }
+
+    fn apply_event(
+        &mut self,
+        event: Event,
+        borrowed: &mut HashMap<ClientId, Option<Account>>,
+    ) -> Result<(), ShardError> {
         let client_id = event.client();
         let tx_id = event.transaction();
+        let stored = match tx_id {
+            Some(tx) => self.store.transaction(tx)?,
+            None => None,
+        };
 
-        let account = self.accounts.entry(client_id).or_default();
-        let transaction = self.transactions.entry(tx_id).or_default();
+        // Any other account that the event touches is loaded alongside the client's account, so
+        // that they can be updated together: the recipient of a transfer (including when the
+        // transfer is disputed), and the house account that fees are credited to.
+        let recipient_id = match event {
+            Event::Transfer { to, .. } => Some(to),
+            Event::Dispute { .. } | Event::Resolve { .. } | Event::Chargeback { .. } => {
+                match stored.as_ref().and_then(Transaction::origin) {
+                    Some(Event::Transfer { to, .. }) => Some(to),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+        .filter(|to| *to != client_id);
+        let house_id = Some(self.policy.house).filter(|house| {
+            *house != client_id
+                && self.policy.fees != FeeSchedule::Free
+                && matches!(event, Event::Deposit { .. } | Event::Withdrawal { .. })
+        });
 
-        if let Err(err) = transaction.apply(event, account) {
-            self.push_error(ShardError::TransactionOprror {
-                tx: tx_id,
-                reason: err,
-            });
+        // The transaction that a refund is for is loaded in the same way.
+        let refunded_id = match event {
+            Event::Refund {
+                tx, original_tx, ..
+            } if tx != original_tx => Some(original_tx),
+            _ => None,
+        };
+
+        // Dispute related events that arrived before the transaction itself, but from another
+        // client than the one that it turns out to belong to, are dropped when it arrives. They
+        // were accepted at the time, so each of them is reported now.
+        let creates = matches!(
+            event,
+            Event::Deposit { .. }
+                | Event::Withdrawal { .. }
+                | Event::Transfer { .. }
+                | Event::Refund { .. }
+        );
+        let dropped = stored.filter(|transaction| {
+            creates && transaction.origin().is_none() && transaction.client() != client_id
+        });
+
+        // A store that keeps everything in memory is changed in place, unless some of the accounts
+        // have been lent to this shard.
+        let others = Others {
+            recipient: recipient_id,
+            house: house_id,
+            refunded: refunded_id,
+        };
+        let in_place = if borrowed.is_empty() {
+            self.store.in_memory()
+        } else {
+            None
+        };
+        let result = match in_place {
+            Some(store) => apply_in_place(store, &self.policy, event, others),
+            None => self.apply_and_commit(event, stored, others, borrowed),
+        };
+
+        if let (Some(dropped), Some(tx_id), true) = (dropped, tx_id, result.is_ok()) {
+            for request in dropped.requests(tx_id) {
+                let owner = TransactionError::ClientMismatch { owner: client_id };
+                self.push_error(ShardError::from_transaction_error(request, tx_id, owner));
+            }
+        }
+
+        result
+    }
+
+    // Applies the event to copies of the accounts and transactions that it touches, and commits
+    // the ones that have changed to the store together.
+    fn apply_and_commit(
+        &mut self,
+        event: Event,
+        stored: Option<Transaction>,
+        others: Others,
+        borrowed: &mut HashMap<ClientId, Option<Account>>,
+    ) -> Result<(), ShardError> {
+        let client_id = event.client();
+        let tx_id = event.transaction();
+        let mut recipient = others
+            .recipient
+            .map(|id| (id, self.load_account(id, borrowed)));
+        let mut house = others.house.map(|id| (id, self.load_account(id, borrowed)));
+        let (mut account, account_existed) = self.load_account(client_id, borrowed);
+        let mut refunded = match others.refunded {
+            Some(id) => self
+                .store
+                .transaction(id)?
+                .map(|transaction| (id, transaction)),
+            None => None,
+        };
+
+        let transaction_existed = stored.is_some();
+        let mut transaction = stored.unwrap_or_else(|| Transaction::new(client_id));
+        let result = match tx_id {
+            Some(tx_id) => transaction
+                .apply(
+                    event,
+                    &mut account,
+                    Related {
+                        recipient: recipient.as_mut().map(|(_, (account, _))| account),
+                        house: house.as_mut().map(|(_, (account, _))| account),
+                        refunded: refunded.as_mut().map(|(_, transaction)| transaction),
+                    },
+                    &self.policy,
+                )
+                .map_err(|err| ShardError::from_transaction_error(event, tx_id, err)),
+            None => {
+                apply_to_account(event, &mut account);
+                Ok(())
+            }
+        };
+
+        // A failed event leaves everything as it was, except that the client's account and the
+        // transaction are still created if they did not exist yet. Other accounts that did not exist
+        // before are only saved if something has happened to them, so that no empty account is left
+        // behind.
+        let applied = result.is_ok();
+        let mut changes = Changes::default();
+        let mut accounts = Vec::new();
+        if applied || !account_existed {
+            accounts.push((client_id, account));
+        }
+        for (id, (other, existed)) in recipient.into_iter().chain(house) {
+            if applied && (existed || other != Account::new()) {
+                accounts.push((id, other));
+            }
+        }
+        let (returned, saved) = accounts
+            .into_iter()
+            .partition(|(id, _)| borrowed.contains_key(id));
+        changes.accounts = saved;
+        if let Some(tx_id) = tx_id.filter(|_| applied || !transaction_existed) {
+            changes.transactions.push((tx_id, transaction));
+        }
+        changes.transactions.extend(refunded.filter(|_| applied));
+        self.store.commit(changes)?;
+        for (id, other) in returned {
+            borrowed.insert(id, Some(other));
+        }
+
+        result
+    }
+
+    // @returns a copy of the account of the client (or a new account if it does not exist yet),
+    // along with whether it existed
+    fn load_account(
+        &self,
+        client: ClientId,
+        borrowed: &HashMap<ClientId, Option<Account>>,
+    ) -> (Account, bool) {
+        match borrowed.get(&client) {
+            Some(Some(account)) => (account.clone(), true),
+            Some(None) => (Account::new(), false),
+            None => match self.store.account(client) {
+                Some(account) => (account.clone(), true),
+                None => (Account::new(), false),
+            },
+        }
+    }
+
+    pub(crate) fn into_parts(self) -> (S, Vec<LoggedEvent>, Vec<ShardError>) {
+        (self.store, self.log, self.errors)
+    }
+
+    pub(crate) fn from_parts(
+        store: S,
+        policy: Policy,
+        log: Vec<LoggedEvent>,
+        errors: Vec<ShardError>,
+    ) -> Self {
+        Self {
+            store,
+            log,
+            errors,
+            policy,
+        }
+    }
+
+    // Saves an account that was lent to another shard (see `Shard::push_event_borrowing`) and has
+    // been given back.
+    pub(crate) fn put_account(&mut self, client: ClientId, account: Account) {
+        let changes = Changes {
+            accounts: vec![(client, account)],
+            transactions: Vec::new(),
+        };
+        if let Err(err) = self.store.commit(changes) {
+            self.push_error(err.into());
         }
     }
 
@@ -52,68 +325,2284 @@ impl Shard {
         self.errors.push(err);
     }
 
+    // @returns a summary of every account, sorted by client id (and then currency)
+    //
+    // Every store keeps its accounts sorted by client id, so nothing has to be sorted here. The
+    // summaries are produced lazily, so that they can be written out as they are generated, without
+    // holding all of them in memory.
+    pub fn summaries(&self) -> impl Iterator<Item = Summary> + '_ {
+        self.store
+            .accounts()
+            .flat_map(|(client, account)| Summary::for_account(client, account))
+    }
+
     pub fn generate_output(&self) -> Vec<Summary> {
-        self.accounts
-            .iter()
-            .map(|(client, account)| Summary {
-                client: *client,
-                available: account.available(),
-                held: account.held(),
-                total: account.total(),
-                locked: account.is_locked(),
-            })
-            .collect()
+        self.summaries().collect()
     }
 
+    // The same as `generate_output`, as the summaries are always sorted.
     pub fn generate_output_sorted(&self) -> Vec<Summary> {
-        self.accounts
-            .iter()
-            .map(|(client, account)| Summary {
-                client: *client,
-                available: account.available(),
-                held: account.held(),
-                total: account.total(),
-                locked: account.is_locked(),
-            })
-            .collect::<BinaryHeap<Summary>>()
-            .into_sorted_vec()
+        self.generate_output()
+    }
+
+    // Merges everything that `other` knows into this shard, leaving `other` untouched.
+    //
+    // The events in the log of `other` are applied to this shard in the order that `other` applied
+    // them, skipping any whose fact about a transaction (its amount, or whether it has been
+    // disputed, resolved, or charged back) this shard has already seen. A transaction can be
+    // disputed (and so resolved and charged back) more than once, so the events of each kind are
+    // counted, and only those beyond what this shard has applied to the transaction are applied.
+    // This way each fact is only applied once, and reconciling the same pair of shards repeatedly
+    // is harmless.
+    //
+    // Events that are not tied to a transaction (eg: `Event::Unlock`) are applied in the same order,
+    // so that the other shard's later events are not rejected by a lock that it has lifted. An unlock
+    // is skipped if the account is not locked in this shard, as it would have no effect. Unlocks can
+    // not be told apart, so one is applied again if this shard has locked the account since the
+    // last time the shards were reconciled.
+    //
+    // Errors are not merged, they stay with the shard that encountered them.
+    pub fn reconcile<T: Store>(&mut self, other: &Shard<T>) {
+        let missing = Changes {
+            accounts: other
+                .store
+                .accounts()
+                .filter(|(client, _)| self.store.account(*client).is_none())
+                .map(|(client, _)| (client, Account::new()))
+                .collect(),
+            transactions: Vec::new(),
+        };
+        if let Err(err) = self.store.commit(missing) {
+            self.push_error(err.into());
+        }
+
+        let mut ours = event_counts(&self.log);
+        let mut theirs = HashMap::new();
+        for LoggedEvent { event, .. } in other.log.iter() {
+            let Some(tx_id) = event.transaction() else {
+                let locked = self
+                    .store
+                    .account(event.client())
+                    .is_some_and(Account::is_locked);
+                if locked {
+                    self.push_event(*event);
+                }
+                continue;
+            };
+
+            let seen = match self.store.transaction(tx_id) {
+                Ok(transaction) => {
+                    transaction.is_some_and(|transaction| transaction.has_seen(event))
+                }
+                Err(err) => {
+                    self.push_error(err.into());
+                    continue;
+                }
+            };
+
+            let key = (tx_id, mem::discriminant(event));
+            let count = theirs.entry(key).or_insert(0);
+            *count += 1;
+            let applied = ours.get(&key).copied().unwrap_or(0).max(usize::from(seen));
+            if *count > applied {
+                let len = self.log.len();
+                self.push_event(*event);
+                if self.log.len() > len {
+                    *ours.entry(key).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+
+    // Writes everything that the shard holds to the writer: its policy, accounts, transactions,
+    // event log and errors. `Shard::restore` reads it back.
+    //
+    // The snapshot starts with `SNAPSHOT_MAGIC` and the version of the format, and ends with a
+    // checksum of everything before it. In between, each part is written with the encoding of
+    // `Encode`, and the accounts, transactions, log and errors are each a list of entries, with every
+    // entry preceded by `true` and the list ended by `false`. This way the transactions are written
+    // one at a time, as they are read from the store.
+    pub fn snapshot(&self, writer: impl Write) -> io::Result<()> {
+        let mut writer = SnapshotWriter {
+            writer,
+            checksum: Checksum::new(),
+            buf: Vec::new(),
+        };
+        writer.write_raw(SNAPSHOT_MAGIC)?;
+        writer.write(&SNAPSHOT_VERSION)?;
+        writer.write(&self.policy)?;
+
+        for (client, account) in self.store.accounts() {
+            writer.write(&true)?;
+            writer.write(&client)?;
+            writer.write(account)?;
+        }
+        writer.write(&false)?;
+        for result in self.store.transactions() {
+            let (tx, transaction) = result?;
+            writer.write(&true)?;
+            writer.write(&tx)?;
+            writer.write(&transaction)?;
+        }
+        writer.write(&false)?;
+        for LoggedEvent { seq, event } in &self.log {
+            writer.write(&true)?;
+            writer.write(seq)?;
+            writer.write(event)?;
+        }
+        writer.write(&false)?;
+        for err in &self.errors {
+            writer.write(&true)?;
+            writer.write(err)?;
+        }
+        writer.write(&false)?;
+
+        let checksum = writer.checksum.finish();
+        writer.write_raw(&checksum.to_le_bytes())?;
+        writer.writer.flush()
+    }
+
+    // Rebuilds a shard from a snapshot written by `Shard::snapshot`, keeping its accounts and
+    // transactions in the given store. Anything that the store already holds for the same clients
+    // and transactions is replaced.
+    //
+    // The snapshot is read a piece at a time, and its accounts and transactions are committed to the
+    // store in batches of `RESTORE_BATCH`, so they are never all held in memory at once (unless the
+    // store keeps them there). The checksum can only be checked once everything has been read, so
+    // the store may already hold part of a snapshot that turns out to be damaged.
+    pub fn restore_with_store(reader: impl Read, store: S) -> io::Result<Self> {
+        let mut reader = SnapshotReader::new(reader);
+        if reader.read_with(take::<8>).ok().as_ref() != Some(SNAPSHOT_MAGIC) {
+            return Err(DecodeError::new("not a snapshot").into());
+        }
+        let version = reader.read::<u32>()?;
+        if !(1..=SNAPSHOT_VERSION).contains(&version) {
+            return Err(DecodeError::new("unsupported snapshot version").into());
+        }
+
+        let mut shard = Self::with_store(store, reader.read()?);
+        let mut changes = Changes::default();
+        while reader.read()? {
+            changes.accounts.push((reader.read()?, reader.read()?));
+            if changes.accounts.len() == RESTORE_BATCH {
+                shard.store.commit(mem::take(&mut changes))?;
+            }
+        }
+        while reader.read()? {
+            changes.transactions.push((reader.read()?, reader.read()?));
+            if changes.transactions.len() == RESTORE_BATCH {
+                shard.store.commit(mem::take(&mut changes))?;
+            }
+        }
+        shard.store.commit(changes)?;
+        while reader.read()? {
+            shard.log.push(LoggedEvent {
+                seq: reader.read()?,
+                event: reader.read()?,
+            });
+        }
+        while reader.read()? {
+            let err = if version == 1 {
+                reader.read_with(decode_error_v1)?
+            } else {
+                reader.read()?
+            };
+            shard.errors.push(err);
+        }
+
+        reader.finish()?;
+        Ok(shard)
+    }
+}
+
+// Identifies a snapshot written by `Shard::snapshot`, along with the version of its format. The
+// first version is still read: it only differs in how csv errors are written (see
+// `decode_error_v1`).
+const SNAPSHOT_MAGIC: &[u8; 8] = b"TXNSHARD";
+const SNAPSHOT_VERSION: u32 = 2;
+
+// The number of accounts or transactions that are committed to the store at once, while restoring a
+// snapshot.
+const RESTORE_BATCH: usize = 1024;
+
+// How much more of a snapshot is read whenever the values read so far have all been decoded.
+const RESTORE_CHUNK: usize = 64 * 1024;
+
+// Writes the parts of a snapshot, keeping track of the checksum of everything written so far.
+struct SnapshotWriter<W> {
+    writer: W,
+    checksum: Checksum,
+    // Reused for the encoding of each part.
+    buf: Vec<u8>,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    fn write(&mut self, value: &impl Encode) -> io::Result<()> {
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        let result = match value.encode(&mut buf) {
+            Ok(()) => self.write_raw(&buf),
+            Err(err) => Err(err.into()),
+        };
+        self.buf = buf;
+        result
+    }
+
+    fn write_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.checksum.update(bytes);
+        self.writer.write_all(bytes)
+    }
+}
+
+// Reads the parts of a snapshot, a chunk of the input at a time, keeping track of the checksum of
+// everything read so far.
+struct SnapshotReader<R> {
+    reader: R,
+    checksum: Checksum,
+    // The part of the input that has been read, but not decoded yet, starting at `pos`.
+    buf: Vec<u8>,
+    pos: usize,
+    eof: bool,
+    // Where each chunk of the input is read to, before it is added to `buf`.
+    chunk: Vec<u8>,
+}
+
+impl<R: Read> SnapshotReader<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            checksum: Checksum::new(),
+            buf: Vec::new(),
+            pos: 0,
+            eof: false,
+            chunk: vec![0; RESTORE_CHUNK],
+        }
+    }
+
+    fn read<T: Decode>(&mut self) -> io::Result<T> {
+        self.read_with(T::decode)
+    }
+
+    // Decodes the next value, reading more of the input until there is enough of it.
+    fn read_with<T>(
+        &mut self,
+        decode: impl Fn(&mut &[u8]) -> Result<T, DecodeError>,
+    ) -> io::Result<T> {
+        loop {
+            let mut input = &self.buf[self.pos..];
+            match decode(&mut input) {
+                Ok(value) => {
+                    let end = self.buf.len() - input.len();
+                    self.checksum.update(&self.buf[self.pos..end]);
+                    self.pos = end;
+                    return Ok(value);
+                }
+                Err(err) if err.is_end_of_data() && !self.eof => self.fill()?,
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    // Reads another chunk of the input, dropping what has already been decoded.
+    fn fill(&mut self) -> io::Result<()> {
+        let read = loop {
+            match self.reader.read(&mut self.chunk) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        self.buf.extend_from_slice(&self.chunk[..read]);
+        self.eof = read == 0;
+        Ok(())
+    }
+
+    // Reads the checksum at the end of the snapshot, and checks it against everything before it.
+    fn finish(mut self) -> io::Result<()> {
+        let checksum = self.checksum.finish();
+        if self.read::<u32>()? != checksum {
+            return Err(DecodeError::new("snapshot has the wrong checksum").into());
+        }
+        if self.pos == self.buf.len() && !self.eof {
+            self.fill()?;
+        }
+        if self.pos < self.buf.len() {
+            return Err(DecodeError::new("unexpected data after the end of the snapshot").into());
+        }
+        Ok(())
+    }
+}
+
+// Something that events (and the errors found while reading them) can be pushed to, which is either
+// a single `Shard` or a `ShardPool`.
+pub trait EventSink {
+    fn push_event(&mut self, event: Event);
+    fn push_error(&mut self, err: ShardError);
+}
+
+impl<S: Store> EventSink for Shard<S> {
+    fn push_event(&mut self, event: Event) {
+        Shard::push_event(self, event)
+    }
+
+    fn push_error(&mut self, err: ShardError) {
+        Shard::push_error(self, err)
+    }
+}
+
+// The accounts and transaction, besides the client's and the event's own, that an event touches.
+#[derive(Debug, Clone, Copy)]
+struct Others {
+    recipient: Option<ClientId>,
+    house: Option<ClientId>,
+    refunded: Option<TransactionId>,
+}
+
+// Applies the event straight to the accounts and transactions of a store that keeps them in memory,
+// with the same outcome as `Shard::apply_and_commit`. The other accounts are taken out of the store
+// while the event is applied, so that they can be changed alongside the client's.
+fn apply_in_place(
+    store: InMemory<'_>,
+    policy: &Policy,
+    event: Event,
+    others: Others,
+) -> Result<(), ShardError> {
+    let InMemory {
+        accounts,
+        transactions,
+    } = store;
+    let mut take = |id: ClientId| match accounts.remove(&id) {
+        Some(account) => (id, account, true),
+        None => (id, Account::new(), false),
+    };
+    let mut recipient = others.recipient.map(&mut take);
+    let mut house = others.house.map(&mut take);
+    let mut refunded = others
+        .refunded
+        .and_then(|id| transactions.get(&id).copied());
+
+    let client_id = event.client();
+    let account = accounts.entry(client_id).or_insert_with(Account::new);
+    let result = match event.transaction() {
+        Some(tx_id) => transactions
+            .entry(tx_id)
+            .or_insert_with(|| Transaction::new(client_id))
+            .apply(
+                event,
+                account,
+                Related {
+                    recipient: recipient.as_mut().map(|(_, account, _)| account),
+                    house: house.as_mut().map(|(_, account, _)| account),
+                    refunded: refunded.as_mut(),
+                },
+                policy,
+            )
+            .map_err(|err| ShardError::from_transaction_error(event, tx_id, err)),
+        None => {
+            apply_to_account(event, account);
+            Ok(())
+        }
+    };
+
+    // A failed event has been undone by `Transaction::apply`, so the other accounts are put back as
+    // they were.
+    let applied = result.is_ok();
+    for (id, other, existed) in recipient.into_iter().chain(house) {
+        if existed || (applied && other != Account::new()) {
+            accounts.insert(id, other);
+        }
+    }
+    if let (Some(id), Some(refund), true) = (others.refunded, refunded, applied) {
+        transactions.insert(id, refund);
+    }
+    result
+}
+
+// Applies an event that is not tied to a transaction, which acts on the account as a whole.
+fn apply_to_account(event: Event, account: &mut Account) {
+    if let Event::Unlock { .. } = event {
+        account.unlock();
+    }
+}
+
+// @returns how many events of each kind the log holds for each transaction
+fn event_counts(log: &[LoggedEvent]) -> HashMap<(TransactionId, Discriminant<Event>), usize> {
+    let mut counts = HashMap::new();
+    for LoggedEvent { event, .. } in log {
+        if let Some(tx_id) = event.transaction() {
+            *counts.entry((tx_id, mem::discriminant(event))).or_insert(0) += 1;
+        }
+    }
+    counts
+}
+
+// An entry in a shard's event log. The sequence number is the position of the event within the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoggedEvent {
+    pub seq: u64,
+    pub event: Event,
+}
+
+// A row of csv input that could not be read as an event. Only the message of the csv::Error is kept
+// (the error itself is not Clone), along with where the row is in the input and the row itself, so
+// that bad rows can be set aside and fixed.
+//
+// A snapshot of the first version only kept the message, so the rest is None for an error restored
+// from one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvError {
+    pub message: String,
+    // Where the row starts, if it is known.
+    pub position: Option<CsvPosition>,
+    // The row exactly as it was written in the input (without its line terminator), with anything
+    // that is not valid utf-8 replaced. None if it is not known (eg: the input could not be read).
+    pub record: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsvPosition {
+    // The offset of the first byte of the row, from the start of the input.
+    pub byte: u64,
+    // The line number of the row, starting at 1.
+    pub line: u64,
+}
+
+impl CsvError {
+    // Keeps the details of a csv error, along with the row that it is for, as it was written in the
+    // input (if it is known).
+    pub fn new(err: &csv::Error, record: Option<&[u8]>) -> Self {
+        let position = err.position().map(|position| CsvPosition {
+            byte: position.byte(),
+            line: position.line(),
+        });
+        Self {
+            message: err.to_string(),
+            position,
+            record: record.map(|record| String::from_utf8_lossy(record).into_owned()),
+        }
+    }
+}
+
+impl Display for CsvError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}", self.message)?;
+        if let Some(record) = &self.record {
+            write!(f, " in row `{}`", record)?;
+        }
+        Ok(())
+    }
+}
+
+impl Encode for CsvPosition {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        self.byte.encode(out)?;
+        self.line.encode(out)?;
+        Ok(())
+    }
+}
+
+impl Decode for CsvPosition {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Self {
+            byte: Decode::decode(input)?,
+            line: Decode::decode(input)?,
+        })
     }
+}
+
+impl Encode for CsvError {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        self.message.encode(out)?;
+        self.position.encode(out)?;
+        self.record.encode(out)?;
+        Ok(())
+    }
+}
 
-    pub fn reconcile(&mut self, _other: &Self) {
-        todo!();
+impl Decode for CsvError {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        Ok(Self {
+            message: Decode::decode(input)?,
+            position: Decode::decode(input)?,
+            record: Decode::decode(input)?,
+        })
     }
 }
 
 #[derive(Debug, Clone)]
 pub enum ShardError {
-    // The csv::Error type is not Clone, so we wrap it in an Rc to make it Clone.
-    CsvParseError(Rc<csv::Error>),
+    CsvParseError(CsvError),
+
+    // The store of the shard failed to load or save something. io::Error is not Clone, so it is
+    // wrapped in an Arc.
+    StorageError(Arc<io::Error>),
+
+    // The event's amount could not be represented with the number of decimal places being used.
+    InvalidAmount {
+        event: Event,
+        reason: RescaleError,
+    },
 
     TransactionOprror {
         tx: TransactionId,
         reason: AmountOpError,
     },
+
+    NonPositiveAmount {
+        tx: TransactionId,
+        event: Event,
+    },
+
+    InsufficientFunds {
+        client: ClientId,
+        tx: TransactionId,
+        requested: Amount,
+        available: Amount,
+    },
+
+    AccountLocked {
+        client: ClientId,
+        event: Event,
+    },
+
+    DuplicateTransaction {
+        tx: TransactionId,
+        original: Event,
+        rejected: Event,
+    },
+
+    DisputeClientMismatch {
+        client: ClientId,
+        owner: ClientId,
+        tx: TransactionId,
+    },
+
+    ResolveClientMismatch {
+        client: ClientId,
+        owner: ClientId,
+        tx: TransactionId,
+    },
+
+    ChargebackClientMismatch {
+        client: ClientId,
+        owner: ClientId,
+        tx: TransactionId,
+    },
+
+    InvalidTransition {
+        tx: TransactionId,
+        from: TransactionState,
+        event: Event,
+    },
+
+    WithdrawalNotDisputable {
+        client: ClientId,
+        tx: TransactionId,
+    },
+
+    InvalidTransfer {
+        client: ClientId,
+        to: ClientId,
+        tx: TransactionId,
+    },
+
+    ExceedsAmount {
+        tx: TransactionId,
+        event: Event,
+        requested: Amount,
+        available: Amount,
+    },
+
+    NotRefundable {
+        client: ClientId,
+        tx: TransactionId,
+        original_tx: TransactionId,
+    },
+
+    RefundClientMismatch {
+        client: ClientId,
+        owner: ClientId,
+        tx: TransactionId,
+        original_tx: TransactionId,
+    },
+}
+
+impl ShardError {
+    // @returns the name of the kind of error, which stays the same regardless of its details
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::CsvParseError(_) => "CsvParseError",
+            Self::StorageError(_) => "StorageError",
+            Self::InvalidAmount { .. } => "InvalidAmount",
+            Self::TransactionOprror { .. } => "TransactionOverflow",
+            Self::NonPositiveAmount { .. } => "NonPositiveAmount",
+            Self::InsufficientFunds { .. } => "InsufficientFunds",
+            Self::AccountLocked { .. } => "AccountLocked",
+            Self::DuplicateTransaction { .. } => "DuplicateTransaction",
+            Self::DisputeClientMismatch { .. } => "DisputeClientMismatch",
+            Self::ResolveClientMismatch { .. } => "ResolveClientMismatch",
+            Self::ChargebackClientMismatch { .. } => "ChargebackClientMismatch",
+            Self::InvalidTransition { .. } => "InvalidTransition",
+            Self::WithdrawalNotDisputable { .. } => "WithdrawalNotDisputable",
+            Self::InvalidTransfer { .. } => "InvalidTransfer",
+            Self::ExceedsAmount { .. } => "ExceedsAmount",
+            Self::NotRefundable { .. } => "NotRefundable",
+            Self::RefundClientMismatch { .. } => "RefundClientMismatch",
+        }
+    }
+
+    fn from_transaction_error(event: Event, tx: TransactionId, err: TransactionError) -> Self {
+        let client = event.client();
+        match err {
+            TransactionError::Overflow(reason) => Self::TransactionOprror { tx, reason },
+            TransactionError::NonPositiveAmount { .. } => Self::NonPositiveAmount { tx, event },
+            TransactionError::InsufficientFunds {
+                requested,
+                available,
+            } => Self::InsufficientFunds {
+                client,
+                tx,
+                requested,
+                available,
+            },
+            TransactionError::AccountLocked { client } => Self::AccountLocked { client, event },
+            TransactionError::ExceedsAmount {
+                requested,
+                available,
+            } => Self::ExceedsAmount {
+                // A refund is limited by what is left of the deposit it refunds, so name that.
+                tx: match event {
+                    Event::Refund { original_tx, .. } => original_tx,
+                    _ => tx,
+                },
+                event,
+                requested,
+                available,
+            },
+            TransactionError::NotRefundable { original_tx } => Self::NotRefundable {
+                client,
+                tx,
+                original_tx,
+            },
+            TransactionError::InvalidTransfer { to } => Self::InvalidTransfer { client, to, tx },
+            TransactionError::DuplicateTransaction { original } => Self::DuplicateTransaction {
+                tx,
+                original,
+                rejected: event,
+            },
+            TransactionError::ClientMismatch { owner } => match event {
+                Event::Refund { original_tx, .. } => Self::RefundClientMismatch {
+                    client,
+                    owner,
+                    tx,
+                    original_tx,
+                },
+                Event::Resolve { .. } => Self::ResolveClientMismatch { client, owner, tx },
+                Event::Chargeback { .. } => Self::ChargebackClientMismatch { client, owner, tx },
+                // Deposits, withdrawals, transfers and refunds take ownership of a transaction, so
+                // only disputes are left to end up here (or a refund of another client's deposit).
+                _ => Self::DisputeClientMismatch { client, owner, tx },
+            },
+            TransactionError::NotDisputable => Self::WithdrawalNotDisputable { client, tx },
+            TransactionError::InvalidTransition { from } => {
+                Self::InvalidTransition { tx, from, event }
+            }
+        }
+    }
+}
+
+// Errors are sent between threads (by a `ShardPool`, and by callers that report them elsewhere), so
+// they must stay `Send + Sync`, which they would not be if a non-owned csv error crept back in.
+const _: fn() = || {
+    fn assert<T: Send + Sync>() {}
+    assert::<ShardError>();
+    assert::<CsvError>();
+    assert::<Shard>();
+};
+
+impl From<io::Error> for ShardError {
+    fn from(err: io::Error) -> Self {
+        Self::StorageError(Arc::new(err))
+    }
 }
 
 impl Display for ShardError {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         match self {
-            Self::CsvParseError(e) => write!(f, "CSV parse error: {}", e.as_ref()),
+            Self::CsvParseError(e) => write!(f, "CSV parse error: {}", e),
+            Self::StorageError(e) => write!(f, "Storage error: {}", e.as_ref()),
+            Self::InvalidAmount { event, reason } => {
+                write!(f, "Event {} rejected because the {}", event, reason)
+            }
+            Self::TransactionOprror { tx, reason } => {
+                write!(f, "Transaction {} failed due to {}", tx, reason)
+            }
+            Self::NonPositiveAmount { tx, event } => write!(
+                f,
+                "Event {} rejected because the amount of transaction {} must be greater than zero",
+                event, tx
+            ),
+            Self::InsufficientFunds {
+                client,
+                tx,
+                requested,
+                available,
+            } => write!(
+                f,
+                "Transaction {} failed due to insufficient funds: client {} requested {} but only has {} available",
+                tx, client, requested, available
+            ),
+            Self::AccountLocked { client, event } => write!(
+                f,
+                "Event {} rejected because the account of client {} is locked",
+                event, client
+            ),
+            Self::DuplicateTransaction {
+                tx,
+                original,
+                rejected,
+            } => write!(
+                f,
+                "Event {} rejected because transaction {} already exists as {}",
+                rejected, tx, original
+            ),
+            Self::DisputeClientMismatch { client, owner, tx } => write!(
+                f,
+                "Dispute of transaction {} rejected because it was sent by client {}, but the transaction belongs to client {}",
+                tx, client, owner
+            ),
+            Self::ResolveClientMismatch { client, owner, tx } => write!(
+                f,
+                "Resolve of transaction {} rejected because it was sent by client {}, but the transaction belongs to client {}",
+                tx, client, owner
+            ),
+            Self::ChargebackClientMismatch { client, owner, tx } => write!(
+                f,
+                "Chargeback of transaction {} rejected because it was sent by client {}, but the transaction belongs to client {}",
+                tx, client, owner
+            ),
+            Self::InvalidTransition { tx, from, event } => write!(
+                f,
+                "Event {} rejected because transaction {} is {}",
+                event, tx, from
+            ),
+            Self::WithdrawalNotDisputable { client, tx } => write!(
+                f,
+                "Dispute of transaction {} by client {} rejected because withdrawals cannot be disputed",
+                tx, client
+            ),
+            Self::InvalidTransfer { client, to, tx } => write!(
+                f,
+                "Transfer {} from client {} to client {} rejected because clients cannot transfer to themselves",
+                tx, client, to
+            ),
+            Self::ExceedsAmount {
+                tx,
+                event,
+                requested,
+                available,
+            } => write!(
+                f,
+                "Event {} rejected because it is for {}, but only {} of transaction {} is left for it",
+                event, requested, available, tx
+            ),
+            Self::NotRefundable {
+                client,
+                tx,
+                original_tx,
+            } => write!(
+                f,
+                "Refund {} by client {} rejected because transaction {} is not a deposit that can be refunded",
+                tx, client, original_tx
+            ),
+            Self::RefundClientMismatch {
+                client,
+                owner,
+                tx,
+                original_tx,
+            } => write!(
+                f,
+                "Refund {} by client {} rejected because transaction {} belongs to client {}",
+                tx, client, original_tx, owner
+            ),
+        }
+    }
+}
+
+// An error is written as a tag for its kind, followed by its fields. The storage error can only be
+// written as its message, so it is restored as an error with the same message, rather than the same
+// error.
+impl Encode for ShardError {
+    fn encode(&self, out: &mut Vec<u8>) -> Result<(), EncodeError> {
+        match self {
+            Self::CsvParseError(err) => {
+                0u8.encode(out)?;
+                err.encode(out)?;
+            }
+            Self::StorageError(err) => {
+                1u8.encode(out)?;
+                err.to_string().encode(out)?;
+            }
+            Self::InvalidAmount { event, reason } => {
+                2u8.encode(out)?;
+                event.encode(out)?;
+                reason.encode(out)?;
+            }
             Self::TransactionOprror { tx, reason } => {
-                if let Some(rhs) = reason.rhs {
-                    write!(
-                        f,
-                        "Transaction {} failed due to arithmetic overflow: {} {} {}",
-                        tx, reason.lhs, reason.op, rhs
-                    )
-                } else {
-                    write!(
-                        f,
-                        "Transaction {} failed due to arithmetic overflow: {}{}",
-                        tx, reason.op, reason.lhs
-                    )
+                3u8.encode(out)?;
+                tx.encode(out)?;
+                reason.encode(out)?;
+            }
+            Self::InsufficientFunds {
+                client,
+                tx,
+                requested,
+                available,
+            } => {
+                4u8.encode(out)?;
+                client.encode(out)?;
+                tx.encode(out)?;
+                requested.encode(out)?;
+                available.encode(out)?;
+            }
+            Self::AccountLocked { client, event } => {
+                5u8.encode(out)?;
+                client.encode(out)?;
+                event.encode(out)?;
+            }
+            Self::DuplicateTransaction {
+                tx,
+                original,
+                rejected,
+            } => {
+                6u8.encode(out)?;
+                tx.encode(out)?;
+                original.encode(out)?;
+                rejected.encode(out)?;
+            }
+            Self::DisputeClientMismatch { client, owner, tx } => {
+                7u8.encode(out)?;
+                client.encode(out)?;
+                owner.encode(out)?;
+                tx.encode(out)?;
+            }
+            Self::ResolveClientMismatch { client, owner, tx } => {
+                8u8.encode(out)?;
+                client.encode(out)?;
+                owner.encode(out)?;
+                tx.encode(out)?;
+            }
+            Self::ChargebackClientMismatch { client, owner, tx } => {
+                9u8.encode(out)?;
+                client.encode(out)?;
+                owner.encode(out)?;
+                tx.encode(out)?;
+            }
+            Self::InvalidTransition { tx, from, event } => {
+                10u8.encode(out)?;
+                tx.encode(out)?;
+                from.encode(out)?;
+                event.encode(out)?;
+            }
+            Self::WithdrawalNotDisputable { client, tx } => {
+                11u8.encode(out)?;
+                client.encode(out)?;
+                tx.encode(out)?;
+            }
+            Self::InvalidTransfer { client, to, tx } => {
+                12u8.encode(out)?;
+                client.encode(out)?;
+                to.encode(out)?;
+                tx.encode(out)?;
+            }
+            Self::ExceedsAmount {
+                tx,
+                event,
+                requested,
+                available,
+            } => {
+                13u8.encode(out)?;
+                tx.encode(out)?;
+                event.encode(out)?;
+                requested.encode(out)?;
+                available.encode(out)?;
+            }
+            Self::NotRefundable {
+                client,
+                tx,
+                original_tx,
+            } => {
+                14u8.encode(out)?;
+                client.encode(out)?;
+                tx.encode(out)?;
+                original_tx.encode(out)?;
+            }
+            Self::RefundClientMismatch {
+                client,
+                owner,
+                tx,
+                original_tx,
+            } => {
+                15u8.encode(out)?;
+                client.encode(out)?;
+                owner.encode(out)?;
+                tx.encode(out)?;
+                original_tx.encode(out)?;
+            }
+            Self::NonPositiveAmount { tx, event } => {
+                16u8.encode(out)?;
+                tx.encode(out)?;
+                event.encode(out)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// @returns an error from a snapshot of the first version, where a csv error was only written as its
+// message
+fn decode_error_v1(input: &mut &[u8]) -> Result<ShardError, DecodeError> {
+    if input.first() != Some(&0) {
+        return ShardError::decode(input);
+    }
+    *input = &input[1..];
+    Ok(ShardError::CsvParseError(CsvError {
+        message: String::decode(input)?,
+        position: None,
+        record: None,
+    }))
+}
+
+impl Decode for ShardError {
+    fn decode(input: &mut &[u8]) -> Result<Self, DecodeError> {
+        let err = match u8::decode(input)? {
+            0 => Self::CsvParseError(Decode::decode(input)?),
+            1 => {
+                let message = String::decode(input)?;
+                Self::StorageError(Arc::new(io::Error::other(message)))
+            }
+            2 => Self::InvalidAmount {
+                event: Decode::decode(input)?,
+                reason: Decode::decode(input)?,
+            },
+            3 => Self::TransactionOprror {
+                tx: Decode::decode(input)?,
+                reason: Decode::decode(input)?,
+            },
+            4 => Self::InsufficientFunds {
+                client: Decode::decode(input)?,
+                tx: Decode::decode(input)?,
+                requested: Decode::decode(input)?,
+                available: Decode::decode(input)?,
+            },
+            5 => Self::AccountLocked {
+                client: Decode::decode(input)?,
+                event: Decode::decode(input)?,
+            },
+            6 => Self::DuplicateTransaction {
+                tx: Decode::decode(input)?,
+                original: Decode::decode(input)?,
+                rejected: Decode::decode(input)?,
+            },
+            7 => Self::DisputeClientMismatch {
+                client: Decode::decode(input)?,
+                owner: Decode::decode(input)?,
+                tx: Decode::decode(input)?,
+            },
+            8 => Self::ResolveClientMismatch {
+                client: Decode::decode(input)?,
+                owner: Decode::decode(input)?,
+                tx: Decode::decode(input)?,
+            },
+            9 => Self::ChargebackClientMismatch {
+                client: Decode::decode(input)?,
+                owner: Decode::decode(input)?,
+                tx: Decode::decode(input)?,
+            },
+            10 => Self::InvalidTransition {
+                tx: Decode::decode(input)?,
+                from: Decode::decode(input)?,
+                event: Decode::decode(input)?,
+            },
+            11 => Self::WithdrawalNotDisputable {
+                client: Decode::decode(input)?,
+                tx: Decode::decode(input)?,
+            },
+            12 => Self::InvalidTransfer {
+                client: Decode::decode(input)?,
+                to: Decode::decode(input)?,
+                tx: Decode::decode(input)?,
+            },
+            13 => Self::ExceedsAmount {
+                tx: Decode::decode(input)?,
+                event: Decode::decode(input)?,
+                requested: Decode::decode(input)?,
+                available: Decode::decode(input)?,
+            },
+            14 => Self::NotRefundable {
+                client: Decode::decode(input)?,
+                tx: Decode::decode(input)?,
+                original_tx: Decode::decode(input)?,
+            },
+            15 => Self::RefundClientMismatch {
+                client: Decode::decode(input)?,
+                owner: Decode::decode(input)?,
+                tx: Decode::decode(input)?,
+                original_tx: Decode::decode(input)?,
+            },
+            16 => Self::NonPositiveAmount {
+                tx: Decode::decode(input)?,
+                event: Decode::decode(input)?,
+            },
+            _ => return Err(DecodeError::new("invalid shard error")),
+        };
+        Ok(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        AmountValue, CacheStats, CachedStore, Currency, DisputeMode, FileStore, LockedPolicy,
+        WithdrawalDisputes,
+    };
+
+    fn deposit(client: ClientId, tx: TransactionId, amount: AmountValue) -> Event {
+        Event::Deposit {
+            client,
+            tx,
+            amount: Amount::new(amount),
+        }
+    }
+
+    fn withdrawal(client: ClientId, tx: TransactionId, amount: AmountValue) -> Event {
+        Event::Withdrawal {
+            client,
+            tx,
+            amount: Amount::new(amount),
+        }
+    }
+
+    fn shard_from(events: &[Event]) -> Shard {
+        let mut shard = Shard::new();
+        events.iter().for_each(|event| shard.push_event(*event));
+        shard
+    }
+
+    #[test]
+    fn test_reconcile_matches_single_shard() {
+        let events = [
+            deposit(1, 1, 100_0000),
+            deposit(2, 2, 50_0000),
+            withdrawal(1, 3, 25_0000),
+            Event::Dispute {
+                client: 2,
+                tx: 2,
+                amount: None,
+            },
+            Event::Dispute {
+                client: 1,
+                tx: 1,
+                amount: None,
+            },
+            Event::Resolve {
+                client: 1,
+                tx: 1,
+                amount: None,
+            },
+            Event::Chargeback {
+                client: 2,
+                tx: 2,
+                amount: None,
+            },
+            deposit(3, 4, 10_0000),
+        ];
+        let want = shard_from(&events).generate_output_sorted();
+
+        // Split the events between two shards, with some of them seen by both.
+        let mut a = shard_from(&[events[0], events[1], events[3], events[6]]);
+        let b = shard_from(&[
+            events[0], events[1], events[2], events[4], events[5], events[6], events[7],
+        ]);
+        a.reconcile(&b);
+
+        assert_eq!(a.generate_output_sorted(), want);
+        assert!(a.errors().is_empty());
+    }
+
+    #[test]
+    fn test_reconcile_is_idempotent() {
+        let mut a = shard_from(&[
+            deposit(1, 1, 100_0000),
+            Event::Dispute {
+                client: 1,
+                tx: 1,
+                amount: None,
+            },
+        ]);
+        let b = shard_from(&[
+            Event::Dispute {
+                client: 1,
+                tx: 1,
+                amount: None,
+            },
+            deposit(2, 2, 5_0000),
+        ]);
+
+        a.reconcile(&b);
+        let once = a.generate_output_sorted();
+        a.reconcile(&b);
+        a.reconcile(&a.clone());
+
+        assert_eq!(a.generate_output_sorted(), once);
+        assert_eq!(
+            a.get_account(1)
+                .map(|account| account.balance(Currency::UNSPECIFIED).held()),
+            Some(Amount::new(100_0000))
+        );
+        assert_eq!(
+            a.get_account(2)
+                .map(|account| account.balance(Currency::UNSPECIFIED).total()),
+            Some(Amount::new(5_0000))
+        );
+    }
+
+    #[test]
+    fn test_reconcile_repeated_disputes() {
+        let dispute = |value| Event::Dispute {
+            client: 1,
+            tx: 1,
+            amount: Some(Amount::new(value)),
+        };
+        let a = shard_from(&[deposit(1, 1, 100_0000), dispute(30_0000), dispute(20_0000)]);
+        let mut b = Shard::new();
+        b.reconcile(&a);
+        b.reconcile(&a);
+
+        assert_eq!(
+            b.get_account(1)
+                .map(|account| account.balance(Currency::UNSPECIFIED).held()),
+            Some(Amount::new(50_0000))
+        );
+        assert_eq!(b.generate_output_sorted(), a.generate_output_sorted());
+    }
+
+    #[test]
+    fn test_reconcile_amount_arrives_after_dispute() {
+        // The dispute and chargeback were seen by one shard, the deposit by the other.
+        let mut a = shard_from(&[
+            Event::Dispute {
+                client: 1,
+                tx: 1,
+                amount: None,
+            },
+            Event::Chargeback {
+                client: 1,
+                tx: 1,
+                amount: None,
+            },
+        ]);
+        let b = shard_from(&[deposit(1, 1, 100_0000)]);
+        a.reconcile(&b);
+
+        let account = a.get_account(1).unwrap();
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).total(),
+            Amount::new(0)
+        );
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).held(),
+            Amount::new(0)
+        );
+        assert!(account.is_locked());
+    }
+
+    #[test]
+    fn test_reconcile_follows_event_order() {
+        // The chargeback arrives before the resolve, so the resolve should have no effect.
+        let b = shard_from(&[
+            deposit(1, 1, 100_0000),
+            Event::Dispute {
+                client: 1,
+                tx: 1,
+                amount: None,
+            },
+            Event::Chargeback {
+                client: 1,
+                tx: 1,
+                amount: None,
+            },
+            Event::Resolve {
+                client: 1,
+                tx: 1,
+                amount: None,
+            },
+        ]);
+        let mut a = Shard::new();
+        a.reconcile(&b);
+
+        assert_eq!(a.generate_output_sorted(), b.generate_output_sorted());
+        assert!(a.get_account(1).unwrap().is_locked());
+    }
+
+    #[test]
+    fn test_reconcile_unlock() {
+        // Both shards see the chargeback, but only one of them the unlock and the deposit after it.
+        let chargeback = [
+            deposit(1, 1, 100_0000),
+            Event::Dispute {
+                client: 1,
+                tx: 1,
+                amount: None,
+            },
+            Event::Chargeback {
+                client: 1,
+                tx: 1,
+                amount: None,
+            },
+        ];
+        let mut a = shard_from(&chargeback);
+        let mut b = shard_from(&chargeback);
+        b.push_event(Event::Unlock { client: 1 });
+        b.push_event(deposit(1, 2, 5_0000));
+
+        a.reconcile(&b);
+        assert_eq!(a.generate_output_sorted(), b.generate_output_sorted());
+        assert!(!a.get_account(1).unwrap().is_locked());
+        assert!(a.errors().is_empty());
+
+        // The unlock has no effect the second time, so it is not applied again.
+        let events = a.events().len();
+        a.reconcile(&b);
+        assert_eq!(a.events().len(), events);
+    }
+
+    #[test]
+    fn test_event_log() {
+        let shard = shard_from(&[
+            deposit(1, 1, 100_0000),
+            deposit(1, 2, AmountValue::MAX), // overflow, not logged
+            Event::Dispute {
+                client: 1,
+                tx: 1,
+                amount: None,
+            },
+        ]);
+
+        assert_eq!(
+            shard.events(),
+            &[
+                LoggedEvent {
+                    seq: 0,
+                    event: deposit(1, 1, 100_0000),
+                },
+                LoggedEvent {
+                    seq: 1,
+                    event: Event::Dispute {
+                        client: 1,
+                        tx: 1,
+                        amount: None
+                    },
+                },
+            ]
+        );
+        assert_eq!(shard.errors().len(), 1);
+    }
+
+    #[test]
+    fn test_replay() {
+        let shard = shard_from(&[
+            deposit(1, 1, 100_0000),
+            deposit(2, 2, 50_0000),
+            Event::Dispute {
+                client: 1,
+                tx: 1,
+                amount: None,
+            },
+            withdrawal(2, 3, 20_0000),
+            Event::Chargeback {
+                client: 1,
+                tx: 1,
+                amount: None,
+            },
+            deposit(2, 4, AmountValue::MAX),
+        ]);
+        let replayed = Shard::replay(shard.events().iter().map(|logged| logged.event));
+
+        assert_eq!(
+            replayed.generate_output_sorted(),
+            shard.generate_output_sorted()
+        );
+        assert_eq!(replayed.events(), shard.events());
+        assert!(replayed.errors().is_empty());
+    }
+
+    #[test]
+    fn test_insufficient_funds() {
+        let shard = shard_from(&[deposit(1, 1, 10_0000), withdrawal(1, 2, 10_0001)]);
+
+        let account = shard.get_account(1).unwrap();
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).total(),
+            Amount::new(10_0000)
+        );
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).available(),
+            Amount::new(10_0000)
+        );
+        assert!(shard.get_transaction(2).unwrap().origin().is_none());
+        assert!(matches!(
+            shard.errors(),
+            [ShardError::InsufficientFunds {
+                client: 1,
+                tx: 2,
+                requested,
+                available,
+            }] if *requested == Amount::new(10_0001) && *available == Amount::new(10_0000)
+        ));
+    }
+
+    #[test]
+    fn test_locked_allow_deposits_and_resolves() {
+        let mut shard = Shard::with_policy(Policy {
+            locked: LockedPolicy::AllowDepositsAndResolves,
+            ..Policy::default()
+        });
+        [
+            deposit(1, 1, 100_0000),
+            deposit(1, 2, 50_0000),
+            Event::Dispute {
+                client: 1,
+                tx: 2,
+                amount: None,
+            },
+            Event::Dispute {
+                client: 1,
+                tx: 1,
+                amount: None,
+            },
+            Event::Chargeback {
+                client: 1,
+                tx: 1,
+                amount: None,
+            },
+            deposit(1, 3, 10_0000), // allowed
+            Event::Resolve {
+                client: 1,
+                tx: 2,
+                amount: None,
+            }, // allowed
+            withdrawal(1, 4, 10_0000), // rejected
+            Event::Dispute {
+                client: 1,
+                tx: 3,
+                amount: None,
+            }, // rejected
+        ]
+        .into_iter()
+        .for_each(|event| shard.push_event(event));
+
+        let account = shard.get_account(1).unwrap();
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).total(),
+            Amount::new(60_0000)
+        );
+        assert_eq!(
+            account.balance(Currency::UNSPECIFIED).held(),
+            Amount::new(0)
+        );
+        assert!(account.is_locked());
+        assert!(matches!(
+            shard.errors(),
+            [
+                ShardError::AccountLocked {
+                    client: 1,
+                    event: Event::Withdrawal { tx: 4, .. },
+                },
+                ShardError::AccountLocked {
+                    client: 1,
+                    event: Event::Dispute { tx: 3, .. },
+                },
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_transaction() {
+        let shard = shard_from(&[deposit(1, 1, 10_0000), withdrawal(2, 1, 5_0000)]);
+
+        assert_eq!(
+            shard
+                .get_account(1)
+                .map(|account| account.balance(Currency::UNSPECIFIED).total()),
+            Some(Amount::new(10_0000))
+        );
+        assert!(matches!(
+            shard.errors(),
+            [ShardError::DuplicateTransaction {
+                tx: 1,
+                original,
+                rejected,
+            }] if *original == deposit(1, 1, 10_0000) && *rejected == withdrawal(2, 1, 5_0000)
+        ));
+    }
+
+    #[test]
+    fn test_client_mismatch() {
+        let shard = shard_from(&[
+            deposit(1, 7, 100_0000),
+            Event::Dispute {
+                client: 2,
+                tx: 7,
+                amount: None,
+            },
+            Event::Resolve {
+                client: 2,
+                tx: 7,
+                amount: None,
+            },
+            Event::Chargeback {
+                client: 2,
+                tx: 7,
+                amount: None,
+            },
+        ]);
+
+        assert_eq!(
+            shard
+                .get_account(1)
+                .map(|account| account.balance(Currency::UNSPECIFIED).held()),
+            Some(Amount::new(0))
+        );
+        assert_eq!(
+            shard
+                .get_account(2)
+                .map(|account| account.balance(Currency::UNSPECIFIED).held()),
+            Some(Amount::new(0))
+        );
+        assert!(matches!(
+            shard.errors(),
+            [
+                ShardError::DisputeClientMismatch {
+                    client: 2,
+                    owner: 1,
+                    tx: 7,
+                },
+                ShardError::ResolveClientMismatch {
+                    client: 2,
+                    owner: 1,
+                    tx: 7,
+                },
+                ShardError::ChargebackClientMismatch {
+                    client: 2,
+                    owner: 1,
+                    tx: 7,
+                },
+            ]
+        ));
+
+        // The same events arriving before the deposit are accepted at first, and reported once the
+        // deposit shows that the transaction belongs to another client.
+        let shard = shard_from(&[
+            Event::Dispute {
+                client: 2,
+                tx: 7,
+                amount: None,
+            },
+            Event::Chargeback {
+                client: 2,
+                tx: 7,
+                amount: Some(Amount::new(5_0000)),
+            },
+            deposit(1, 7, 100_0000),
+        ]);
+        assert_eq!(
+            shard.generate_output_sorted(),
+            [
+                summary(1, 100_0000, 0, 100_0000, false),
+                summary(2, 0, 0, 0, false),
+            ]
+        );
+        assert!(matches!(
+            shard.errors(),
+            [
+                ShardError::DisputeClientMismatch {
+                    client: 2,
+                    owner: 1,
+                    tx: 7,
+                },
+                ShardError::ChargebackClientMismatch {
+                    client: 2,
+                    owner: 1,
+                    tx: 7,
+                },
+            ]
+        ));
+    }
+
+    fn summary(
+        client: ClientId,
+        available: AmountValue,
+        held: AmountValue,
+        total: AmountValue,
+        locked: bool,
+    ) -> Summary {
+        Summary {
+            client,
+            currency: Some(Currency::UNSPECIFIED),
+            available: Amount::new(available),
+            held: Amount::new(held),
+            total: Amount::new(total),
+            locked,
+        }
+    }
+
+    // Deposits 100, withdraws 40, and then disputes the withdrawal, followed by `last` if given.
+    fn dispute_withdrawal(withdrawal_disputes: WithdrawalDisputes, last: Option<Event>) -> Shard {
+        let mut shard = Shard::with_policy(Policy {
+            withdrawal_disputes,
+            ..Policy::default()
+        });
+        [
+            deposit(1, 1, 100_0000),
+            withdrawal(1, 2, 40_0000),
+            Event::Dispute {
+                client: 1,
+                tx: 2,
+                amount: None,
+            },
+        ]
+        .into_iter()
+        .chain(last)
+        .for_each(|event| shard.push_event(event));
+        shard
+    }
+
+    #[test]
+    fn test_withdrawal_disputes_legacy() {
+        let shard = dispute_withdrawal(WithdrawalDisputes::Legacy, None);
+        assert_eq!(
+            shard.generate_output_sorted(),
+            [summary(1, 100_0000, -40_0000, 60_0000, false)]
+        );
+
+        let shard = dispute_withdrawal(
+            WithdrawalDisputes::Legacy,
+            Some(Event::Resolve {
+                client: 1,
+                tx: 2,
+                amount: None,
+            }),
+        );
+        assert_eq!(
+            shard.generate_output_sorted(),
+            [summary(1, 60_0000, 0, 60_0000, false)]
+        );
+
+        let shard = dispute_withdrawal(
+            WithdrawalDisputes::Legacy,
+            Some(Event::Chargeback {
+                client: 1,
+                tx: 2,
+                amount: None,
+            }),
+        );
+        assert_eq!(
+            shard.generate_output_sorted(),
+            [summary(1, 100_0000, 0, 100_0000, true)]
+        );
+    }
+
+    #[test]
+    fn test_withdrawal_disputes_not_disputable() {
+        let shard = dispute_withdrawal(WithdrawalDisputes::NotDisputable, None);
+        assert_eq!(
+            shard.generate_output_sorted(),
+            [summary(1, 60_0000, 0, 60_0000, false)]
+        );
+        assert!(matches!(
+            shard.errors(),
+            [ShardError::WithdrawalNotDisputable { client: 1, tx: 2 }]
+        ));
+
+        // Deposits can still be disputed. The whole deposit is held, even though 40.0 of it has been
+        // withdrawn since, which leaves the available funds negative. This is allowed on purpose,
+        // as it is for any disputed deposit: the dispute is about what was deposited, so a
+        // chargeback has to claw all of it back, and the client owes what was already withdrawn.
+        let mut shard = dispute_withdrawal(
+            WithdrawalDisputes::NotDisputable,
+            Some(Event::Dispute {
+                client: 1,
+                tx: 1,
+                amount: None,
+            }),
+        );
+        assert_eq!(
+            shard.generate_output_sorted(),
+            [summary(1, -40_0000, 100_0000, 60_0000, false)]
+        );
+
+        // An early dispute of what turns out to be a withdrawal is never applied.
+        shard.push_event(Event::Dispute {
+            client: 2,
+            tx: 3,
+            amount: None,
+        });
+        shard.push_event(deposit(2, 4, 10_0000));
+        shard.push_event(withdrawal(2, 3, 5_0000));
+        assert_eq!(
+            shard
+                .get_account(2)
+                .unwrap()
+                .balance(Currency::UNSPECIFIED)
+                .held(),
+            Amount::new(0)
+        );
+        assert_eq!(
+            shard.get_transaction(3).unwrap().state(),
+            TransactionState::Settled
+        );
+    }
+
+    #[test]
+    fn test_withdrawal_disputes_reversal() {
+        let shard = dispute_withdrawal(WithdrawalDisputes::Reversal, None);
+        assert_eq!(
+            shard.generate_output_sorted(),
+            [summary(1, 60_0000, 40_0000, 100_0000, false)]
+        );
+
+        let shard = dispute_withdrawal(
+            WithdrawalDisputes::Reversal,
+            Some(Event::Resolve {
+                client: 1,
+                tx: 2,
+                amount: None,
+            }),
+        );
+        assert_eq!(
+            shard.generate_output_sorted(),
+            [summary(1, 60_0000, 0, 60_0000, false)]
+        );
+
+        let shard = dispute_withdrawal(
+            WithdrawalDisputes::Reversal,
+            Some(Event::Chargeback {
+                client: 1,
+                tx: 2,
+                amount: None,
+            }),
+        );
+        assert_eq!(
+            shard.generate_output_sorted(),
+            [summary(1, 100_0000, 0, 100_0000, true)]
+        );
+    }
+
+    #[test]
+    fn test_summaries() {
+        let shard = shard_from(&[
+            deposit(3, 1, 30_0000),
+            deposit(1, 2, 10_0000),
+            deposit(2, 3, 20_0000),
+        ]);
+
+        assert_eq!(
+            shard.summaries().collect::<Vec<_>>(),
+            [
+                summary(1, 10_0000, 0, 10_0000, false),
+                summary(2, 20_0000, 0, 20_0000, false),
+                summary(3, 30_0000, 0, 30_0000, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fees() {
+        let mut shard = Shard::with_policy(Policy {
+            fees: FeeSchedule::BasisPoints(100),
+            house: 99,
+            ..Policy::default()
+        });
+        for event in [
+            deposit(1, 1, 100_0000),
+            withdrawal(1, 2, 50_0000),
+            // The withdrawal and its fee come to more than is available.
+            withdrawal(1, 3, 49_5000),
+            // The house does not pay fees to itself.
+            withdrawal(99, 4, 1_0000),
+            // A chargeback claws back what was credited, the fee stays with the house.
+            deposit(2, 5, 10_0000),
+            Event::Dispute {
+                client: 2,
+                tx: 5,
+                amount: None,
+            },
+            Event::Chargeback {
+                client: 2,
+                tx: 5,
+                amount: None,
+            },
+        ] {
+            shard.push_event(event);
+        }
+
+        assert_eq!(
+            shard.generate_output_sorted(),
+            [
+                summary(1, 48_5000, 0, 48_5000, false),
+                summary(2, 0, 0, 0, true),
+                summary(99, 6000, 0, 6000, false),
+            ]
+        );
+        assert_eq!(
+            shard.get_transaction(1).unwrap().fee(),
+            Some(Amount::new(1_0000))
+        );
+        assert_eq!(shard.get_transaction(4).unwrap().fee(), None);
+        assert!(matches!(
+            shard.errors(),
+            [ShardError::InsufficientFunds { tx: 3, requested, .. }]
+                if *requested == Amount::new(49_9950)
+        ));
+
+        // Every fee moves funds between accounts, so the totals balance with what came in and out.
+        let total = shard
+            .summaries()
+            .try_fold(Amount::new(0), |total, summary| total + summary.total)
+            .unwrap();
+        assert_eq!(
+            total,
+            Amount::new(100_0000 - 50_0000 - 1_0000 + 10_0000 - 9_9000)
+        );
+
+        // Fees are not credited to a locked house account.
+        for event in [
+            deposit(99, 6, 1_0000),
+            Event::Dispute {
+                client: 99,
+                tx: 6,
+                amount: None,
+            },
+            Event::Chargeback {
+                client: 99,
+                tx: 6,
+                amount: None,
+            },
+            deposit(1, 7, 10_0000),
+        ] {
+            shard.push_event(event);
+        }
+        assert!(matches!(
+            shard.errors().last(),
+            Some(ShardError::AccountLocked { client: 99, .. })
+        ));
+        assert_eq!(
+            shard
+                .get_account(1)
+                .unwrap()
+                .balance(Currency::UNSPECIFIED)
+                .total(),
+            Amount::new(48_5000)
+        );
+    }
+
+    #[test]
+    fn test_fees_without_house_account() {
+        // Without any fees, the house account does not show up in the output.
+        let shard = shard_from(&[deposit(1, 1, 10_0000)]);
+        assert!(shard.get_account(0).is_none());
+
+        let mut shard = Shard::with_policy(Policy {
+            fees: FeeSchedule::Flat(Amount::new(20_0000)),
+            ..Policy::default()
+        });
+        // The fee is capped at the deposit, and a failed withdrawal charges nothing.
+        shard.push_event(deposit(1, 1, 10_0000));
+        shard.push_event(withdrawal(1, 2, 1));
+        assert_eq!(
+            shard.generate_output_sorted(),
+            [
+                summary(0, 10_0000, 0, 10_0000, false),
+                summary(1, 0, 0, 0, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transfer_is_atomic() {
+        let transfer = |from, to, tx, amount| Event::Transfer {
+            from,
+            to,
+            tx,
+            amount: Amount::new(amount),
+        };
+        let mut shard = shard_from(&[
+            deposit(1, 1, 10_0000),
+            deposit(2, 2, AmountValue::MAX),
+            // Crediting client 2 overflows, so client 1 keeps the funds.
+            transfer(1, 2, 3, 5_0000),
+            // Client 3 does not get an account for a transfer that fails.
+            transfer(1, 3, 4, 20_0000),
+            transfer(1, 1, 5, 1_0000),
+            // A negative transfer would take funds from the recipient instead, without checking
+            // that it has them.
+            transfer(1, 2, 8, -5_0000),
+            transfer(1, 2, 9, 0),
+        ]);
+        assert_eq!(
+            shard
+                .errors()
+                .iter()
+                .map(ShardError::kind)
+                .collect::<Vec<_>>(),
+            [
+                "TransactionOverflow",
+                "InsufficientFunds",
+                "InvalidTransfer",
+                "NonPositiveAmount",
+                "NonPositiveAmount"
+            ]
+        );
+        assert_eq!(
+            shard.errors()[3].to_string(),
+            "Event transfer (client 1 to client 2, tx 8, amount -5.0000) rejected because the amount of transaction 8 must be greater than zero"
+        );
+        assert!(shard.get_account(3).is_none());
+        assert_eq!(
+            shard
+                .get_account(2)
+                .unwrap()
+                .balance(Currency::UNSPECIFIED)
+                .total(),
+            Amount::new(AmountValue::MAX)
+        );
+        assert_eq!(
+            shard
+                .get_account(1)
+                .unwrap()
+                .balance(Currency::UNSPECIFIED)
+                .total(),
+            Amount::new(10_0000)
+        );
+
+        // A transfer into a locked account fails, unless the policy allows deposits into it.
+        shard.push_event(transfer(1, 4, 6, 1_0000));
+        shard.push_event(Event::Dispute {
+            client: 1,
+            tx: 6,
+            amount: None,
+        });
+        shard.push_event(Event::Chargeback {
+            client: 1,
+            tx: 6,
+            amount: None,
+        });
+        shard.push_event(transfer(1, 4, 7, 1_0000));
+        assert!(matches!(
+            shard.errors().last(),
+            Some(ShardError::AccountLocked { client: 4, .. })
+        ));
+        assert_eq!(shard.generate_output_sorted()[2], summary(4, 0, 0, 0, true));
+    }
+
+    #[test]
+    fn test_transfer_disputes() {
+        let events = [
+            deposit(1, 1, 10_0000),
+            Event::Transfer {
+                from: 1,
+                to: 2,
+                tx: 2,
+                amount: Amount::new(4_0000),
+            },
+            Event::Dispute {
+                client: 1,
+                tx: 2,
+                amount: None,
+            },
+        ];
+        let shard = shard_from(&events);
+        assert_eq!(
+            shard.generate_output_sorted(),
+            [
+                summary(1, 6_0000, 0, 6_0000, false),
+                summary(2, 0, 4_0000, 4_0000, false),
+            ]
+        );
+
+        // The recipient can not dispute the transfer, only the sender can.
+        let mut shard = shard_from(&events[..2]);
+        shard.push_event(Event::Dispute {
+            client: 2,
+            tx: 2,
+            amount: None,
+        });
+        assert_eq!(shard.errors()[0].kind(), "DisputeClientMismatch");
+
+        // The dispute can arrive before the transfer, and the resolve releases the held funds.
+        let shard = shard_from(&[
+            events[2],
+            Event::Resolve {
+                client: 1,
+                tx: 2,
+                amount: None,
+            },
+            events[0],
+            events[1],
+        ]);
+        assert_eq!(
+            shard.generate_output_sorted(),
+            [
+                summary(1, 6_0000, 0, 6_0000, false),
+                summary(2, 4_0000, 0, 4_0000, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_refunds() {
+        let refund = |tx, original_tx, amount| Event::Refund {
+            client: 1,
+            tx,
+            original_tx,
+            amount: Amount::new(amount),
+        };
+        let mut shard = shard_from(&[
+            deposit(1, 1, 100_0000),
+            refund(2, 1, 30_0000),
+            refund(3, 1, 70_0001),
+            refund(4, 4, 1_0000),
+            withdrawal(1, 5, 10_0000),
+            refund(6, 5, 1_0000),
+            Event::Dispute {
+                client: 1,
+                tx: 1,
+                amount: Some(Amount::new(50_0000)),
+            },
+            // Only what is neither refunded nor disputed is left to refund.
+            refund(7, 1, 20_0001),
+            refund(2, 1, 1_0000),
+        ]);
+        assert_eq!(
+            shard
+                .errors()
+                .iter()
+                .map(ShardError::kind)
+                .collect::<Vec<_>>(),
+            [
+                "ExceedsAmount",
+                "NotRefundable",
+                "NotRefundable",
+                "ExceedsAmount",
+                "DuplicateTransaction"
+            ]
+        );
+        assert_eq!(
+            shard.errors()[0].to_string(),
+            "Event refund (client 1, tx 3 refunding tx 1, amount 70.0001) rejected because it \
+             is for 70.0001, but only 70.0000 of transaction 1 is left for it"
+        );
+        assert_eq!(
+            shard.get_transaction(1).unwrap().refunded_amount(),
+            Amount::new(30_0000)
+        );
+        assert_eq!(
+            shard.get_transaction(2).unwrap().amount(),
+            Amount::new(-30_0000)
+        );
+
+        // A chargeback can only claw back what was disputed, which excludes what was refunded.
+        shard.push_event(Event::Chargeback {
+            client: 1,
+            tx: 1,
+            amount: None,
+        });
+        assert_eq!(
+            shard.generate_output_sorted(),
+            [summary(1, 10_0000, 0, 10_0000, true)]
+        );
+    }
+
+    #[test]
+    fn test_file_store() {
+        let path = std::env::temp_dir().join(format!("txn_shard_{}.store", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let policy = Policy {
+            fees: FeeSchedule::Flat(Amount::new(1000)),
+            house: 99,
+            ..Policy::default()
+        };
+        let events = [
+            deposit(1, 1, 10_0000),
+            Event::Transfer {
+                from: 1,
+                to: 2,
+                tx: 2,
+                amount: Amount::new(4_0000),
+            },
+            Event::Refund {
+                client: 1,
+                tx: 3,
+                original_tx: 1,
+                amount: Amount::new(1_0000),
+            },
+            withdrawal(2, 4, 5_0000),
+            Event::Dispute {
+                client: 1,
+                tx: 2,
+                amount: Some(Amount::new(1_0000)),
+            },
+            deposit(3, 1, 1_0000),
+        ];
+
+        let memory = Shard::replay_with_policy(policy.clone(), events);
+        let mut shard = Shard::with_store(FileStore::open(&path).unwrap(), policy.clone());
+        events.into_iter().for_each(|event| shard.push_event(event));
+        assert_eq!(
+            shard.generate_output_sorted(),
+            memory.generate_output_sorted()
+        );
+        assert_eq!(shard.events(), memory.events());
+        assert_eq!(
+            shard
+                .errors()
+                .iter()
+                .map(ShardError::kind)
+                .collect::<Vec<_>>(),
+            ["InsufficientFunds", "DuplicateTransaction"]
+        );
+        drop(shard);
+
+        // Only the accounts and transactions are kept in the store, the log and errors are not.
+        let shard = Shard::with_store(FileStore::open(&path).unwrap(), policy);
+        assert_eq!(
+            shard.generate_output_sorted(),
+            memory.generate_output_sorted()
+        );
+        for tx in 1..=4 {
+            assert_eq!(shard.get_transaction(tx), memory.get_transaction(tx));
+        }
+        assert!(shard.events().is_empty());
+
+        // Every transaction is read back from the file for a snapshot.
+        let mut snapshot = Vec::new();
+        shard.snapshot(&mut snapshot).unwrap();
+        let restored = Shard::restore(&snapshot[..]).unwrap();
+        for tx in 1..=4 {
+            assert_eq!(restored.get_transaction(tx), memory.get_transaction(tx));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_cached_store() {
+        let path = std::env::temp_dir().join(format!("txn_shard_{}.spill", std::process::id()));
+        let mut shard = Shard::with_store(CachedStore::new(2, &path).unwrap(), Policy::default());
+        for tx in 1..=5 {
+            shard.push_event(deposit(1, tx, 1_0000));
+        }
+        assert_eq!(shard.store().stats().evictions, 3);
+
+        // The deposit was evicted long ago, but is loaded back for the dispute.
+        shard.push_event(Event::Dispute {
+            client: 1,
+            tx: 1,
+            amount: None,
+        });
+        assert!(shard.errors().is_empty());
+        assert_eq!(
+            shard.generate_output_sorted(),
+            [summary(1, 4_0000, 1_0000, 5_0000, false)]
+        );
+        assert!(shard.get_transaction(1).unwrap().is_disputed());
+        assert_eq!(
+            shard.store().stats(),
+            CacheStats {
+                hits: 1,
+                misses: 6,
+                evictions: 4,
+            }
+        );
+    }
+
+    #[test]
+    fn test_csv_error() {
+        let input = "type,client,tx,amount\ndeposit,1,\"1,000\",1.0\n";
+        let mut reader = csv::Reader::from_reader(input.as_bytes());
+        let headers = reader.byte_headers().unwrap().clone();
+        let mut record = csv::ByteRecord::new();
+        assert!(reader.read_byte_record(&mut record).unwrap());
+        let err = record.deserialize::<Event>(Some(&headers)).unwrap_err();
+
+        let err = CsvError::new(&err, Some(b"deposit,1,\"1,000\",1.0\xff"));
+        assert_eq!(err.position, Some(CsvPosition { byte: 22, line: 2 }));
+        // The row is kept as it was, except for anything that is not valid utf-8.
+        assert_eq!(
+            err.record.as_deref(),
+            Some("deposit,1,\"1,000\",1.0\u{FFFD}")
+        );
+        assert!(err
+            .to_string()
+            .ends_with(" in row `deposit,1,\"1,000\",1.0\u{FFFD}`"));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let policy = Policy {
+            disputes: DisputeMode::Strict,
+            fees: FeeSchedule::BasisPoints(100),
+            house: 99,
+            ..Policy::default()
+        };
+        let mut shard = Shard::replay_with_policy(
+            policy,
+            [
+                deposit(1, 1, 10_0000),
+                deposit(1, 2, 5_0000),
+                Event::Dispute {
+                    client: 1,
+                    tx: 1,
+                    amount: Some(Amount::new(2_0000)),
+                },
+                Event::Dispute {
+                    client: 1,
+                    tx: 2,
+                    amount: None,
+                },
+                Event::Chargeback {
+                    client: 1,
+                    tx: 2,
+                    amount: None,
+                },
+                withdrawal(2, 3, 1_0000),
+            ],
+        );
+        shard.push_error(ShardError::StorageError(Arc::new(io::Error::other(
+            "disk full",
+        ))));
+        let csv_error = CsvError {
+            message: "invalid event type".to_string(),
+            position: Some(CsvPosition { byte: 40, line: 3 }),
+            record: Some("refill,1,4,1.0".to_string()),
+        };
+        shard.push_error(ShardError::CsvParseError(csv_error.clone()));
+
+        let mut snapshot = Vec::new();
+        shard.snapshot(&mut snapshot).unwrap();
+        let restored = Shard::restore(&snapshot[..]).unwrap();
+        assert_eq!(
+            restored.generate_output_sorted(),
+            shard.generate_output_sorted()
+        );
+        assert_eq!(restored.policy(), shard.policy());
+        assert_eq!(restored.events(), shard.events());
+        for tx in 1..=3 {
+            assert_eq!(restored.get_transaction(tx), shard.get_transaction(tx));
+        }
+        assert!(restored.get_transaction(1).unwrap().is_disputed());
+        assert!(restored.get_transaction(2).unwrap().is_chargebacked());
+        assert_eq!(
+            restored.errors().iter().map(|err| err.to_string()).collect::<Vec<_>>(),
+            [
+                "Transaction 3 failed due to insufficient funds: client 2 requested 1.0100 but only has 0.0000 available",
+                "Storage error: disk full",
+                "CSV parse error: invalid event type in row `refill,1,4,1.0`",
+            ]
+        );
+        match &restored.errors()[2] {
+            ShardError::CsvParseError(err) => assert_eq!(err, &csv_error),
+            err => panic!("unexpected error: {}", err),
+        }
+
+        // Any damage to the snapshot is noticed.
+        for i in [0, 12, snapshot.len() / 2, snapshot.len() - 1] {
+            let mut damaged = snapshot.clone();
+            damaged[i] ^= 1;
+            let err = Shard::restore(&damaged[..]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        assert!(Shard::restore(&snapshot[..snapshot.len() - 1]).is_err());
+        assert!(Shard::restore(&b""[..]).is_err());
+    }
+
+    #[test]
+    fn test_restore_v1_snapshot() {
+        // The first version only wrote the message of a csv error, after its tag (0).
+        let mut snapshot = SNAPSHOT_MAGIC.to_vec();
+        1u32.encode(&mut snapshot).unwrap();
+        Policy::default().encode(&mut snapshot).unwrap();
+        for _ in 0..3 {
+            false.encode(&mut snapshot).unwrap();
+        }
+        true.encode(&mut snapshot).unwrap();
+        0u8.encode(&mut snapshot).unwrap();
+        "invalid event type"
+            .to_string()
+            .encode(&mut snapshot)
+            .unwrap();
+        true.encode(&mut snapshot).unwrap();
+        ShardError::StorageError(Arc::new(io::Error::other("disk full")))
+            .encode(&mut snapshot)
+            .unwrap();
+        false.encode(&mut snapshot).unwrap();
+        let checksum = crate::checksum(&snapshot);
+        checksum.encode(&mut snapshot).unwrap();
+
+        let restored = Shard::restore(&snapshot[..]).unwrap();
+        match &restored.errors()[0] {
+            ShardError::CsvParseError(err) => assert_eq!(
+                err,
+                &CsvError {
+                    message: "invalid event type".to_string(),
+                    position: None,
+                    record: None,
                 }
+            ),
+            err => panic!("unexpected error: {}", err),
+        }
+        assert_eq!(restored.errors()[1].to_string(), "Storage error: disk full");
+
+        // The same snapshot is not read as the current version.
+        snapshot[8..12].copy_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        let checksum = crate::checksum(&snapshot[..snapshot.len() - 4]);
+        let len = snapshot.len();
+        snapshot[len - 4..].copy_from_slice(&checksum.to_le_bytes());
+        assert!(Shard::restore(&snapshot[..]).is_err());
+    }
+
+    #[test]
+    fn test_restore_in_batches() {
+        // Hands out the snapshot a byte at a time, so that every value is split between reads.
+        struct Trickle<'a>(&'a [u8]);
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let len = buf.len().min(self.0.len()).min(1);
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0 = &self.0[len..];
+                Ok(len)
             }
         }
+
+        let count = 3 * RESTORE_BATCH as TransactionId;
+        let shard = Shard::replay((1..=count).map(|tx| deposit(1, tx, 1_0000)));
+        let mut snapshot = Vec::new();
+        shard.snapshot(&mut snapshot).unwrap();
+
+        let path = std::env::temp_dir().join(format!("txn_restore_{}.spill", std::process::id()));
+        let store = CachedStore::new(10, &path).unwrap();
+        let restored = Shard::restore_with_store(Trickle(&snapshot), store).unwrap();
+        assert_eq!(restored.generate_output(), shard.generate_output());
+        assert_eq!(restored.events(), shard.events());
+
+        // Each batch evicts the one before it, and the last one is evicted by the final commit, so
+        // once the snapshot has been restored only 10 transactions are left in the cache.
+        assert_eq!(restored.store().stats().evictions, u64::from(count) - 10);
+        assert_eq!(
+            restored.store().transaction(1).unwrap(),
+            shard.store().transaction(1).unwrap()
+        );
     }
 }
@@ -0,0 +1,267 @@
+use super::{spill::SpillFile, Changes, Store};
+use crate::{Account, ClientId, Transaction, TransactionId};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap},
+    io,
+    path::Path,
+};
+
+// Keeps every account in memory, but only up to a fixed number of transactions. When there are more,
+// the least recently used transactions are evicted to a spill file, from which they are read back
+// when an event needs them again (eg: a dispute of an old deposit).
+//
+// The spill file is a hash table of transactions (see `SpillFile`), so the store does not keep track
+// of which transactions it has evicted, and evicting a transaction again overwrites its earlier copy.
+// It is only scratch space for this store: it is emptied when the store is created, and removed when
+// it is dropped. Use a `FileStore` to keep a shard across restarts.
+#[derive(Debug)]
+pub struct CachedStore {
+    accounts: BTreeMap<ClientId, Account>,
+    cache: RefCell<Lru>,
+    capacity: usize,
+    spill: SpillFile,
+    stats: Cell<CacheStats>,
+}
+
+// How well the transaction cache of a `CachedStore` has worked so far.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    // Transactions that were found in memory.
+    pub hits: u64,
+    // Transactions that were not in memory, whether or not they had been evicted to the spill file.
+    pub misses: u64,
+    // Transactions that were written to the spill file to make room for others.
+    pub evictions: u64,
+}
+
+// The cached transactions, along with the order they were last used in. Each use is stamped with
+// the next number from a counter, so the smallest stamp belongs to the least recently used one.
+#[derive(Debug, Default)]
+struct Lru {
+    entries: HashMap<TransactionId, (Transaction, u64)>,
+    order: BTreeMap<u64, TransactionId>,
+    next: u64,
+}
+
+impl Lru {
+    fn get(&mut self, tx: TransactionId) -> Option<Transaction> {
+        let stamp = self.next;
+        let (transaction, used) = self.entries.get_mut(&tx)?;
+        self.order.remove(used);
+        self.order.insert(stamp, tx);
+        *used = stamp;
+        self.next += 1;
+        Some(*transaction)
+    }
+
+    fn insert(&mut self, tx: TransactionId, transaction: Transaction) {
+        if let Some((_, used)) = self.entries.insert(tx, (transaction, self.next)) {
+            self.order.remove(&used);
+        }
+        self.order.insert(self.next, tx);
+        self.next += 1;
+    }
+
+    fn remove(&mut self, tx: TransactionId) -> Option<Transaction> {
+        let (transaction, used) = self.entries.remove(&tx)?;
+        self.order.remove(&used);
+        Some(transaction)
+    }
+
+    // @returns the ids of the cached transactions, from the least to the most recently used
+    fn oldest(&self) -> impl Iterator<Item = TransactionId> + '_ {
+        self.order.values().copied()
+    }
+}
+
+impl CachedStore {
+    // Creates an empty store, which keeps at most `capacity` transactions in memory and evicts the
+    // rest to the file at `path`. Anything already in the file is discarded.
+    //
+    // The transactions changed by the latest commit are never evicted, so for a moment the cache can
+    // hold a couple more than its capacity (or a whole batch more, while a snapshot is restored).
+    pub fn new(capacity: usize, path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            accounts: BTreeMap::new(),
+            cache: RefCell::new(Lru::default()),
+            capacity,
+            spill: SpillFile::create(path)?,
+            stats: Cell::new(CacheStats::default()),
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats.get()
+    }
+
+    fn record(&self, update: impl FnOnce(&mut CacheStats)) {
+        let mut stats = self.stats.get();
+        update(&mut stats);
+        self.stats.set(stats);
+    }
+}
+
+impl Store for CachedStore {
+    fn account(&self, client: ClientId) -> Option<&Account> {
+        self.accounts.get(&client)
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = (ClientId, &Account)> + '_> {
+        Box::new(
+            self.accounts
+                .iter()
+                .map(|(client, account)| (*client, account)),
+        )
+    }
+
+    // A transaction that is read back from the spill file is not put back in the cache here, that
+    // only happens once an event changes it (see `commit`).
+    fn transaction(&self, tx: TransactionId) -> io::Result<Option<Transaction>> {
+        if let Some(transaction) = self.cache.borrow_mut().get(tx) {
+            self.record(|stats| stats.hits += 1);
+            return Ok(Some(transaction));
+        }
+        self.record(|stats| stats.misses += 1);
+        self.spill.get(tx)
+    }
+
+    // The cached transactions are copied up front, as the cache can't stay borrowed while the
+    // iterator is in use (looking up a transaction in the meantime would panic). Neither the stats
+    // nor the order of the cache are affected.
+    fn transactions(
+        &self,
+    ) -> Box<dyn Iterator<Item = io::Result<(TransactionId, Transaction)>> + '_> {
+        let cached: HashMap<TransactionId, Transaction> = self
+            .cache
+            .borrow()
+            .entries
+            .iter()
+            .map(|(tx, (transaction, _))| (*tx, *transaction))
+            .collect();
+        let spilled = self.spill.iter().filter(|result| {
+            result.as_ref().map_or(true, |(tx, _)| {
+                !self.cache.borrow().entries.contains_key(tx)
+            })
+        });
+        Box::new(cached.into_iter().map(Ok).chain(spilled))
+    }
+
+    fn commit(&mut self, changes: Changes) -> io::Result<()> {
+        let cache = self.cache.get_mut();
+
+        // Room is made before anything is changed, so that nothing is lost if the spill file cannot
+        // be written to.
+        let incoming = changes
+            .transactions
+            .iter()
+            .filter(|(tx, _)| !cache.entries.contains_key(tx))
+            .count();
+        let excess = (cache.entries.len() + incoming).saturating_sub(self.capacity);
+        let evicted: Vec<TransactionId> = cache
+            .oldest()
+            .filter(|tx| {
+                changes
+                    .transactions
+                    .iter()
+                    .all(|(changed, _)| changed != tx)
+            })
+            .take(excess)
+            .collect();
+        if !evicted.is_empty() {
+            // A transaction is only removed from the cache once all of them have been written, so
+            // that a failed write leaves the cache as it was (what was written is a copy).
+            for tx in &evicted {
+                self.spill.put(*tx, &cache.entries[tx].0)?;
+            }
+            for tx in &evicted {
+                cache.remove(*tx);
+            }
+            let count = evicted.len() as u64;
+            self.record(|stats| stats.evictions += count);
+        }
+
+        let cache = self.cache.get_mut();
+        for (tx, transaction) in changes.transactions {
+            cache.insert(tx, transaction);
+        }
+        self.accounts.extend(changes.accounts);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Amount, AmountValue, Event, Policy, Related};
+
+    fn deposit(tx: TransactionId) -> (TransactionId, Transaction) {
+        let mut transaction = Transaction::new(1);
+        let deposit = Event::Deposit {
+            client: 1,
+            tx,
+            amount: Amount::new(AmountValue::from(tx)),
+        };
+        transaction
+            .apply(
+                deposit,
+                &mut Account::new(),
+                Related::default(),
+                &Policy::default(),
+            )
+            .unwrap();
+        (tx, transaction)
+    }
+
+    #[test]
+    fn test_cached_store() {
+        let path = std::env::temp_dir().join(format!("txn_cache_{}.spill", std::process::id()));
+        let mut store = CachedStore::new(2, &path).unwrap();
+        for tx in 1..=3 {
+            store
+                .commit(Changes {
+                    accounts: Vec::new(),
+                    transactions: vec![deposit(tx)],
+                })
+                .unwrap();
+        }
+        assert_eq!(
+            store.stats(),
+            CacheStats {
+                evictions: 1,
+                ..CacheStats::default()
+            }
+        );
+
+        // Using transaction 2 leaves transaction 3 as the least recently used one.
+        assert_eq!(store.transaction(2).unwrap(), Some(deposit(2).1));
+        assert_eq!(store.transaction(1).unwrap(), Some(deposit(1).1));
+        assert_eq!(store.transaction(4).unwrap(), None);
+        assert_eq!(
+            store.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 2,
+                evictions: 1,
+            }
+        );
+
+        store
+            .commit(Changes {
+                accounts: Vec::new(),
+                transactions: vec![deposit(1)],
+            })
+            .unwrap();
+        assert_eq!(store.stats().evictions, 2);
+        assert_eq!(store.transaction(1).unwrap(), Some(deposit(1).1));
+        assert_eq!(store.transaction(3).unwrap(), Some(deposit(3).1));
+        assert_eq!(store.stats().hits, 2);
+
+        drop(store);
+        assert!(!path.exists());
+    }
+}
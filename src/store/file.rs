@@ -0,0 +1,336 @@
+use super::{Changes, Store};
+use crate::{
+    checksum, decode_from_slice, encode_len, take, Account, ClientId, Decode, DecodeError, Encode,
+    EncodeError, Transaction, TransactionId,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+// Identifies a store file, along with the version of its format.
+const MAGIC: &[u8; 8] = b"TXNSTORE";
+const VERSION: u32 = 1;
+const HEADER_LEN: u64 = MAGIC.len() as u64 + 4;
+
+// The kinds of entries in a record.
+const ACCOUNT: u8 = 0;
+const TRANSACTION: u8 = 1;
+
+// Keeps everything in a single append-only file, so that the accounts and transactions survive a
+// restart. Opening the file again (with `FileStore::open`) restores them.
+//
+// After the header, the file is a sequence of records, one for each commit. A record is the length
+// of its payload and a checksum of it (both u32), followed by the payload: the number of entries
+// (u32), and then each entry, which is a kind (`ACCOUNT` or `TRANSACTION`), a key, and the encoded
+// value. Later entries for the same key replace earlier ones.
+//
+// Accounts are kept in memory (see `Store`), while for transactions only the position of their
+// latest entry is kept, and they are read back from the file when needed. The file is never
+// compacted, so it keeps growing with every change.
+#[derive(Debug)]
+pub struct FileStore {
+    file: File,
+    // The length of the file, which is where the next record will be written.
+    len: u64,
+    accounts: BTreeMap<ClientId, Account>,
+    // The offset and length of the latest encoding of each transaction.
+    transactions: HashMap<TransactionId, (u64, u32)>,
+}
+
+impl FileStore {
+    // Opens the store in the given file, creating it if it does not exist.
+    //
+    // A record that was cut short (eg: by a crash while it was being written) at the end of the file
+    // is dropped, along with the changes that it held. Any other damage to the file is an error.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let mut store = Self {
+            file,
+            len: 0,
+            accounts: BTreeMap::new(),
+            transactions: HashMap::new(),
+        };
+
+        if store.file.metadata()?.len() == 0 {
+            let mut header = MAGIC.to_vec();
+            VERSION.encode(&mut header)?;
+            store.file.write_all(&header)?;
+            store.len = HEADER_LEN;
+        } else {
+            store.load()?;
+        }
+        Ok(store)
+    }
+
+    // Reads every record in the file, building up the accounts and the index of transactions.
+    fn load(&mut self) -> io::Result<()> {
+        let file_len = self.file.metadata()?.len();
+        let mut reader = BufReader::new(self.file.try_clone()?);
+        reader.seek(SeekFrom::Start(0))?;
+
+        let mut header = [0; HEADER_LEN as usize];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| DecodeError::new("not a store file"))?;
+        let mut input = &header[..];
+        if &take::<8>(&mut input)? != MAGIC {
+            return Err(DecodeError::new("not a store file").into());
+        }
+        if u32::decode(&mut input)? != VERSION {
+            return Err(DecodeError::new("unsupported store file version").into());
+        }
+
+        let mut offset = HEADER_LEN;
+        let mut payload = Vec::new();
+        while offset < file_len {
+            let mut prefix = [0; 8];
+            let complete = offset + 8 <= file_len && reader.read_exact(&mut prefix).is_ok();
+            let mut input = &prefix[..];
+            let len = u32::decode(&mut input)?;
+            let expected = u32::decode(&mut input)?;
+            if !complete || offset + 8 + u64::from(len) > file_len {
+                break;
+            }
+
+            payload.resize(len as usize, 0);
+            reader.read_exact(&mut payload)?;
+            if checksum(&payload) != expected {
+                return Err(DecodeError::new("store record has the wrong checksum").into());
+            }
+            self.load_record(&payload, offset + 8)?;
+            offset += 8 + u64::from(len);
+        }
+
+        // Drop whatever is left of a record that was cut short, so that the next one is written
+        // straight after the last complete record.
+        if offset < file_len {
+            self.file.set_len(offset)?;
+        }
+        self.len = offset;
+        Ok(())
+    }
+
+    fn read_transaction(&self, offset: u64, len: u32) -> io::Result<Transaction> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut bytes = vec![0; len as usize];
+        file.read_exact(&mut bytes)?;
+        Ok(decode_from_slice(&bytes)?)
+    }
+
+    // Applies the entries of a record, whose payload starts at the given offset in the file.
+    fn load_record(&mut self, payload: &[u8], start: u64) -> Result<(), DecodeError> {
+        let mut input = payload;
+        for _ in 0..u32::decode(&mut input)? {
+            match u8::decode(&mut input)? {
+                ACCOUNT => {
+                    let client = ClientId::decode(&mut input)?;
+                    self.accounts.insert(client, Account::decode(&mut input)?);
+                }
+                TRANSACTION => {
+                    let tx = TransactionId::decode(&mut input)?;
+                    let len = u32::decode(&mut input)?;
+                    let offset = start + (payload.len() - input.len()) as u64;
+                    if input.len() < len as usize {
+                        return Err(DecodeError::end_of_data());
+                    }
+                    decode_from_slice::<Transaction>(&input[..len as usize])?;
+                    input = &input[len as usize..];
+                    self.transactions.insert(tx, (offset, len));
+                }
+                _ => return Err(DecodeError::new("invalid store entry")),
+            }
+        }
+        if !input.is_empty() {
+            return Err(DecodeError::new(
+                "unexpected data after the end of a store record",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Store for FileStore {
+    fn account(&self, client: ClientId) -> Option<&Account> {
+        self.accounts.get(&client)
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = (ClientId, &Account)> + '_> {
+        Box::new(
+            self.accounts
+                .iter()
+                .map(|(client, account)| (*client, account)),
+        )
+    }
+
+    fn transaction(&self, tx: TransactionId) -> io::Result<Option<Transaction>> {
+        match self.transactions.get(&tx) {
+            Some((offset, len)) => self.read_transaction(*offset, *len).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn transactions(
+        &self,
+    ) -> Box<dyn Iterator<Item = io::Result<(TransactionId, Transaction)>> + '_> {
+        Box::new(self.transactions.iter().map(|(tx, (offset, len))| {
+            self.read_transaction(*offset, *len)
+                .map(|transaction| (*tx, transaction))
+        }))
+    }
+
+    fn commit(&mut self, changes: Changes) -> io::Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        // The record is built up front, so that it can be written all at once. Transactions are
+        // prefixed with their length, and their position in the record is remembered for the index.
+        // A record that is too large for its lengths is rejected before anything is written.
+        let mut payload = Vec::new();
+        encode_len(
+            changes.accounts.len() + changes.transactions.len(),
+            &mut payload,
+        )?;
+        for (client, account) in &changes.accounts {
+            ACCOUNT.encode(&mut payload)?;
+            client.encode(&mut payload)?;
+            account.encode(&mut payload)?;
+        }
+        let mut positions = Vec::with_capacity(changes.transactions.len());
+        for (tx, transaction) in &changes.transactions {
+            TRANSACTION.encode(&mut payload)?;
+            tx.encode(&mut payload)?;
+            let len_at = payload.len();
+            0u32.encode(&mut payload)?;
+            transaction.encode(&mut payload)?;
+            let len = payload.len() - len_at - 4;
+            let len =
+                u32::try_from(len).map_err(|_| EncodeError::new("transaction is too long"))?;
+            payload[len_at..len_at + 4].copy_from_slice(&len.to_le_bytes());
+            positions.push((*tx, len_at + 4, len));
+        }
+
+        let mut record = Vec::with_capacity(payload.len() + 8);
+        encode_len(payload.len(), &mut record)?;
+        checksum(&payload).encode(&mut record)?;
+        record.extend_from_slice(&payload);
+        if let Err(err) = self.file.write_all(&record) {
+            // Do not leave part of the record behind, the next one would be written after it.
+            let _ = self.file.set_len(self.len);
+            return Err(err);
+        }
+
+        let start = self.len + 8;
+        self.len += record.len() as u64;
+        self.accounts.extend(changes.accounts);
+        for (tx, position, len) in positions {
+            self.transactions.insert(tx, (start + position as u64, len));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Amount, Event, Policy, Related};
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("txn_{}_{}.store", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn changes() -> Changes {
+        let mut account = Account::new();
+        let mut transaction = Transaction::new(1);
+        let deposit = Event::Deposit {
+            client: 1,
+            tx: 1,
+            amount: Amount::new(10_0000),
+        };
+        transaction
+            .apply(
+                deposit,
+                &mut account,
+                Related::default(),
+                &Policy::default(),
+            )
+            .unwrap();
+        Changes {
+            accounts: vec![(1, account)],
+            transactions: vec![(1, transaction)],
+        }
+    }
+
+    #[test]
+    fn test_file_store_reopen() {
+        let path = temp_path("reopen");
+        let changes = changes();
+        {
+            let mut store = FileStore::open(&path).unwrap();
+            store.commit(changes.clone()).unwrap();
+            store
+                .commit(Changes {
+                    accounts: vec![(2, Account::new())],
+                    transactions: Vec::new(),
+                })
+                .unwrap();
+        }
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.account(1), Some(&changes.accounts[0].1));
+        assert_eq!(store.accounts().count(), 2);
+        let transaction = store.transaction(1).unwrap().unwrap();
+        assert_eq!(transaction.origin(), changes.transactions[0].1.origin());
+        assert_eq!(transaction.amount(), Amount::new(10_0000));
+        assert!(store.transaction(2).unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_file_store_damage() {
+        let path = temp_path("damage");
+        {
+            let mut store = FileStore::open(&path).unwrap();
+            store.commit(changes()).unwrap();
+            store.commit(changes()).unwrap();
+        }
+        let len = std::fs::metadata(&path).unwrap().len();
+
+        // A record that was cut short is dropped, and the next one is written in its place.
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        {
+            let mut store = FileStore::open(&path).unwrap();
+            assert!(store.transaction(1).unwrap().is_some());
+            store.commit(changes()).unwrap();
+        }
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        assert!(FileStore::open(&path).is_ok());
+
+        // Anything else is reported, rather than silently losing data.
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[HEADER_LEN as usize + 10] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(
+            FileStore::open(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        std::fs::write(&path, b"not a store").unwrap();
+        assert!(FileStore::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,68 @@
+use super::{Changes, InMemory, Store};
+use crate::{Account, ClientId, Transaction, TransactionId};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+};
+
+// Keeps everything in memory, the accounts sorted by client id, and the transactions in a hash map.
+// This is the default store of a shard.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStore {
+    accounts: BTreeMap<ClientId, Account>,
+    transactions: HashMap<TransactionId, Transaction>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Moves everything from the other store into this one, replacing any accounts and transactions
+    // that both have.
+    pub(crate) fn extend(&mut self, other: MemoryStore) {
+        self.accounts.extend(other.accounts);
+        self.transactions.extend(other.transactions);
+    }
+}
+
+impl Store for MemoryStore {
+    fn account(&self, client: ClientId) -> Option<&Account> {
+        self.accounts.get(&client)
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = (ClientId, &Account)> + '_> {
+        Box::new(
+            self.accounts
+                .iter()
+                .map(|(client, account)| (*client, account)),
+        )
+    }
+
+    fn transaction(&self, tx: TransactionId) -> io::Result<Option<Transaction>> {
+        Ok(self.transactions.get(&tx).copied())
+    }
+
+    fn transactions(
+        &self,
+    ) -> Box<dyn Iterator<Item = io::Result<(TransactionId, Transaction)>> + '_> {
+        Box::new(
+            self.transactions
+                .iter()
+                .map(|(tx, transaction)| Ok((*tx, *transaction))),
+        )
+    }
+
+    fn commit(&mut self, changes: Changes) -> io::Result<()> {
+        self.accounts.extend(changes.accounts);
+        self.transactions.extend(changes.transactions);
+        Ok(())
+    }
+
+    fn in_memory(&mut self) -> Option<InMemory<'_>> {
+        Some(InMemory {
+            accounts: &mut self.accounts,
+            transactions: &mut self.transactions,
+        })
+    }
+}
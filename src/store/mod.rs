@@ -0,0 +1,62 @@
+mod cached;
+mod file;
+mod memory;
+mod spill;
+
+pub use cached::*;
+pub use file::*;
+pub use memory::*;
+
+use crate::{Account, ClientId, Transaction, TransactionId};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+};
+
+// Where a shard keeps its accounts and transactions.
+//
+// Every account is kept in memory, as there can only be as many of them as there are client ids.
+// Transactions on the other hand can be left in storage until they are needed, which is why loading
+// one can fail.
+pub trait Store {
+    fn account(&self, client: ClientId) -> Option<&Account>;
+
+    // @returns every account, sorted by client id
+    fn accounts(&self) -> Box<dyn Iterator<Item = (ClientId, &Account)> + '_>;
+
+    fn transaction(&self, tx: TransactionId) -> io::Result<Option<Transaction>>;
+
+    // @returns every transaction, in no particular order
+    fn transactions(
+        &self,
+    ) -> Box<dyn Iterator<Item = io::Result<(TransactionId, Transaction)>> + '_>;
+
+    // Saves the accounts and transactions changed by a single event. Either all of the changes are
+    // saved, or (if an error is returned) none of them are.
+    fn commit(&mut self, changes: Changes) -> io::Result<()>;
+
+    // @returns the accounts and transactions of a store that keeps all of them in memory, so that an
+    // event can change them in place, rather than through `commit`
+    fn in_memory(&mut self) -> Option<InMemory<'_>> {
+        None
+    }
+}
+
+// The accounts and transactions of a store that keeps all of them in memory (see `Store::in_memory`).
+pub struct InMemory<'a> {
+    pub accounts: &'a mut BTreeMap<ClientId, Account>,
+    pub transactions: &'a mut HashMap<TransactionId, Transaction>,
+}
+
+// The accounts and transactions changed by a single event, which are saved together.
+#[derive(Debug, Default, Clone)]
+pub struct Changes {
+    pub accounts: Vec<(ClientId, Account)>,
+    pub transactions: Vec<(TransactionId, Transaction)>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty() && self.transactions.is_empty()
+    }
+}
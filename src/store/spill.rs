@@ -0,0 +1,320 @@
+use crate::{decode_from_slice, Encode, EncodeError, Transaction, TransactionId};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+// Every slot has the same length: a flag that is 1 if the slot is used, the id of the transaction
+// (u32), the length of its encoding (u16), and the encoding itself, followed by padding.
+const SLOT_LEN: usize = 256;
+const SLOT_HEADER_LEN: usize = 7;
+
+// The number of slots that a new table starts with, and that are read at once when iterating.
+const INITIAL_SLOTS: u64 = 64;
+const CHUNK_SLOTS: u64 = 64;
+
+// A hash table of transactions, kept in a file, where a `CachedStore` puts the transactions that it
+// evicts. Each transaction has a single slot, which is found by hashing its id (and probing the
+// slots after it), so nothing but the size of the table is kept in memory. Writing a transaction
+// again overwrites its slot, so the file only grows with the number of distinct transactions: it
+// doubles in size whenever half of its slots are in use.
+//
+// The file is only scratch space: it is emptied when the table is created, and removed when it is
+// dropped.
+#[derive(Debug)]
+pub(crate) struct SpillFile {
+    file: File,
+    path: PathBuf,
+    // The number of slots, which is always a power of two.
+    slots: u64,
+    used: u64,
+}
+
+impl SpillFile {
+    pub(crate) fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::with_slots(path.as_ref().to_path_buf(), INITIAL_SLOTS)
+    }
+
+    fn with_slots(path: PathBuf, slots: u64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        // The file starts out as zeroes, which is an empty slot.
+        file.set_len(slots * SLOT_LEN as u64)?;
+        Ok(Self {
+            file,
+            path,
+            slots,
+            used: 0,
+        })
+    }
+
+    pub(crate) fn get(&self, tx: TransactionId) -> io::Result<Option<Transaction>> {
+        let (_, slot) = self.find(tx)?;
+        match slot {
+            Some(slot) => Ok(Some(decode_slot(&slot)?.1)),
+            None => Ok(None),
+        }
+    }
+
+    // Writes the transaction to its slot, replacing what was there before.
+    pub(crate) fn put(&mut self, tx: TransactionId, transaction: &Transaction) -> io::Result<()> {
+        let slot = encode_slot(tx, transaction)?;
+        let (index, existing) = self.find(tx)?;
+        if existing.is_some() {
+            return self.write_slot(index, &slot);
+        }
+        if (self.used + 1) * 2 > self.slots {
+            self.grow()?;
+        }
+        let (index, _) = self.find(tx)?;
+        self.write_slot(index, &slot)?;
+        self.used += 1;
+        Ok(())
+    }
+
+    // @returns every transaction in the table, in no particular order
+    pub(crate) fn iter(
+        &self,
+    ) -> impl Iterator<Item = io::Result<(TransactionId, Transaction)>> + '_ {
+        (0..self.slots)
+            .step_by(CHUNK_SLOTS as usize)
+            .flat_map(move |start| {
+                let count = CHUNK_SLOTS.min(self.slots - start);
+                match self.read_slots(start, count) {
+                    Ok(bytes) => bytes
+                        .chunks(SLOT_LEN)
+                        .filter(|slot| slot[0] != 0)
+                        .map(decode_slot)
+                        .collect(),
+                    Err(err) => vec![Err(err)],
+                }
+            })
+    }
+
+    // @returns the index of the slot that holds the transaction (along with the slot), or of the
+    // empty slot where it would go
+    fn find(&self, tx: TransactionId) -> io::Result<(u64, Option<Vec<u8>>)> {
+        let mut index = hash(tx) & (self.slots - 1);
+        loop {
+            let slot = self.read_slots(index, 1)?;
+            if slot[0] == 0 {
+                return Ok((index, None));
+            }
+            if slot[1..5] == tx.to_le_bytes() {
+                return Ok((index, Some(slot)));
+            }
+            index = (index + 1) & (self.slots - 1);
+        }
+    }
+
+    fn read_slots(&self, index: u64, count: u64) -> io::Result<Vec<u8>> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(index * SLOT_LEN as u64))?;
+        let mut bytes = vec![0; count as usize * SLOT_LEN];
+        file.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn write_slot(&self, index: u64, slot: &[u8]) -> io::Result<()> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(index * SLOT_LEN as u64))?;
+        file.write_all(slot)
+    }
+
+    // Moves every transaction to a new table with twice as many slots, which replaces this one.
+    // Until it does, this table is left as it was, so nothing is lost if the new one can't be
+    // written.
+    fn grow(&mut self) -> io::Result<()> {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".grow");
+        let mut table = Self::with_slots(PathBuf::from(path), self.slots * 2)?;
+        let result = self.iter().try_for_each(|entry| {
+            let (tx, transaction) = entry?;
+            table.put(tx, &transaction)
+        });
+        if let Err(err) = result.and_then(|()| fs::rename(&table.path, &self.path)) {
+            let _ = fs::remove_file(&table.path);
+            return Err(err);
+        }
+
+        // The new table takes the place of this one. The old file has already been replaced, so
+        // only its handle is left to close.
+        std::mem::swap(&mut self.file, &mut table.file);
+        self.slots = table.slots;
+        self.used = table.used;
+        Ok(())
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// @returns a used slot that holds the value, or an error if its encoding does not fit in one
+fn encode_slot(tx: TransactionId, value: &impl Encode) -> io::Result<Vec<u8>> {
+    let mut slot = vec![1];
+    tx.encode(&mut slot)?;
+    0u16.encode(&mut slot)?;
+    value.encode(&mut slot)?;
+    let len = u16::try_from(slot.len() - SLOT_HEADER_LEN)
+        .ok()
+        .filter(|_| slot.len() <= SLOT_LEN)
+        .ok_or(EncodeError::new("transaction is too long for a spill slot"))?;
+    slot[5..SLOT_HEADER_LEN].copy_from_slice(&len.to_le_bytes());
+    slot.resize(SLOT_LEN, 0);
+    Ok(slot)
+}
+
+// @returns the transaction in a used slot
+fn decode_slot(slot: &[u8]) -> io::Result<(TransactionId, Transaction)> {
+    let tx = TransactionId::from_le_bytes([slot[1], slot[2], slot[3], slot[4]]);
+    let len = usize::from(u16::from_le_bytes([slot[5], slot[6]]));
+    let end = (SLOT_HEADER_LEN + len).min(slot.len());
+    Ok((tx, decode_from_slice(&slot[SLOT_HEADER_LEN..end])?))
+}
+
+// Spreads consecutive ids over the table (Fibonacci hashing), so that they are not all probed in
+// a single run of slots.
+fn hash(tx: TransactionId) -> u64 {
+    u64::from(tx).wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Account, Amount, AmountValue, Currency, DisputeMode, Event, FeeSchedule, Policy, Related,
+    };
+
+    fn deposit(tx: TransactionId) -> Transaction {
+        let mut transaction = Transaction::new(1);
+        let deposit = Event::Deposit {
+            client: 1,
+            tx,
+            amount: Amount::new(AmountValue::from(tx) + 1),
+        };
+        transaction
+            .apply(
+                deposit,
+                &mut Account::new(),
+                Related::default(),
+                &Policy::default(),
+            )
+            .unwrap();
+        transaction
+    }
+
+    #[test]
+    fn test_spill_file() {
+        let path = std::env::temp_dir().join(format!("txn_spill_{}.spill", std::process::id()));
+        let mut spill = SpillFile::create(&path).unwrap();
+        assert_eq!(spill.get(1).unwrap(), None);
+
+        // Enough transactions to grow the table a few times.
+        for tx in 0..1000 {
+            spill.put(tx, &deposit(tx)).unwrap();
+        }
+        assert_eq!(spill.slots, 2048);
+        let len = fs::metadata(&path).unwrap().len();
+
+        // Writing a transaction again replaces it, without growing the file.
+        for tx in 0..1000 {
+            spill.put(tx, &deposit(tx + 1)).unwrap();
+        }
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(spill.get(7).unwrap(), Some(deposit(8)));
+        assert_eq!(spill.get(1000).unwrap(), None);
+
+        let mut all: Vec<_> = spill.iter().map(Result::unwrap).collect();
+        all.sort_by_key(|(tx, _)| *tx);
+        assert_eq!(all.len(), 1000);
+        assert_eq!(all[999], (999, deposit(1000)));
+
+        drop(spill);
+        assert!(!path.exists());
+        let mut grow = path.into_os_string();
+        grow.push(".grow");
+        assert!(!Path::new(&grow).exists());
+    }
+
+    #[test]
+    fn test_spill_slot_fits_transaction() {
+        // A deposit in a currency with a fee, that a partial dispute, resolve and chargeback arrived
+        // before, has about the longest encoding that a transaction can have (refunds and transfers
+        // only take a couple of bytes more).
+        let usd = Currency::from_code("USD").unwrap();
+        let amount = |value| Amount::new(value).in_currency(usd).unwrap();
+        let policy = Policy {
+            disputes: DisputeMode::Deferred,
+            fees: FeeSchedule::Flat(amount(1_0000)),
+            house: 99,
+            ..Policy::default()
+        };
+        let tx = TransactionId::MAX;
+        let mut transaction = Transaction::new(1);
+        let (mut account, mut house) = (Account::new(), Account::new());
+        for event in [
+            Event::Dispute {
+                client: 1,
+                tx,
+                amount: Some(amount(5_0000)),
+            },
+            Event::Resolve {
+                client: 1,
+                tx,
+                amount: Some(amount(2_0000)),
+            },
+            Event::Chargeback {
+                client: 1,
+                tx,
+                amount: Some(amount(3_0000)),
+            },
+            Event::Deposit {
+                client: 1,
+                tx,
+                amount: amount(10_0000),
+            },
+        ] {
+            let related = Related {
+                house: Some(&mut house),
+                ..Related::default()
+            };
+            transaction
+                .apply(event, &mut account, related, &policy)
+                .unwrap();
+        }
+
+        let path = std::env::temp_dir().join(format!("txn_slot_{}.spill", std::process::id()));
+        let mut spill = SpillFile::create(&path).unwrap();
+        spill.put(tx, &transaction).unwrap();
+        assert_eq!(spill.get(tx).unwrap(), Some(transaction));
+    }
+
+    #[test]
+    fn test_spill_slot_too_long() {
+        // A string with as many characters as fit in a slot next to its length, and one with one
+        // more, which is rejected rather than cut off.
+        let mut empty = Vec::new();
+        String::new().encode(&mut empty).unwrap();
+        let fits = "x".repeat(SLOT_LEN - SLOT_HEADER_LEN - empty.len());
+        let slot = encode_slot(1, &fits).unwrap();
+        assert_eq!(slot.len(), SLOT_LEN);
+        assert_eq!(
+            decode_from_slice::<String>(&slot[SLOT_HEADER_LEN..]).unwrap(),
+            fits
+        );
+
+        let err = encode_slot(1, &format!("{fits}x")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "can not encode: transaction is too long for a spill slot"
+        );
+    }
+}